@@ -1,15 +1,33 @@
 use std::{
+    collections::HashMap,
+    convert::Infallible,
     ffi::OsStr,
-    path::{Component, PathBuf},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{bail, Error};
+use anyhow::{bail, Context, Error};
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_TYPE, HOST},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
 use structopt::StructOpt;
 use wasi_cap_std_sync::WasiCtxBuilder;
-use wasi_experimental_http_wasmtime::{HttpCtx, HttpState};
-use wasmtime::{AsContextMut, Engine, Func, Instance, Linker, Store, Val, ValType};
+use wasi_experimental_http_wasmtime::{
+    CertificatePin, ClientIdentity, ContentEncoding, CookieJar, Decompression, HttpCtx,
+    HttpInstance, HttpState, InboundRequest, Metrics, TlsConfig, TlsVersion, TraceContext,
+};
+use wasmtime::{AsContextMut, Engine, Func, Instance, Linker, Module, Store, Val, ValType};
 use wasmtime_wasi::*;
 
+/// The function called for each request in `--serve` mode, unless another
+/// one is set with `--invoke`.
+const DEFAULT_HANDLER: &str = "handle_http_request";
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "wasmtime-http")]
 struct Opt {
@@ -19,10 +37,16 @@ struct Opt {
     #[structopt(
         short = "i",
         long = "invoke",
-        default_value = "_start",
-        help = "The name of the function to run"
+        help = "The name of the function to run [default: _start, or handle_http_request with --serve]"
     )]
-    invoke: String,
+    invoke: Option<String>,
+
+    #[structopt(
+        long = "serve",
+        value_name = "ADDR",
+        help = "Listen for HTTP requests on ADDR, and handle each of them with a new instance of the module"
+    )]
+    serve: Option<SocketAddr>,
 
     #[structopt(
         short = "e",
@@ -47,6 +71,121 @@ struct Opt {
     )]
     max_concurrency: Option<u32>,
 
+    #[structopt(
+        long = "tls-root-cert",
+        value_name = "PATH",
+        help = "PEM file with additional root certificates to trust for outbound HTTPS requests"
+    )]
+    tls_root_certs: Vec<PathBuf>,
+
+    #[structopt(
+        long = "tls-no-system-roots",
+        help = "Do not trust the system root certificates for outbound HTTPS requests"
+    )]
+    tls_no_system_roots: bool,
+
+    #[structopt(
+        long = "tls-client-identity",
+        value_name = "HOST=CERT,KEY",
+        parse(try_from_str = parse_client_identity),
+        help = "PEM certificate chain and private key to present to HOST for mutual TLS"
+    )]
+    tls_client_identities: Vec<(String, PathBuf, PathBuf)>,
+
+    #[structopt(
+        long = "tls-min-version",
+        value_name = "VERSION",
+        help = "The minimum TLS version for outbound HTTPS requests (1.2 or 1.3)"
+    )]
+    tls_min_version: Option<TlsVersion>,
+
+    #[structopt(
+        long = "tls-alpn",
+        value_name = "PROTOCOL",
+        help = "ALPN protocol to offer for outbound HTTPS requests, in order of preference"
+    )]
+    tls_alpn: Vec<String>,
+
+    #[structopt(
+        long = "tls-pin",
+        value_name = "HOST=PIN",
+        parse(try_from_str = parse_certificate_pin),
+        help = "Pin the certificate presented by HOST, as `sha256/<base64 SPKI digest>` or `cert-sha256/<base64 certificate digest>`"
+    )]
+    tls_pins: Vec<(String, CertificatePin)>,
+
+    #[structopt(
+        long = "unix-socket",
+        value_name = "HOST=PATH",
+        parse(try_from_str = parse_unix_socket),
+        help = "Send requests for HOST over the Unix domain socket at PATH"
+    )]
+    unix_sockets: Vec<(String, PathBuf)>,
+
+    #[structopt(
+        long = "cookies",
+        help = "Store the cookies set by servers, and send them with later requests (of the same inbound request with --serve)"
+    )]
+    cookies: bool,
+
+    #[structopt(
+        long = "cookie-jar",
+        value_name = "PATH",
+        conflicts_with = "serve",
+        help = "Load cookies from the cookie file at PATH, and save them back when the module returns (implies --cookies)"
+    )]
+    cookie_jar: Option<PathBuf>,
+
+    #[structopt(
+        long = "decompression",
+        value_name = "MODE",
+        default_value = "passthrough",
+        help = "Whether the host decodes compressed response bodies (auto) or passes them to the module as received (passthrough)"
+    )]
+    decompression: Decompression,
+
+    #[structopt(
+        long = "compress-requests",
+        value_name = "ENCODING",
+        help = "Compress request bodies with ENCODING (gzip, deflate, br or zstd)"
+    )]
+    request_compression: Option<ContentEncoding>,
+
+    #[structopt(
+        long = "request-timeout",
+        value_name = "MILLISECONDS",
+        help = "Time limit of outbound requests, up to reading the entire response body"
+    )]
+    request_timeout: Option<u64>,
+
+    #[structopt(
+        long = "max-response-size",
+        value_name = "BYTES",
+        help = "Maximum size of response bodies, once decoded"
+    )]
+    max_response_size: Option<usize>,
+
+    #[structopt(
+        long = "trace-context",
+        help = "Send W3C trace context headers with outbound requests, continuing the trace of each inbound request with --serve"
+    )]
+    trace_context: bool,
+
+    #[cfg(feature = "opentelemetry")]
+    #[structopt(
+        long = "otlp-endpoint",
+        value_name = "URL",
+        help = "Export the spans of outbound requests to the OpenTelemetry collector at URL, with OTLP over gRPC. This implies --trace-context"
+    )]
+    otlp_endpoint: Option<String>,
+
+    #[structopt(
+        long = "metrics-addr",
+        value_name = "ADDR",
+        help = "Serve metrics of the outbound requests of the module on ADDR, in the Prometheus text format"
+    )]
+    metrics_addr: Option<SocketAddr>,
+
     #[structopt(value_name = "ARGS", help = "The arguments to pass to the module")]
     module_args: Vec<String>,
 }
@@ -54,70 +193,274 @@ struct Opt {
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Error> {
     let opt = Opt::from_args();
-    let method = opt.invoke.clone();
     // println!("{:?}", opt);
-    let (instance, mut store) = create_instance(
-        opt.module,
-        opt.vars,
-        opt.module_args.clone(),
-        opt.allowed_hosts,
-        opt.max_concurrency,
-    )?;
-    let func = instance
-        .get_func(&mut store, method.as_str())
-        .unwrap_or_else(|| panic!("cannot find function {}", method));
+    #[cfg(feature = "opentelemetry")]
+    let _exporter = opt.otlp_endpoint.as_deref().map(otlp::init).transpose()?;
+    #[cfg(feature = "opentelemetry")]
+    let trace_context = opt.trace_context || opt.otlp_endpoint.is_some();
+    #[cfg(not(feature = "opentelemetry"))]
+    let trace_context = opt.trace_context;
+    // Without an inbound request to continue the trace of, the requests of
+    // the module are part of the trace of this span.
+    let run = tracing::info_span!("run", module = %opt.module);
+    let tls = tls_config(&opt)?;
+    let cookie_jar = match &opt.cookie_jar {
+        Some(path) => Some(CookieJar::load(path)?),
+        None if opt.cookies => Some(CookieJar::new()),
+        None => None,
+    };
+    let metrics = opt.metrics_addr.map(|addr| {
+        let module = Path::new(&opt.module)
+            .file_stem()
+            .map_or(opt.module.clone(), |stem| {
+                stem.to_string_lossy().into_owned()
+            });
+        let metrics = Metrics::new().with_module(&module);
+        tokio::spawn(serve_metrics(addr, metrics.clone()));
+        metrics
+    });
+    let http = HttpCtx {
+        allowed_hosts: opt.allowed_hosts,
+        max_concurrent_requests: opt.max_concurrency,
+        certificate_pins: certificate_pins(&opt.tls_pins),
+        unix_sockets: opt.unix_sockets.into_iter().collect(),
+        cookie_jar: cookie_jar.clone(),
+        decompression: opt.decompression,
+        request_compression: opt.request_compression,
+        request_timeout: opt.request_timeout.map(Duration::from_millis),
+        max_response_size: opt.max_response_size,
+        trace_context: trace_context.then(|| run.in_scope(TraceContext::new)),
+        metrics,
+        ..Default::default()
+    };
+    let state = HttpState::new_with_tls(tls)?;
+    let (linker, module) = load_module(&opt.module)?;
 
-    invoke_func(func, opt.module_args, &mut store)?;
+    match opt.serve {
+        Some(addr) => {
+            let server = Server {
+                addr,
+                linker,
+                module,
+                filename: opt.module,
+                vars: opt.vars,
+                args: opt.module_args,
+                state,
+                http,
+                handler: opt.invoke.unwrap_or_else(|| DEFAULT_HANDLER.to_string()),
+            };
+            server.serve().await?;
+        }
+        None => {
+            let method = opt.invoke.unwrap_or_else(|| "_start".to_string());
+            let (instance, mut store) = instantiate(
+                &linker,
+                &module,
+                &opt.module,
+                &opt.vars,
+                &opt.module_args,
+                state.instance(http),
+            )?;
+            let func = instance
+                .get_func(&mut store, method.as_str())
+                .unwrap_or_else(|| panic!("cannot find function {}", method));
+
+            invoke_func(func, opt.module_args, &mut store)?;
+        }
+    }
+
+    if let (Some(jar), Some(path)) = (cookie_jar, opt.cookie_jar) {
+        jar.save(path)?;
+    }
 
     Ok(())
 }
 
-fn create_instance(
-    filename: String,
-    vars: Vec<(String, String)>,
-    args: Vec<String>,
-    allowed_hosts: Option<Vec<String>>,
-    max_concurrent_requests: Option<u32>,
-) -> Result<(Instance, Store<WasmtimeHttpCtx>), Error> {
+/// Compile the module, and define the functions it can import. The linker
+/// can then instantiate the module several times, and the handles of each
+/// instance are kept in the `HttpInstance` of its store.
+fn load_module(filename: &str) -> Result<(Linker<WasmtimeHttpCtx>, Module), Error> {
     let mut wasmtime_config = wasmtime::Config::default();
     wasmtime_config.wasm_multi_memory(true);
     wasmtime_config.wasm_module_linking(true);
     let engine = Engine::new(&wasmtime_config)?;
     let mut linker = Linker::new(&engine);
 
-    let args = compute_argv(filename.clone(), &args);
+    wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut WasmtimeHttpCtx| -> &mut WasiCtx {
+        &mut cx.wasi
+    })?;
+    // Link `wasi_experimental_http`
+    HttpState::add_to_linker(
+        &mut linker,
+        |cx: &mut WasmtimeHttpCtx| -> &mut HttpInstance { &mut cx.http },
+    )?;
+
+    let module = Module::from_file(&engine, filename)?;
+    // Let modules built for newer hosts detect what is missing.
+    HttpState::define_unknown_imports(&mut linker, &module)?;
+    Ok((linker, module))
+}
+
+fn instantiate(
+    linker: &Linker<WasmtimeHttpCtx>,
+    module: &Module,
+    filename: &str,
+    vars: &[(String, String)],
+    args: &[String],
+    http: HttpInstance,
+) -> Result<(Instance, Store<WasmtimeHttpCtx>), Error> {
+    let args = compute_argv(filename.to_string(), args);
 
     let wasi = WasiCtxBuilder::new()
         .inherit_stdin()
         .inherit_stdout()
         .inherit_stderr()
-        .envs(&vars)?
+        .envs(vars)?
         .args(&args)?
         .build();
 
-    let http = HttpCtx {
-        allowed_hosts,
-        max_concurrent_requests,
-    };
-
     let ctx = WasmtimeHttpCtx { wasi, http };
 
-    let mut store = Store::new(&engine, ctx);
-    wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut WasmtimeHttpCtx| -> &mut WasiCtx {
-        &mut cx.wasi
-    })?;
-    // Link `wasi_experimental_http`
-    let http = HttpState::new()?;
-    http.add_to_linker(&mut linker, |cx: &WasmtimeHttpCtx| -> HttpCtx {
-        cx.http.clone()
-    })?;
-
-    let module = wasmtime::Module::from_file(store.engine(), filename)?;
-    let instance = linker.instantiate(&mut store, &module)?;
+    let mut store = Store::new(linker.engine(), ctx);
+    let instance = linker.instantiate(&mut store, module)?;
 
     Ok((instance, store))
 }
 
+/// Server of the `--serve` mode, which handles each request it receives
+/// with a new instance of the module.
+struct Server {
+    addr: SocketAddr,
+    linker: Linker<WasmtimeHttpCtx>,
+    module: Module,
+    filename: String,
+    vars: Vec<(String, String)>,
+    args: Vec<String>,
+    /// State shared by the instances handling the requests, created from
+    /// `http`.
+    state: HttpState,
+    http: HttpCtx,
+    handler: String,
+}
+
+impl Server {
+    /// Serve requests until the process is interrupted.
+    async fn serve(self) -> Result<(), Error> {
+        let addr = self.addr;
+        let server = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| server.clone().handle(req))) }
+        });
+
+        println!("Listening on http://{}", addr);
+        hyper::Server::try_bind(&addr)?
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Handle a request, responding with `500 Internal Server Error` if the
+    /// module fails or does not set a response.
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        match self.call(req).await {
+            Ok(res) => Ok(res.map(Body::from)),
+            Err(e) => {
+                eprintln!("cannot handle request: {:#}", e);
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(res)
+            }
+        }
+    }
+
+    async fn call(self: Arc<Self>, req: Request<Body>) -> Result<Response<Bytes>, Error> {
+        let (mut parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        // Guests get the absolute URL of the request.
+        let authority = match parts.headers.get(HOST) {
+            Some(host) => host.to_str()?.to_string(),
+            None => self.addr.to_string(),
+        };
+        let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        parts.uri = format!("http://{}{}", authority, path).parse()?;
+        let trace_context = self
+            .http
+            .trace_context
+            .as_ref()
+            .map(|_| TraceContext::from_headers(&parts.headers));
+        let inbound = InboundRequest::new(Request::from_parts(parts, body));
+
+        // Host functions block, so the module runs outside of the runtime.
+        let server = self.clone();
+        let guest_inbound = inbound.clone();
+        tokio::task::spawn_blocking(move || server.run(guest_inbound, trace_context)).await??;
+        inbound
+            .take_response()
+            .context("the module did not set a response")
+    }
+
+    /// Instantiate the module for `inbound`, and call its handler. The
+    /// requests of the instance join the trace of `trace_context`, and
+    /// cookies are only shared by the requests of the same instance.
+    fn run(
+        &self,
+        inbound: InboundRequest,
+        trace_context: Option<TraceContext>,
+    ) -> Result<(), Error> {
+        let http = HttpCtx {
+            inbound: Some(inbound),
+            trace_context,
+            cookie_jar: self.http.cookie_jar.as_ref().map(|_| CookieJar::new()),
+            ..self.http.clone()
+        };
+        let (instance, mut store) = instantiate(
+            &self.linker,
+            &self.module,
+            &self.filename,
+            &self.vars,
+            &self.args,
+            self.state.instance(http),
+        )?;
+        let handler = instance.get_typed_func::<(), (), _>(&mut store, &self.handler)?;
+        handler.call(&mut store, ())?;
+        Ok(())
+    }
+}
+
+/// Serve `metrics` on `GET /metrics` until the process exits.
+async fn serve_metrics(addr: SocketAddr, metrics: Metrics) {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let mut res = Response::new(Body::empty());
+                if req.method() == Method::GET && req.uri().path() == "/metrics" {
+                    *res.body_mut() = Body::from(metrics.encode());
+                    res.headers_mut()
+                        .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+                } else {
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                }
+                async { Ok::<_, Infallible>(res) }
+            }))
+        }
+    });
+
+    match hyper::Server::try_bind(&addr) {
+        Ok(server) => {
+            println!("Serving metrics on http://{}/metrics", addr);
+            if let Err(e) = server.serve(make_service).await {
+                eprintln!("cannot serve metrics: {:#}", e);
+            }
+        }
+        Err(e) => eprintln!("cannot serve metrics on {}: {:#}", addr, e),
+    }
+}
+
 // Invoke function given module arguments and print results.
 // Adapted from https://github.com/bytecodealliance/wasmtime/blob/main/src/commands/run.rs.
 fn invoke_func(func: Func, args: Vec<String>, mut store: impl AsContextMut) -> Result<(), Error> {
@@ -166,6 +509,60 @@ fn parse_env_var(s: &str) -> Result<(String, String), Error> {
     Ok((parts[0].to_owned(), parts[1].to_owned()))
 }
 
+fn parse_client_identity(s: &str) -> Result<(String, PathBuf, PathBuf), Error> {
+    let (host, files) = parse_env_var(s).context("must be of the form `host=cert,key`")?;
+    let parts: Vec<_> = files.splitn(2, ',').collect();
+    if parts.len() != 2 {
+        bail!("must be of the form `host=cert,key`");
+    }
+    Ok((host, PathBuf::from(parts[0]), PathBuf::from(parts[1])))
+}
+
+fn parse_certificate_pin(s: &str) -> Result<(String, CertificatePin), Error> {
+    let (host, pin) = parse_env_var(s).context("must be of the form `host=pin`")?;
+    Ok((host, pin.parse()?))
+}
+
+fn parse_unix_socket(s: &str) -> Result<(String, PathBuf), Error> {
+    let (host, path) = parse_env_var(s).context("must be of the form `host=path`")?;
+    Ok((host, PathBuf::from(path)))
+}
+
+fn certificate_pins(pins: &[(String, CertificatePin)]) -> HashMap<String, Vec<CertificatePin>> {
+    let mut res: HashMap<String, Vec<CertificatePin>> = HashMap::new();
+    for (host, pin) in pins {
+        res.entry(host.clone()).or_default().push(pin.clone());
+    }
+    res
+}
+
+fn tls_config(opt: &Opt) -> Result<TlsConfig, Error> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+    };
+
+    let mut client_identities = HashMap::new();
+    for (host, cert, key) in &opt.tls_client_identities {
+        let identity = ClientIdentity {
+            certificate_chain: read(cert)?,
+            private_key: read(key)?,
+        };
+        client_identities.insert(host.clone(), identity);
+    }
+
+    Ok(TlsConfig {
+        root_certificates: opt
+            .tls_root_certs
+            .iter()
+            .map(read)
+            .collect::<Result<_, _>>()?,
+        disable_system_roots: opt.tls_no_system_roots,
+        client_identities,
+        min_tls_version: opt.tls_min_version,
+        alpn_protocols: opt.tls_alpn.clone(),
+    })
+}
+
 fn compute_argv(module: String, args: &[String]) -> Vec<String> {
     let mut result = Vec::new();
     let module = PathBuf::from(module);
@@ -191,5 +588,44 @@ fn compute_argv(module: String, args: &[String]) -> Vec<String> {
 
 struct WasmtimeHttpCtx {
     pub wasi: WasiCtx,
-    pub http: HttpCtx,
+    pub http: HttpInstance,
+}
+
+/// Export of the spans of the host with OTLP.
+#[cfg(feature = "opentelemetry")]
+mod otlp {
+    use anyhow::Error;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Export the spans of the process to the collector at `endpoint`.
+    pub(crate) fn init(endpoint: &str) -> Result<Exporter, Error> {
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                "wasmtime-http",
+            )])))
+            .install_batch(runtime::Tokio)?;
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber)?;
+        Ok(Exporter)
+    }
+
+    /// Exports the remaining spans when dropped.
+    pub(crate) struct Exporter;
+
+    impl Drop for Exporter {
+        fn drop(&mut self) {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
 }
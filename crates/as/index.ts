@@ -88,6 +88,41 @@ export class Response {
     return String.UTF8.decode(value.buffer);
   }
 
+  /**
+   * Get all values of a header given its key.
+   * This requires a host defining the `wasi_experimental_http_v2` module.
+   */
+  public headerValuesGet(name: string): Array<string> {
+    let name_buf = String.UTF8.encode(name);
+    let name_ptr = changetype<usize>(name_buf);
+    let name_len = name_buf.byteLength;
+
+    let values_buf = new Uint8Array(4096);
+    let values_buf_ptr = changetype<usize>(values_buf.buffer);
+    let values_buf_len = values_buf.byteLength;
+    let values_len_ptr = memory.data(8);
+
+    if (
+      raw.headerValuesGet(
+        this.handle,
+        name_ptr,
+        name_len,
+        values_buf_ptr,
+        values_buf_len,
+        values_len_ptr
+      ) != 0
+    ) {
+      return new Array<string>();
+    }
+
+    let values = String.UTF8.decode(
+      values_buf.subarray(0, load<u32>(values_len_ptr)).buffer
+    ).split("\n");
+    // Every value is followed by a new line.
+    values.pop();
+    return values;
+  }
+
   /** Read all response headers into a header map */
   public headerGetAll(): Map<string, string> {
     let headers_buf = new Uint8Array(4 * 1024);
@@ -225,6 +260,26 @@ function raw_request(
   return new Response(status, handle);
 }
 
+/**
+ * Get the message the host recorded for the last error it returned.
+ * This requires a host defining the `wasi_experimental_http_v2` module.
+ */
+export function lastErrorMessage(): string {
+  let message_buf = new Uint8Array(4096);
+  let message_buf_ptr = changetype<usize>(message_buf.buffer);
+  let message_len_ptr = memory.data(8);
+
+  if (
+    raw.lastError(message_buf_ptr, message_buf.byteLength, message_len_ptr) != 0
+  ) {
+    return "";
+  }
+
+  return String.UTF8.decode(
+    message_buf.subarray(0, load<u32>(message_len_ptr)).buffer
+  );
+}
+
 /** Transform the header map into a string. */
 function headersToString(headers: Map<string, string>): string {
   let res = "";
@@ -317,6 +372,20 @@ function errorToHumanReadableMessage(e: u32): string {
       return "Runtime error.";
     case 13:
       return "Too many sessions.";
+    case 14:
+      return "Server certificate does not match the configured pins.";
+    case 15:
+      return "Unable to resolve the destination host.";
+    case 16:
+      return "Connection refused by the destination.";
+    case 17:
+      return "TLS handshake or session error.";
+    case 18:
+      return "Request timed out.";
+    case 19:
+      return "Unable to decode the response body.";
+    case 20:
+      return "Too many redirects.";
 
     default:
       return "Unknown error.";
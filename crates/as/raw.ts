@@ -74,6 +74,13 @@ export namespace HttpError {
     export const REQUEST_ERROR: HttpError = 11;
     export const RUNTIME_ERROR: HttpError = 12;
     export const TOO_MANY_SESSIONS: HttpError = 13;
+    export const CERTIFICATE_PIN_MISMATCH: HttpError = 14;
+    export const DNS_ERROR: HttpError = 15;
+    export const CONNECTION_REFUSED: HttpError = 16;
+    export const TLS_ERROR: HttpError = 17;
+    export const TIMEOUT: HttpError = 18;
+    export const DECODE_ERROR: HttpError = 19;
+    export const TOO_MANY_REDIRECTS: HttpError = 20;
 }
 
 /**
@@ -171,3 +178,40 @@ export declare function bodyRead(
     result_ptr: WasiMutPtr<WrittenBytes>
 ): HttpError;
 
+/**
+ * ---------------------- Module: [wasi_experimental_http_v2] ----------------------
+ *
+ * Modules using these functions can only be instantiated by hosts that define
+ * the versioned module.
+ */
+
+/**
+ * Buffer to store an error message
+ */
+export type ErrorMessageBuf = WasiMutSlice<u8>;
+
+/**
+ * Get all values associated with a header, separated by new lines
+ */
+// @ts-ignore: decorator
+@external("wasi_experimental_http_v2", "header_values_get")
+export declare function headerValuesGet(
+    response_handle: ResponseHandle,
+    header_name_ptr: WasiPtr<Char8>,
+    header_name_len: usize,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+    result_ptr: WasiMutPtr<WrittenBytes>
+): HttpError;
+
+/**
+ * Get a human-readable message describing the last error of the instance
+ */
+// @ts-ignore: decorator
+@external("wasi_experimental_http_v2", "last_error")
+export declare function lastError(
+    message_buf_ptr: WasiMutPtr<u8>,
+    message_buf_len: usize,
+    result_ptr: WasiMutPtr<WrittenBytes>
+): HttpError;
+
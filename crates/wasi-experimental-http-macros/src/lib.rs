@@ -0,0 +1,89 @@
+//! Procedural macros of the `wasi-experimental-http` crate, which re-exports
+//! them.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::ItemFn;
+
+/// Name of the function hosts call for each inbound request.
+const HANDLER_EXPORT: &str = "handle_http_request";
+
+/// Export a function as the handler of inbound requests. The function takes
+/// an `http::Request<bytes::Bytes>`, and returns a
+/// `Result<http::Response<bytes::Bytes>, E>`, where `E` converts into an
+/// `anyhow::Error`. Async functions are driven by `block_on`.
+#[proc_macro_attribute]
+pub fn handler(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    expand(attr.into(), item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(attr: TokenStream, item: TokenStream) -> syn::Result<TokenStream> {
+    if !attr.is_empty() {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "the handler attribute takes no arguments",
+        ));
+    }
+    let func: ItemFn = syn::parse2(item)?;
+    if func.sig.inputs.len() != 1 {
+        return Err(syn::Error::new_spanned(
+            &func.sig,
+            "a handler takes the request as its only argument",
+        ));
+    }
+
+    let name = &func.sig.ident;
+    let call = match func.sig.asyncness {
+        Some(_) => quote!(::wasi_experimental_http::block_on(#name(req))),
+        None => quote!(#name(req)),
+    };
+    // The exported function has its own name, so that it cannot collide
+    // with the handler.
+    let export = syn::LitStr::new(HANDLER_EXPORT, Span::call_site());
+    Ok(quote! {
+        #func
+
+        #[export_name = #export]
+        pub extern "C" fn __wasi_experimental_http_handler() {
+            ::wasi_experimental_http::handle(|req| #call)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let item = quote! {
+            fn hello(req: Request<Bytes>) -> Result<Response<Bytes>, Error> {
+                todo!()
+            }
+        };
+        let res = expand(quote!(), item.clone()).unwrap().to_string();
+        assert!(res.starts_with(&item.to_string()));
+        assert!(res.contains("export_name = \"handle_http_request\""));
+        assert!(res.contains("handle (| req | hello (req))"));
+
+        let item = quote! {
+            async fn hello(req: Request<Bytes>) -> Result<Response<Bytes>, Error> {
+                todo!()
+            }
+        };
+        let res = expand(quote!(), item).unwrap().to_string();
+        assert!(res.contains("block_on (hello (req))"));
+
+        let item = quote! { fn hello(req: Request<Bytes>) {} };
+        assert!(expand(quote!(path = "/"), item).is_err());
+        let item = quote! { fn hello() {} };
+        assert!(expand(quote!(), item).is_err());
+        let item = quote! { struct Hello; };
+        assert!(expand(quote!(), item).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Translate the witx definitions of the repository into the witx dialect
+//! `wiggle` parses, so that the host functions are generated from them.
+
+use std::{env, fs, path::Path};
+
+/// The witx modules defined by the host.
+const MODULES: [&str; 2] = ["wasi_experimental_http", "wasi_experimental_http_v2"];
+
+fn main() {
+    let witx_dir = env::var("WASI_EXPERIMENTAL_HTTP_WITX_DIR")
+        .unwrap_or_else(|_| format!("{}/../../witx", env::var("CARGO_MANIFEST_DIR").unwrap()));
+    println!("cargo:rerun-if-env-changed=WASI_EXPERIMENTAL_HTTP_WITX_DIR");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    for module in MODULES {
+        let path = Path::new(&witx_dir).join(format!("{}.witx", module));
+        println!("cargo:rerun-if-changed={}", path.display());
+        let witx = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+        fs::write(
+            Path::new(&out_dir).join(format!("{}.witx", module)),
+            translate(&witx),
+        )
+        .unwrap();
+    }
+}
+
+/// Translate a module written for `witx-codegen`, which the guest bindings
+/// follow, into the syntax of the `witx` crate: type names are declared
+/// before the module, which imports the memory of the guest, resources are
+/// implied by handles, buffers are lists, and `char8` is a byte.
+fn translate(witx: &str) -> String {
+    let module = witx.find("(module ").expect("no module");
+    let header_end = module + witx[module..].find('\n').unwrap() + 1;
+    let first_func = witx.find("(@interface func").expect("no function");
+    // The documentation of the first function starts after a blank line.
+    let types_end = witx[..first_func].rfind("\n\n").unwrap() + 1;
+
+    let mut out = String::new();
+    out.push_str(&witx[..module]);
+    out.push_str(&witx[header_end..types_end]);
+    out.push_str(&witx[module..header_end]);
+    out.push_str("  (import \"memory\" (memory))\n");
+    out.push_str(&witx[types_end..]);
+
+    let mut translated = String::new();
+    for line in out.lines() {
+        if line.trim_start().starts_with("(resource ") {
+            continue;
+        }
+        let mut line = line.to_string();
+        if let Some(start) = line.find("(handle $") {
+            let end = start + line[start..].find(')').unwrap();
+            line.replace_range(start..=end, "(handle)");
+        }
+        let line = line
+            .replace("(in-buffer ", "(list ")
+            .replace("(out-buffer ", "(list ")
+            .replace(" char8)", " u8)");
+        translated.push_str(&line);
+        translated.push('\n');
+    }
+    translated
+}
@@ -0,0 +1,1746 @@
+//! The HTTP client used to send the requests of guest modules.
+//!
+//! Connections are opened with hyper-rustls and hyper-proxy and pooled by
+//! hyper, while the connector and resolver of the host time every phase of
+//! a new connection, so that it can be reported to the guest.
+
+use crate::{
+    check_allowed,
+    encoding::{self, ContentEncoding, Decompression},
+    tls, transport_error, HttpCtx, HttpError, TransportError,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use http::{
+    header::{
+        ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+        COOKIE, HOST, LOCATION, PROXY_AUTHORIZATION, TRANSFER_ENCODING,
+    },
+    uri::Scheme,
+    HeaderMap, HeaderValue, Method, StatusCode, Version,
+};
+use hyper::{
+    body::HttpBody,
+    client::connect::{
+        dns::{GaiResolver, Name},
+        Connected, Connection, HttpConnector, HttpInfo,
+    },
+    service::Service,
+    Body, Uri,
+};
+use hyper_proxy::{ProxyConnector, ProxyStream};
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+use percent_encoding::percent_decode_str;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, protocol::WebSocketConfig},
+    WebSocketStream,
+};
+use tracing::Instrument;
+use url::{Position, Url};
+
+/// Maximum number of redirects followed for a single request.
+const MAX_REDIRECTS: usize = 10;
+
+/// A connection to a server, over TCP, TLS or a Unix domain socket.
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+/// A request sent on behalf of a guest.
+pub(crate) struct Request {
+    pub(crate) url: Url,
+    pub(crate) method: Method,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: RequestBody,
+    pub(crate) options: Options,
+}
+
+/// Options of a request chosen by the guest. Limits are only applied if
+/// they are lower than those of the `HttpCtx`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) follow_redirects: bool,
+    pub(crate) max_response_size: Option<usize>,
+    /// HTTP version to use, either `HTTP_11` or `HTTP_2`. HTTP/2 is
+    /// preferred over TLS, and used with prior knowledge otherwise.
+    pub(crate) http_version: Option<Version>,
+    /// Overrides the `decompression` of the `HttpCtx`.
+    pub(crate) decompression: Option<Decompression>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            timeout: None,
+            follow_redirects: true,
+            max_response_size: None,
+            http_version: None,
+            decompression: None,
+        }
+    }
+}
+
+/// The body of a request.
+#[derive(Debug)]
+pub(crate) enum RequestBody {
+    /// A body in memory, which can be sent again when following redirects.
+    Full(Bytes),
+    /// A body streamed by the guest, which can only be sent once.
+    Streaming(Body),
+    /// A streamed body which was already sent.
+    Sent,
+}
+
+impl RequestBody {
+    /// Get the body to send, leaving it in place if it can be sent again.
+    fn take(&mut self) -> Body {
+        match std::mem::replace(self, RequestBody::Sent) {
+            RequestBody::Full(bytes) => {
+                *self = RequestBody::Full(bytes.clone());
+                Body::from(bytes)
+            }
+            RequestBody::Streaming(body) => body,
+            RequestBody::Sent => Body::empty(),
+        }
+    }
+}
+
+/// A response, with its entire body and trailers. Trailers are only
+/// received over HTTP/2.
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+    pub(crate) trailers: HeaderMap,
+    pub(crate) metadata: Metadata,
+}
+
+/// Details about how a response was received.
+#[derive(Clone, Debug)]
+pub(crate) struct Metadata {
+    pub(crate) version: Version,
+    /// URL of the response, after following redirects.
+    pub(crate) url: Url,
+    /// Address of the server, or of the proxy the request was sent through,
+    /// unless connected over a Unix domain socket.
+    pub(crate) remote_addr: Option<SocketAddr>,
+    /// Content codings the host removed from the body, such as `gzip`, if
+    /// it decoded it.
+    pub(crate) decoded_encoding: Option<String>,
+    pub(crate) timings: Timings,
+}
+
+/// Time spent in each phase of a request. If redirects were followed,
+/// the phases are those of the last request, while `total` covers the
+/// whole exchange, up to reading the entire response body. The phases of
+/// the connection are zero if the request reused a pooled connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timings {
+    pub(crate) dns: Duration,
+    pub(crate) connect: Duration,
+    pub(crate) tls: Duration,
+    /// Time between sending the request and receiving the response head.
+    pub(crate) ttfb: Duration,
+    pub(crate) total: Duration,
+}
+
+impl Metadata {
+    /// Encode the metadata as a header map, so that it can be passed to
+    /// guests with the same binary encoding as headers.
+    pub(crate) fn to_header_map(&self) -> HeaderMap {
+        let mut hm = HeaderMap::new();
+        let mut insert = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                hm.insert(name, value);
+            }
+        };
+        insert("http-version", format!("{:?}", self.version));
+        insert("url", self.url.to_string());
+        if let Some(addr) = self.remote_addr {
+            insert("remote-addr", addr.to_string());
+        }
+        if let Some(encoding) = &self.decoded_encoding {
+            insert("decoded-content-encoding", encoding.clone());
+        }
+        let t = &self.timings;
+        insert("dns-time-us", t.dns.as_micros().to_string());
+        insert("connect-time-us", t.connect.as_micros().to_string());
+        insert("tls-time-us", t.tls.as_micros().to_string());
+        insert("ttfb-us", t.ttfb.as_micros().to_string());
+        insert("total-time-us", t.total.as_micros().to_string());
+        hm
+    }
+}
+
+/// The connections of an instance, pooled by hyper. Connections are opened
+/// with the options the `HttpCtx` of the instance sets for their host, and
+/// offer different HTTP versions depending on the version the guest
+/// prefers, so each version has a client of its own.
+pub(crate) struct Transport {
+    ctx: Arc<HttpCtx>,
+    default: hyper::Client<Connector, Body>,
+    http1: hyper::Client<Connector, Body>,
+    http2: hyper::Client<Connector, Body>,
+    /// HTTP/2 with prior knowledge, for `http` URLs.
+    prior_knowledge: hyper::Client<Connector, Body>,
+    /// Opens the connections of WebSockets, which are tunnelled through
+    /// proxies as the opening handshake is an HTTP/1.1 upgrade.
+    websocket: Connector,
+    /// Proxies requests are forwarded by, whose headers are added to them.
+    proxies: ProxyConnector<()>,
+}
+
+impl Transport {
+    pub(crate) fn new(tls: Arc<tls::Tls>, proxies: &Proxies, ctx: Arc<HttpCtx>) -> Self {
+        let connector = |version, tunnel| Connector {
+            tls: tls.clone(),
+            proxies: proxies.connector(&ctx, tunnel),
+            ctx: ctx.clone(),
+            version,
+        };
+        let client = |version| hyper::Client::builder().build(connector(version, false));
+        Transport {
+            default: client(None),
+            http1: client(Some(Version::HTTP_11)),
+            http2: client(Some(Version::HTTP_2)),
+            prior_knowledge: hyper::Client::builder()
+                .http2_only(true)
+                .build(connector(Some(Version::HTTP_2), false)),
+            websocket: connector(Some(Version::HTTP_11), true),
+            proxies: proxies.connector(&ctx, false),
+            ctx,
+        }
+    }
+
+    /// Get the client sending requests to `url` with `version`.
+    fn client(&self, url: &Url, version: Option<Version>) -> &hyper::Client<Connector, Body> {
+        match version {
+            Some(Version::HTTP_11) => &self.http1,
+            Some(Version::HTTP_2) if url.scheme() == "http" => &self.prior_knowledge,
+            Some(Version::HTTP_2) => &self.http2,
+            _ => &self.default,
+        }
+    }
+}
+
+/// Client applying the policy of an `HttpCtx` to the requests of a guest.
+pub(crate) struct Client {
+    transport: Arc<Transport>,
+    ctx: Arc<HttpCtx>,
+}
+
+impl Client {
+    pub(crate) fn new(transport: Arc<Transport>) -> Self {
+        let ctx = transport.ctx.clone();
+        Client { transport, ctx }
+    }
+
+    /// Send a request, within the time limit of the request.
+    pub(crate) async fn send(&self, req: Request) -> Result<Response, HttpError> {
+        let span = self.span(&req.method, &req.url);
+        self.send_in_span(req).instrument(span).await
+    }
+
+    /// The span of a request, which is linked to the trace context of the
+    /// instance unless it is sent from a host call that already is.
+    fn span(&self, method: &Method, url: &Url) -> tracing::Span {
+        let linked = !tracing::Span::current().is_none();
+        let span = tracing::info_span!("send", %method, %url);
+        if let Some(trace_context) = self.ctx.trace_context.as_ref().filter(|_| !linked) {
+            trace_context.link(&span);
+        }
+        span
+    }
+
+    async fn send_in_span(&self, mut req: Request) -> Result<Response, HttpError> {
+        if let Some(trace_context) = &self.ctx.trace_context {
+            trace_context.inject(&mut req.headers);
+        }
+        let metrics = self.ctx.metrics.as_ref().map(|metrics| {
+            let body_size = match &req.body {
+                RequestBody::Full(bytes) => Some(bytes.len()),
+                _ => None,
+            };
+            metrics.request_started(&req.url, req.method.as_str(), body_size)
+        });
+        let res = match lowest(self.ctx.request_timeout, req.options.timeout) {
+            Some(timeout) => tokio::time::timeout(timeout, self.exchange(req))
+                .await
+                .map_err(|_| HttpError::Timeout(format!("no response after {:?}", timeout).into()))
+                .and_then(|res| res),
+            None => self.exchange(req).await,
+        };
+        if let Some(metrics) = metrics {
+            metrics.finish(res.as_ref().ok().map(|res| (res.status, res.body.len())));
+        }
+        res
+    }
+
+    /// Send a request and read its response, following redirects to allowed
+    /// destinations unless the guest disabled them.
+    async fn exchange(&self, mut req: Request) -> Result<Response, HttpError> {
+        let start = Instant::now();
+        let max_size = lowest(self.ctx.max_response_size, req.options.max_response_size);
+        if let Some(encoding) = self.ctx.request_compression {
+            compress(&mut req, encoding)?;
+        }
+        // A guest setting `Accept-Encoding` itself handles the encoded body.
+        let decode = req.options.decompression.unwrap_or(self.ctx.decompression)
+            == Decompression::Auto
+            && !req.headers.contains_key(ACCEPT_ENCODING);
+        if decode {
+            req.headers
+                .insert(ACCEPT_ENCODING, encoding::accept_encoding());
+        }
+        for _ in 0..=MAX_REDIRECTS {
+            let (res, mut metadata) = self.send_once(&mut req).await?;
+            if let Some(jar) = &self.ctx.cookie_jar {
+                jar.store_response_cookies(&req.url, res.headers());
+            }
+            if let Some(next) =
+                redirect(&req, res.status(), res.headers()).filter(|_| req.options.follow_redirects)
+            {
+                check_allowed(next.url.as_str(), &self.ctx)?;
+                tracing::debug!(url = %next.url, "following redirect");
+                req = next;
+                continue;
+            }
+
+            let (mut parts, mut body) = res.into_parts();
+            let mut bytes = BytesMut::new();
+            while let Some(chunk) = body.data().await {
+                bytes.extend_from_slice(&chunk?);
+                if let Some(max) = max_size.filter(|max| bytes.len() > *max) {
+                    return Err(HttpError::ResponseTooLarge(max));
+                }
+            }
+            let trailers = body.trailers().await?.unwrap_or_default();
+            let mut body = bytes.freeze();
+            if decode {
+                // Decoding large bodies takes a while, so it runs on a
+                // blocking thread rather than holding up other requests.
+                let mut headers = std::mem::take(&mut parts.headers);
+                let decoded = tokio::task::spawn_blocking(move || {
+                    let decoded = encoding::decode(&mut headers, body, max_size);
+                    (headers, decoded)
+                })
+                .await
+                .map_err(|_| HttpError::RuntimeError)?;
+                parts.headers = decoded.0;
+                (body, metadata.decoded_encoding) = decoded.1?;
+            }
+            metadata.timings.total = start.elapsed();
+            return Ok(Response {
+                status: parts.status.as_u16(),
+                headers: parts.headers,
+                body,
+                trailers,
+                metadata,
+            });
+        }
+        Err(HttpError::TooManyRedirects(
+            format!("stopped after {} redirects", MAX_REDIRECTS).into(),
+        ))
+    }
+
+    /// Open a WebSocket connection to a `ws` or `wss` URL, with the headers
+    /// of the guest added to the opening handshake, within the time limit
+    /// of requests.
+    pub(crate) async fn websocket(
+        &self,
+        url: Url,
+        headers: HeaderMap,
+    ) -> Result<WebSocketStream<Box<dyn Io>>, HttpError> {
+        let span = self.span(&Method::GET, &url);
+        let open = self.open_websocket(url, headers).instrument(span);
+        match self.ctx.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, open).await.map_err(|_| {
+                HttpError::Timeout(format!("no handshake after {:?}", timeout).into())
+            })?,
+            None => open.await,
+        }
+    }
+
+    async fn open_websocket(
+        &self,
+        url: Url,
+        mut headers: HeaderMap,
+    ) -> Result<WebSocketStream<Box<dyn Io>>, HttpError> {
+        if let Some(trace_context) = &self.ctx.trace_context {
+            trace_context.inject(&mut headers);
+        }
+        let mut http_url = url.clone();
+        let scheme = match url.scheme() {
+            "ws" => "http",
+            "wss" => "https",
+            _ => return Err(HttpError::InvalidUrl),
+        };
+        http_url
+            .set_scheme(scheme)
+            .map_err(|_| HttpError::InvalidUrl)?;
+
+        // Proxies tunnel to port 443 unless the URI has a port.
+        let uri = format!(
+            "{}://{}:{}/",
+            scheme,
+            http_url.host_str().ok_or(HttpError::InvalidUrl)?,
+            http_url
+                .port_or_known_default()
+                .ok_or(HttpError::InvalidUrl)?
+        )
+        .parse::<Uri>()
+        .map_err(|_| HttpError::InvalidUrl)?;
+        let io: Box<dyn Io> = Box::new(self.transport.websocket.clone().connect(uri).await?);
+        let mut req = url.as_str().into_client_request()?;
+        // The headers of the handshake itself cannot be overridden.
+        let reserved = req.headers().keys().cloned().collect::<Vec<_>>();
+        for (name, value) in &headers {
+            if !reserved.contains(name) {
+                req.headers_mut().append(name, value.clone());
+            }
+        }
+        if let Some(jar) = &self.ctx.cookie_jar {
+            jar.add_cookie_header(&http_url, req.headers_mut());
+        }
+
+        let mut config = WebSocketConfig::default();
+        if let Some(max) = self.ctx.max_response_size {
+            config.max_message_size = Some(max);
+        }
+        let (stream, res) =
+            tokio_tungstenite::client_async_with_config(req, io, Some(config)).await?;
+        if let Some(jar) = &self.ctx.cookie_jar {
+            jar.store_response_cookies(&http_url, res.headers());
+        }
+        Ok(stream)
+    }
+
+    /// Send a request on a pooled connection, or on a new one if none is
+    /// idle, and wait for the response head.
+    async fn send_once(
+        &self,
+        req: &mut Request,
+    ) -> Result<(http::Response<Body>, Metadata), HttpError> {
+        let client = self.transport.client(&req.url, req.options.http_version);
+        let mut request = build_request(req)?;
+        if let Some(jar) = &self.ctx.cookie_jar {
+            jar.add_cookie_header(&req.url, request.headers_mut());
+        }
+        if let Some(headers) = self.transport.proxies.http_headers(request.uri()) {
+            for (name, value) in headers {
+                request
+                    .headers_mut()
+                    .entry(name)
+                    .or_insert_with(|| value.clone());
+            }
+        }
+
+        let sent = Instant::now();
+        let mut res = client.request(request).await.map_err(connect_error)?;
+        let elapsed = sent.elapsed();
+        let mut timings = res
+            .extensions_mut()
+            .remove::<Arc<ConnectInfo>>()
+            .and_then(|info| info.take_timings())
+            .unwrap_or_default();
+        timings.ttfb = elapsed.saturating_sub(timings.dns + timings.connect + timings.tls);
+
+        let metadata = Metadata {
+            version: res.version(),
+            url: req.url.clone(),
+            remote_addr: res
+                .extensions()
+                .get::<HttpInfo>()
+                .map(HttpInfo::remote_addr),
+            decoded_encoding: None,
+            timings,
+        };
+        Ok((res, metadata))
+    }
+}
+
+/// Get the error of a request, which is the error of the connector if the
+/// connection could not be established.
+fn connect_error(e: hyper::Error) -> HttpError {
+    if !e.is_connect() {
+        return e.into();
+    }
+    match e.into_cause().map(|cause| cause.downcast::<HttpError>()) {
+        Some(Ok(e)) => *e,
+        Some(Err(cause)) => transport_error(cause),
+        None => HttpError::RequestError("connection failed".into()),
+    }
+}
+
+/// A connection opened by a `Connector`: a socket, possibly to a proxy or
+/// through a tunnel, with TLS for `https` URLs.
+type Stream = MaybeHttpsStream<ProxyStream<Socket>>;
+
+/// Connector of the clients of an instance. It opens connections with
+/// hyper-rustls and hyper-proxy over the sockets of a `SocketConnector`,
+/// and times each phase of the connection.
+#[derive(Clone)]
+struct Connector {
+    tls: Arc<tls::Tls>,
+    proxies: ProxyConnector<()>,
+    ctx: Arc<HttpCtx>,
+    /// HTTP version the connections offer first over TLS, if any.
+    version: Option<Version>,
+}
+
+impl Connector {
+    /// Connect to the server of `uri`.
+    async fn connect(self, uri: Uri) -> Result<Conn, HttpError> {
+        let host = uri.host().ok_or(HttpError::InvalidUrl)?;
+        let pins = self
+            .ctx
+            .certificate_pins
+            .get(host)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        // rustls cannot choose a client certificate by server name, so each
+        // connection gets the configuration of its host.
+        let mut config = self.tls.client_config(host, pins);
+        match self.version {
+            Some(Version::HTTP_11) => config.alpn_protocols = vec![b"http/1.1".to_vec()],
+            // Offer HTTP/2 first, if it is offered at all.
+            Some(Version::HTTP_2) => config.alpn_protocols.sort_by_key(|p| p != b"h2"),
+            _ => {}
+        }
+
+        let timings = Arc::new(Mutex::new(Timings::default()));
+        let mut tcp = HttpConnector::new_with_resolver(TimedResolver(timings.clone()));
+        tcp.set_nodelay(true);
+        tcp.enforce_http(false);
+        let socket = SocketConnector {
+            tcp,
+            ctx: self.ctx.clone(),
+        };
+        let timed = Timed {
+            inner: self.proxies.with_connector(socket),
+            timings: timings.clone(),
+        };
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let start = Instant::now();
+        let stream = HttpsConnector::from((timed, config))
+            .call(uri)
+            .await
+            .map_err(transport_error)?;
+        let mut timings = *timings.lock().map_err(|_| HttpError::RuntimeError)?;
+        if https {
+            timings.tls = start
+                .elapsed()
+                .saturating_sub(timings.dns + timings.connect);
+        }
+        Ok(Conn {
+            stream,
+            info: Arc::new(ConnectInfo {
+                timings: Mutex::new(Some(timings)),
+            }),
+        })
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = Conn;
+    type Error = HttpError;
+    type Future = Pin<Box<dyn Future<Output = Result<Conn, HttpError>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), HttpError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+/// Connector recording the time its inner connector takes to connect, less
+/// the time spent resolving host names. It is wrapped by the TLS connector,
+/// so this covers connecting to the server or to a proxy, and opening a
+/// tunnel through the proxy.
+struct Timed<C> {
+    inner: C,
+    timings: Arc<Mutex<Timings>>,
+}
+
+impl<C> Service<Uri> for Timed<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<C::Response, C::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), C::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let timings = self.timings.clone();
+        let start = Instant::now();
+        let connect = self.inner.call(uri);
+        Box::pin(async move {
+            let stream = connect.await?;
+            if let Ok(mut timings) = timings.lock() {
+                timings.connect = start.elapsed().saturating_sub(timings.dns);
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/// Connector opening sockets, over the Unix domain socket the host of the
+/// URI is mapped to by the `HttpCtx`, or over TCP with hyper's connector,
+/// which tries the addresses of both IP families with the happy eyeballs
+/// algorithm.
+#[derive(Clone)]
+struct SocketConnector {
+    tcp: HttpConnector<TimedResolver>,
+    ctx: Arc<HttpCtx>,
+}
+
+impl Service<Uri> for SocketConnector {
+    type Response = Socket;
+    type Error = TransportError;
+    type Future = Pin<Box<dyn Future<Output = Result<Socket, TransportError>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), TransportError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match uri.host().and_then(|host| self.ctx.unix_sockets.get(host)) {
+            Some(socket) => {
+                let socket = socket.clone();
+                Box::pin(async move {
+                    tracing::trace!(socket = %socket.display(), "connecting to unix socket");
+                    Ok(connect_unix(&socket).await?)
+                })
+            }
+            None => {
+                let connect = self.tcp.call(uri);
+                Box::pin(async move { Ok(Socket::Tcp(connect.await?)) })
+            }
+        }
+    }
+}
+
+/// A socket opened by a `SocketConnector`.
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Connection for Socket {
+    fn connected(&self) -> Connected {
+        match self {
+            // Reports the address of the server, or of the proxy.
+            Socket::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Socket::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Socket::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Details about a connection, passed by hyper to each of its responses.
+#[derive(Debug)]
+struct ConnectInfo {
+    /// Phases of the connection, which are only reported with the first
+    /// response received on it.
+    timings: Mutex<Option<Timings>>,
+}
+
+impl ConnectInfo {
+    fn take_timings(&self) -> Option<Timings> {
+        self.timings.lock().ok()?.take()
+    }
+}
+
+/// A connection opened by a `Connector`.
+struct Conn {
+    stream: Stream,
+    info: Arc<ConnectInfo>,
+}
+
+impl Connection for Conn {
+    fn connected(&self) -> Connected {
+        self.stream.connected().extra(self.info.clone())
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Resolver recording the time spent resolving a host name.
+#[derive(Clone)]
+struct TimedResolver(Arc<Mutex<Timings>>);
+
+impl Service<Name> for TimedResolver {
+    type Response = <GaiResolver as Service<Name>>::Response;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let timings = self.0.clone();
+        let start = Instant::now();
+        let resolve = GaiResolver::new().call(name);
+        Box::pin(async move {
+            let addrs = resolve.await;
+            if let Ok(mut timings) = timings.lock() {
+                timings.dns = start.elapsed();
+            }
+            addrs
+        })
+    }
+}
+
+/// Proxies set with the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and
+/// `NO_PROXY` environment variables, or their lowercase variants, like
+/// most HTTP clients do.
+#[derive(Debug, Default)]
+pub(crate) struct Proxies {
+    http: Option<Proxy>,
+    https: Option<Proxy>,
+    /// Hosts and domains reached without a proxy, or `*` for all of them.
+    no_proxy: Vec<String>,
+}
+
+impl Proxies {
+    pub(crate) fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name.to_ascii_lowercase())
+                .or_else(|_| std::env::var(name))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        let all = var("ALL_PROXY");
+        let proxy = |name| {
+            var(name)
+                .or_else(|| all.clone())
+                .and_then(|p| Proxy::parse(&p))
+        };
+        Proxies {
+            http: proxy("HTTP_PROXY"),
+            https: proxy("HTTPS_PROXY"),
+            no_proxy: var("NO_PROXY")
+                .unwrap_or_default()
+                .split(',')
+                .map(|host| host.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Get a proxy connector for the connections of `ctx`, which reach the
+    /// hosts it maps to Unix domain sockets directly. `http` URLs are also
+    /// reached through a tunnel if `tunnel` is set, rather than forwarded.
+    fn connector(&self, ctx: &Arc<HttpCtx>, tunnel: bool) -> ProxyConnector<()> {
+        let mut connector = ProxyConnector::unsecured(());
+        for (scheme, proxy) in [("http", &self.http), ("https", &self.https)] {
+            let Some(proxy) = proxy else { continue };
+            let (ctx, no_proxy) = (ctx.clone(), self.no_proxy.clone());
+            let intercept = move |s: Option<&str>, host: Option<&str>, _: Option<u16>| {
+                s == Some(scheme)
+                    && host.is_some_and(|host| {
+                        !ctx.unix_sockets.contains_key(host) && !bypass(&no_proxy, host)
+                    })
+            };
+            let mut p = hyper_proxy::Proxy::new(intercept, proxy.uri.clone());
+            if let Some(auth) = &proxy.auth {
+                p.set_header(PROXY_AUTHORIZATION, auth.clone());
+            }
+            if tunnel {
+                p.force_connect();
+            }
+            connector.add_proxy(p);
+        }
+        connector
+    }
+}
+
+/// Whether `host` is reached without a proxy according to `no_proxy`.
+fn bypass(no_proxy: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy.iter().any(|no_proxy| {
+        no_proxy == "*"
+            || host == no_proxy
+            || host
+                .strip_suffix(no_proxy.as_str())
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+/// An HTTP proxy.
+#[derive(Clone, Debug)]
+struct Proxy {
+    uri: Uri,
+    /// `Proxy-Authorization` header built from the credentials in the URL
+    /// of the proxy.
+    auth: Option<HeaderValue>,
+}
+
+impl Proxy {
+    /// Parse the URL of a proxy, which may omit its `http` scheme.
+    fn parse(proxy: &str) -> Option<Self> {
+        let url = if proxy.contains("://") {
+            Url::parse(proxy)
+        } else {
+            Url::parse(&format!("http://{}", proxy))
+        };
+        let url = match url {
+            Ok(url) if url.scheme() == "http" => url,
+            _ => {
+                tracing::warn!(proxy, "ignoring proxy, only http proxies are supported");
+                return None;
+            }
+        };
+        let auth = match url.username() {
+            "" => None,
+            username => {
+                let credentials = format!(
+                    "{}:{}",
+                    percent_decode_str(username).decode_utf8_lossy(),
+                    percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy()
+                );
+                let auth = format!("Basic {}", STANDARD.encode(credentials));
+                HeaderValue::from_str(&auth).ok()
+            }
+        };
+        let uri = format!(
+            "http://{}:{}",
+            url.host_str()?,
+            url.port_or_known_default()?
+        );
+        Some(Proxy {
+            uri: uri.parse().ok()?,
+            auth,
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(socket: &Path) -> io::Result<Socket> {
+    Ok(Socket::Unix(tokio::net::UnixStream::connect(socket).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_socket: &Path) -> io::Result<Socket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+/// The lowest of two optional limits.
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Compress a request body held in memory, unless the guest already
+/// encoded it. Streamed bodies are sent as written by the guest.
+fn compress(req: &mut Request, encoding: ContentEncoding) -> Result<(), HttpError> {
+    let bytes = match &req.body {
+        RequestBody::Full(bytes) if !bytes.is_empty() => bytes,
+        _ => return Ok(()),
+    };
+    if req.headers.contains_key(CONTENT_ENCODING) {
+        return Ok(());
+    }
+    let encoded = encoding
+        .encode(bytes)
+        .map_err(|e| HttpError::RequestError(e.into()))?;
+    req.body = RequestBody::Full(encoded.into());
+    req.headers.remove(CONTENT_LENGTH);
+    req.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    Ok(())
+}
+
+/// Build the request sent to the server. hyper turns the URL into the
+/// target of HTTP/1.1 requests and adds their `Host` header. Streamed
+/// bodies are sent with chunked encoding over HTTP/1.1, which does not
+/// carry their trailers.
+fn build_request(req: &mut Request) -> Result<http::Request<Body>, HttpError> {
+    let body = req.body.take();
+    let mut res = http::Request::new(body);
+    *res.method_mut() = req.method.clone();
+    *res.uri_mut() = req.url[..Position::AfterQuery]
+        .parse()
+        .map_err(|_| HttpError::InvalidUrl)?;
+    *res.headers_mut() = req.headers.clone();
+    res.headers_mut()
+        .entry(ACCEPT)
+        .or_insert(HeaderValue::from_static("*/*"));
+    Ok(res)
+}
+
+/// Get the request to send next if the response to `req` is a redirect.
+/// `301`, `302` and `303` redirects are followed with a `GET` request
+/// without a body, while `307` and `308` redirects repeat the request, unless
+/// its body was streamed. Credentials are not sent to a different server,
+/// nor over plain HTTP after a redirect from HTTPS.
+fn redirect(req: &Request, status: StatusCode, headers: &HeaderMap) -> Option<Request> {
+    let repeat = match status {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => false,
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => true,
+        _ => return None,
+    };
+    let location = headers.get(LOCATION)?.to_str().ok()?;
+    let url = req.url.join(location).ok()?;
+
+    let mut headers = req.headers.clone();
+    headers.remove(HOST);
+    let downgrade = req.url.scheme() == "https" && url.scheme() != "https";
+    if downgrade
+        || url.host_str() != req.url.host_str()
+        || url.port_or_known_default() != req.url.port_or_known_default()
+    {
+        for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION] {
+            headers.remove(name);
+        }
+    }
+
+    let (method, body) = if repeat || req.method == Method::GET || req.method == Method::HEAD {
+        match &req.body {
+            RequestBody::Full(bytes) => (req.method.clone(), RequestBody::Full(bytes.clone())),
+            _ => return None,
+        }
+    } else {
+        for name in [
+            CONTENT_TYPE,
+            CONTENT_LENGTH,
+            CONTENT_ENCODING,
+            TRANSFER_ENCODING,
+        ] {
+            headers.remove(name);
+        }
+        (Method::GET, RequestBody::Full(Bytes::new()))
+    };
+
+    Some(Request {
+        url,
+        method,
+        headers,
+        body,
+        options: req.options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CookieJar;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Read a request head from `stream`, and reply with `response`.
+    async fn serve(stream: impl AsyncRead + AsyncWrite + Unpin, response: &[u8]) -> String {
+        serve_until(stream, b"\r\n\r\n", response).await
+    }
+
+    /// Read a request from `stream` until it ends with `end`, and reply
+    /// with `response`.
+    async fn serve_until(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        end: &[u8],
+        response: &[u8],
+    ) -> String {
+        let mut req = vec![];
+        let mut buf = [0u8; 1024];
+        while !req.ends_with(end) {
+            let n = stream.read(&mut buf).await.unwrap();
+            req.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response).await.unwrap();
+        String::from_utf8(req).unwrap()
+    }
+
+    fn transport(proxies: Proxies, ctx: HttpCtx) -> Arc<Transport> {
+        let tls = tls::Tls::new(&Default::default()).unwrap();
+        Arc::new(Transport::new(Arc::new(tls), &proxies, Arc::new(ctx)))
+    }
+
+    /// Generate a self-signed certificate for `host`, and get a TLS
+    /// acceptor presenting it and a client trusting it.
+    fn self_signed(host: &str, ctx: HttpCtx) -> (tokio_rustls::TlsAcceptor, Client) {
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let server = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let tls = tls::Tls::new(&tls::TlsConfig {
+            root_certificates: vec![cert.serialize_pem().unwrap().into_bytes()],
+            disable_system_roots: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let transport = Transport::new(Arc::new(tls), &Proxies::default(), Arc::new(ctx));
+        let client = Client::new(Arc::new(transport));
+        (Arc::new(server).into(), client)
+    }
+
+    fn client(ctx: HttpCtx) -> Client {
+        Client::new(transport(Proxies::default(), ctx))
+    }
+
+    fn get(url: &str) -> Request {
+        Request {
+            url: url.parse().unwrap(),
+            method: Method::GET,
+            headers: HeaderMap::new(),
+            body: RequestBody::Full(Bytes::new()),
+            options: Options::default(),
+        }
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut req = get("https://example.com/a");
+        req.method = Method::POST;
+        req.body = RequestBody::Full(Bytes::from_static(b"body"));
+        req.headers
+            .insert(AUTHORIZATION, HeaderValue::from_static("secret"));
+        req.headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("/b"));
+        let next = redirect(&req, StatusCode::SEE_OTHER, &headers).unwrap();
+        assert_eq!("https://example.com/b", next.url.as_str());
+        assert_eq!(Method::GET, next.method);
+        assert!(matches!(next.body, RequestBody::Full(b) if b.is_empty()));
+        assert!(next.headers.contains_key(AUTHORIZATION));
+        assert!(!next.headers.contains_key(CONTENT_TYPE));
+
+        headers.insert(LOCATION, HeaderValue::from_static("https://other.com/"));
+        let next = redirect(&req, StatusCode::TEMPORARY_REDIRECT, &headers).unwrap();
+        assert_eq!(Method::POST, next.method);
+        assert!(matches!(next.body, RequestBody::Full(b) if &b[..] == b"body"));
+        assert!(!next.headers.contains_key(AUTHORIZATION));
+
+        // Credentials are not sent in plaintext, even to the same server.
+        req.headers.insert(COOKIE, HeaderValue::from_static("a=1"));
+        headers.insert(
+            LOCATION,
+            HeaderValue::from_static("http://example.com:443/b"),
+        );
+        let next = redirect(&req, StatusCode::FOUND, &headers).unwrap();
+        assert_eq!("http://example.com:443/b", next.url.as_str());
+        assert!(!next.headers.contains_key(AUTHORIZATION));
+        assert!(!next.headers.contains_key(COOKIE));
+
+        assert!(redirect(&req, StatusCode::OK, &headers).is_none());
+        assert!(redirect(&req, StatusCode::FOUND, &HeaderMap::new()).is_none());
+
+        // A streamed body cannot be sent again.
+        req.body = RequestBody::Sent;
+        assert!(redirect(&req, StatusCode::TEMPORARY_REDIRECT, &headers).is_none());
+        assert!(redirect(&req, StatusCode::SEE_OTHER, &headers).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let first = serve(
+                stream,
+                b"HTTP/1.1 302 Found\r\nlocation: /next\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+            let (stream, _) = listener.accept().await.unwrap();
+            let second = serve(stream, b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await;
+            (first, second)
+        });
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let res = client(ctx)
+            .send(get(&format!("http://{}/first", addr)))
+            .await
+            .unwrap();
+        let (first, second) = server.await.unwrap();
+
+        assert_eq!(200, res.status);
+        assert_eq!(&b"ok"[..], &res.body[..]);
+        assert!(first.starts_with("GET /first HTTP/1.1\r\n"));
+        assert!(second.starts_with("GET /next HTTP/1.1\r\n"));
+        assert!(second.contains(&format!("host: {}\r\n", addr)));
+
+        let metadata = res.metadata;
+        assert_eq!(Version::HTTP_11, metadata.version);
+        assert_eq!(format!("http://{}/next", addr), metadata.url.as_str());
+        assert_eq!(Some(addr), metadata.remote_addr);
+        assert!(metadata.timings.total >= metadata.timings.ttfb);
+
+        let hm = metadata.to_header_map();
+        assert_eq!("HTTP/1.1", hm.get("http-version").unwrap());
+        assert_eq!(
+            addr.to_string(),
+            hm.get("remote-addr").unwrap().to_str().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(
+                stream,
+                b"HTTP/1.1 302 Found\r\nlocation: /next\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello").await;
+            // Accept the last request without ever responding.
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            max_response_size: Some(1024),
+            ..Default::default()
+        };
+        let client = client(ctx);
+
+        let mut req = get(&format!("http://{}/", addr));
+        req.options.follow_redirects = false;
+        let res = client.send(req).await.unwrap();
+        assert_eq!(302, res.status);
+
+        let mut req = get(&format!("http://{}/", addr));
+        req.options.max_response_size = Some(4);
+        let res = client.send(req).await;
+        assert!(matches!(res, Err(HttpError::ResponseTooLarge(4))));
+
+        let mut req = get(&format!("http://{}/", addr));
+        req.options.timeout = Some(Duration::from_millis(50));
+        let res = client.send(req).await;
+        assert!(matches!(res, Err(HttpError::Timeout(_))));
+    }
+
+    #[test]
+    fn test_lowest() {
+        assert_eq!(Some(1), lowest(Some(1), Some(2)));
+        assert_eq!(Some(2), lowest(None, Some(2)));
+        assert_eq!(Some(1), lowest(Some(1), None));
+        assert_eq!(None::<u32>, lowest(None, None));
+    }
+
+    #[tokio::test]
+    async fn test_cookie_jar() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(
+                stream,
+                b"HTTP/1.1 303 See Other\r\nlocation: /home\r\nset-cookie: session=abc; Path=/\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await
+        });
+
+        let jar = CookieJar::new();
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            cookie_jar: Some(jar.clone()),
+            ..Default::default()
+        };
+        let mut req = get(&format!("http://{}/login", addr));
+        req.method = Method::POST;
+        client(ctx).send(req).await.unwrap();
+
+        let home = server.await.unwrap();
+        assert!(home.starts_with("GET /home HTTP/1.1\r\n"));
+        assert!(home.contains("cookie: session=abc\r\n"));
+        assert!(jar.to_text(true).ends_with("\tsession\tabc\n"));
+    }
+
+    /// Read a request with a body of `content-length` bytes from `stream`,
+    /// and reply with the body compressed with gzip.
+    async fn serve_gzip(mut stream: impl Io) -> (String, Vec<u8>) {
+        let mut req = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            req.extend_from_slice(&buf[..n]);
+            if let Some(i) = req.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8(req[..i].to_vec()).unwrap();
+                let len: usize = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .map_or(0, |l| l.parse().unwrap());
+                if req.len() < i + 4 + len {
+                    continue;
+                }
+                let body = req[i + 4..].to_vec();
+                let gzip = ContentEncoding::Gzip.encode(&body).unwrap();
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\n\r\n",
+                    gzip.len()
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+                stream.write_all(&gzip).await.unwrap();
+                return (head, body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_encoding() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut reqs = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                reqs.push(serve_gzip(stream).await);
+            }
+            reqs
+        });
+
+        let mut req = get(&format!("http://{}/echo", addr));
+        req.method = Method::POST;
+        req.body = RequestBody::Full(Bytes::from_static(b"hello"));
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            decompression: Decompression::Auto,
+            request_compression: Some(ContentEncoding::Brotli),
+            ..Default::default()
+        };
+        let decoded = client(ctx).send(req).await.unwrap();
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let mut req = get(&format!("http://{}/echo", addr));
+        req.method = Method::POST;
+        req.body = RequestBody::Full(Bytes::from_static(b"hello"));
+        let raw = client(ctx).send(req).await.unwrap();
+
+        let reqs = server.await.unwrap();
+        let (head, body) = &reqs[0];
+        assert!(head.contains("accept-encoding: gzip, deflate, br, zstd\r\n"));
+        assert!(head.contains("content-encoding: br\r\n"));
+        assert_eq!(body, &decoded.body[..]);
+        assert_eq!(
+            b"hello".to_vec(),
+            ContentEncoding::Brotli.decode(body, None).unwrap()
+        );
+        assert_eq!(Some("gzip"), decoded.metadata.decoded_encoding.as_deref());
+        assert!(!decoded.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(
+            "gzip",
+            decoded
+                .metadata
+                .to_header_map()
+                .get("decoded-content-encoding")
+                .unwrap()
+        );
+
+        let (head, body) = &reqs[1];
+        assert!(!head.contains("accept-encoding"));
+        assert_eq!(b"hello", &body[..]);
+        assert_eq!(None, raw.metadata.decoded_encoding);
+        assert_eq!("gzip", raw.headers.get(CONTENT_ENCODING).unwrap());
+        assert_eq!(
+            b"hello".to_vec(),
+            ContentEncoding::Gzip.decode(&raw.body, None).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_streaming_body() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_until(
+                stream,
+                b"0\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await
+        });
+
+        let (mut sender, body) = Body::channel();
+        let mut req = get(&format!("http://{}/upload", addr));
+        req.method = Method::POST;
+        req.body = RequestBody::Streaming(body);
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let res = tokio::spawn(async move { client(ctx).send(req).await });
+
+        for chunk in ["hello", " world"] {
+            sender
+                .send_data(Bytes::from_static(chunk.as_bytes()))
+                .await
+                .unwrap();
+        }
+        drop(sender);
+
+        let res = res.await.unwrap().unwrap();
+        let req = server.await.unwrap();
+        assert_eq!(200, res.status);
+        assert!(res.trailers.is_empty());
+        assert!(req.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(req.contains("transfer-encoding: chunked\r\n"));
+        assert!(req.ends_with("\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_not_allowed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(
+                stream,
+                b"HTTP/1.1 301 Moved Permanently\r\nlocation: http://example.com/\r\n\r\n",
+            )
+            .await;
+        });
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let res = client(ctx).send(get(&format!("http://{}/", addr))).await;
+        assert!(matches!(res, Err(HttpError::DestinationNotAllowed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let res = client(HttpCtx::default())
+            .send(get(&format!("http://{}/", addr)))
+            .await;
+        assert!(matches!(res, Err(HttpError::ConnectionRefused(_))));
+    }
+
+    #[tokio::test]
+    async fn test_dns_error() {
+        let res = client(HttpCtx::default())
+            .send(get("http://host.invalid/"))
+            .await;
+        assert!(matches!(res, Err(HttpError::DnsError(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_send_over_unix_socket() {
+        let socket = std::env::temp_dir().join(format!("wasi-http-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(
+                stream,
+                b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nx-sidecar: yes\r\n\r\nok",
+            )
+            .await
+        });
+
+        let ctx = HttpCtx {
+            unix_sockets: [("sidecar.local".to_string(), socket.clone())].into(),
+            ..Default::default()
+        };
+        let res = client(ctx)
+            .send(get("http://sidecar.local/ping?x=1"))
+            .await
+            .unwrap();
+        let req = server.await.unwrap();
+        std::fs::remove_file(&socket).unwrap();
+
+        assert_eq!(200, res.status);
+        assert_eq!("yes", res.headers.get("x-sidecar").unwrap());
+        assert_eq!(&b"ok"[..], &res.body[..]);
+        assert_eq!(None, res.metadata.remote_addr);
+        assert!(req.starts_with("GET /ping?x=1 HTTP/1.1\r\n"));
+        assert!(req.contains("host: sidecar.local\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_reuse() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for _ in 0..2 {
+                serve(
+                    &mut stream,
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+                )
+                .await;
+            }
+        });
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let transport = transport(Proxies::default(), ctx);
+        let url = format!("http://{}/", addr);
+        let first = Client::new(transport.clone())
+            .send(get(&url))
+            .await
+            .unwrap();
+        // Another client of the same transport uses the idle connection,
+        // as the server only accepts one.
+        let second = Client::new(transport).send(get(&url)).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(Some(addr), first.metadata.remote_addr);
+        assert_eq!(Some(addr), second.metadata.remote_addr);
+        assert!(first.metadata.timings.connect > Duration::ZERO);
+        assert_eq!(Duration::ZERO, second.metadata.timings.connect);
+    }
+
+    #[tokio::test]
+    async fn test_send_through_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await
+        });
+
+        let proxies = Proxies {
+            http: Proxy::parse(&format!("user:p%40ss@{}", addr)),
+            ..Default::default()
+        };
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec!["http://example.com".to_string()]),
+            ..Default::default()
+        };
+        let res = Client::new(transport(proxies, ctx))
+            .send(get("http://example.com/path?q=1"))
+            .await
+            .unwrap();
+        let req = proxy.await.unwrap();
+
+        assert_eq!(200, res.status);
+        assert_eq!(Some(addr), res.metadata.remote_addr);
+        assert!(req.starts_with("GET http://example.com/path?q=1 HTTP/1.1\r\n"));
+        assert!(req.contains("host: example.com\r\n"));
+        // base64 of `user:p@ss`.
+        assert!(req.contains("proxy-authorization: Basic dXNlcjpwQHNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let accepted = serve(stream, b"HTTP/1.1 200 Connection established\r\n\r\n").await;
+            let (stream, _) = listener.accept().await.unwrap();
+            let refused = serve(
+                stream,
+                b"HTTP/1.1 407 Proxy Authentication Required\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+            (accepted, refused)
+        });
+
+        let proxy_url = format!("user:pass@{}", addr);
+        let proxies = Proxies {
+            http: Proxy::parse(&proxy_url),
+            https: Proxy::parse(&proxy_url),
+            ..Default::default()
+        };
+        let client = Client::new(transport(proxies, HttpCtx::default()));
+        // WebSockets are tunnelled even for `ws` URLs. The proxy closes the
+        // tunnel before the handshake.
+        let url = "ws://example.com/".parse().unwrap();
+        let ws = client.websocket(url, HeaderMap::new()).await;
+        let res = client.send(get("https://example.com/")).await;
+        let (accepted, refused) = proxy.await.unwrap();
+
+        assert!(ws.is_err());
+        assert!(matches!(res, Err(HttpError::RequestError(_))));
+        assert_eq!(
+            "CONNECT example.com:80 HTTP/1.1\r\nHost: example.com:80\r\nproxy-authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+            accepted
+        );
+        assert!(refused.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_proxies() {
+        let proxies = Proxies {
+            http: Proxy::parse("http://proxy.local:3128"),
+            https: Proxy::parse("proxy.local"),
+            no_proxy: vec!["internal.com".to_string(), "10.0.0.1".to_string()],
+        };
+        let ctx = HttpCtx {
+            unix_sockets: [("sidecar.local".to_string(), "/tmp/sidecar.sock".into())].into(),
+            ..Default::default()
+        };
+        let connector = proxies.connector(&Arc::new(ctx), false);
+        let proxy = |url: &str| {
+            let uri = url.parse::<Uri>().unwrap();
+            let proxy = connector
+                .proxies()
+                .iter()
+                .find(|proxy| proxy.intercept().matches(&uri))?;
+            Some((proxy.uri().host()?, proxy.uri().port_u16()?))
+        };
+        assert_eq!(Some(("proxy.local", 3128)), proxy("http://example.com/"));
+        assert_eq!(Some(("proxy.local", 80)), proxy("https://example.com/"));
+        assert_eq!(None, proxy("http://internal.com/"));
+        assert_eq!(None, proxy("https://api.internal.com/"));
+        assert_eq!(
+            Some(("proxy.local", 3128)),
+            proxy("http://notinternal.com/")
+        );
+        assert_eq!(None, proxy("http://10.0.0.1:8080/"));
+        // Hosts mapped to Unix domain sockets are reached directly.
+        assert_eq!(None, proxy("http://sidecar.local/"));
+
+        assert!(Proxy::parse("socks5://proxy.local").is_none());
+        let all = Proxies {
+            http: Proxy::parse("proxy.local"),
+            no_proxy: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let uri = Uri::from_static("http://example.com/");
+        let connector = all.connector(&Default::default(), false);
+        assert!(!connector.proxies()[0].intercept().matches(&uri));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_send_over_unix_socket_with_tls() {
+        let socket =
+            std::env::temp_dir().join(format!("wasi-http-tls-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let ctx = HttpCtx {
+            unix_sockets: [("sidecar.local".to_string(), socket.clone())].into(),
+            ..Default::default()
+        };
+        let (acceptor, client) = self_signed("sidecar.local", ctx);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            serve(stream, b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await
+        });
+        let res = client
+            .send(get("https://sidecar.local/ping"))
+            .await
+            .unwrap();
+        let req = server.await.unwrap();
+        std::fs::remove_file(&socket).unwrap();
+
+        assert_eq!(&b"ok"[..], &res.body[..]);
+        assert_eq!(None, res.metadata.remote_addr);
+        assert!(res.metadata.timings.tls > Duration::ZERO);
+        assert!(req.starts_with("GET /ping HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Accept the connection without ever completing the handshake.
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let ctx = HttpCtx {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let url = format!("ws://{}/", addr).parse().unwrap();
+        let res = client(ctx).websocket(url, HeaderMap::new()).await;
+        assert!(matches!(res, Err(HttpError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_pin_after_redirect() {
+        let tls_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_addr = tls_listener.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let location = format!(
+                "HTTP/1.1 302 Found\r\nlocation: https://localhost:{}/\r\ncontent-length: 0\r\n\r\n",
+                tls_addr.port()
+            );
+            serve(stream, location.as_bytes()).await;
+        });
+
+        let ctx = HttpCtx {
+            allowed_hosts: Some(vec![
+                "http://127.0.0.1".to_string(),
+                "https://localhost".to_string(),
+            ]),
+            certificate_pins: [(
+                "localhost".to_string(),
+                vec![tls::CertificatePin::PublicKey([0; 32])],
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let (acceptor, client) = self_signed("localhost", ctx);
+        tokio::spawn(async move {
+            let (stream, _) = tls_listener.accept().await.unwrap();
+            // The client aborts the handshake.
+            assert!(acceptor.accept(stream).await.is_err());
+        });
+
+        let res = client.send(get(&format!("http://{}/", addr))).await;
+        assert!(matches!(res, Err(HttpError::CertificatePinMismatch(_))));
+    }
+}
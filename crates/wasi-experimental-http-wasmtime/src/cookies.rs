@@ -0,0 +1,449 @@
+//! Cookie jar storing the cookies set by servers, and sending them back with
+//! later requests of the same instance.
+//!
+//! Cookies are saved and exchanged with guests in the Netscape cookie file
+//! format used by curl and wget, with one cookie per line and tab-separated
+//! fields: domain, whether subdomains are included, path, whether the cookie
+//! is secure, expiry as a Unix timestamp (`0` for session cookies), name and
+//! value.
+
+use anyhow::{Context, Error};
+use http::{
+    header::{COOKIE, SET_COOKIE},
+    HeaderMap, HeaderValue,
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+/// Prefix of the domain of `HttpOnly` cookies in cookie files.
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// Cookies of an instance. Clones share the same cookies, so that a jar
+/// stored in an `HttpCtx` is kept across requests.
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Domain the cookie is sent to, without a leading dot.
+    domain: String,
+    /// Whether the cookie is also sent to subdomains of `domain`, which is
+    /// the case when the server set the `Domain` attribute.
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// Expiry time, or `None` for a session cookie.
+    expires: Option<SystemTime>,
+}
+
+impl CookieJar {
+    /// Create an empty cookie jar.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a cookie jar from a cookie file. A missing file is an empty jar.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        let jar = Self::new();
+        *jar.lock() = parse_cookie_file(&text)
+            .with_context(|| format!("invalid cookie file {}", path.display()))?;
+        Ok(jar)
+    }
+
+    /// Save the cookies to a cookie file. Session cookies are not saved.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut text = String::from("# Netscape HTTP Cookie File\n");
+        text.push_str(&self.to_text(false));
+        std::fs::write(path, text).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    /// Remove all cookies.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Get the cookies that are not expired, one per line, optionally
+    /// including session cookies.
+    pub(crate) fn to_text(&self, session: bool) -> String {
+        let now = SystemTime::now();
+        let mut res = String::new();
+        for cookie in self.lock().iter() {
+            if cookie.is_expired(now) || (cookie.expires.is_none() && !session) {
+                continue;
+            }
+            res.push_str(&cookie.to_line());
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Add the cookies matching `url` to the `Cookie` header of a request,
+    /// after the cookies set by the guest, if any.
+    pub(crate) fn add_cookie_header(&self, url: &Url, headers: &mut HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies: Vec<_> = self
+            .lock()
+            .iter()
+            .filter(|c| !c.is_expired(now) && c.matches(url))
+            .cloned()
+            .collect();
+        if cookies.is_empty() {
+            return;
+        }
+        // Cookies with longer paths are sent first.
+        cookies.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+
+        let mut value = headers
+            .get(COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_default();
+        for cookie in cookies {
+            if !value.is_empty() {
+                value.push_str("; ");
+            }
+            value.push_str(&format!("{}={}", cookie.name, cookie.value));
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(COOKIE, value);
+        }
+    }
+
+    /// Store the cookies set by the response to a request to `url`.
+    pub(crate) fn store_response_cookies(&self, url: &Url, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies = self.lock();
+        for value in headers.get_all(SET_COOKIE) {
+            let cookie = match value
+                .to_str()
+                .ok()
+                .and_then(|v| parse_set_cookie(v, url, now))
+            {
+                Some(cookie) => cookie,
+                None => {
+                    tracing::debug!(?value, "ignoring invalid cookie");
+                    continue;
+                }
+            };
+            let existing = cookies.iter().position(|c| {
+                (&c.name, &c.domain, &c.path) == (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            // Servers delete cookies by setting them with an expiry in the past.
+            // Replaced cookies keep their position, so that they are still sent
+            // in the order they were first set.
+            match (existing, cookie.is_expired(now)) {
+                (Some(i), true) => {
+                    cookies.remove(i);
+                }
+                (Some(i), false) => cookies[i] = cookie,
+                (None, true) => {}
+                (None, false) => cookies.push(cookie),
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Cookie>> {
+        self.cookies
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Cookie {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    /// Whether the cookie should be sent with a request to `url`.
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let domain_matches = host == self.domain
+            || (self.include_subdomains && host.ends_with(&format!(".{}", self.domain)));
+        let path = url.path();
+        let path_matches = path == self.path
+            || (path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')));
+        domain_matches && path_matches && (!self.secure || url.scheme() == "https")
+    }
+
+    fn to_line(&self) -> String {
+        let flag = |b: bool| if b { "TRUE" } else { "FALSE" };
+        let expires = self
+            .expires
+            .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!(
+            "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            if self.http_only { HTTP_ONLY_PREFIX } else { "" },
+            if self.include_subdomains { "." } else { "" },
+            self.domain,
+            flag(self.include_subdomains),
+            self.path,
+            flag(self.secure),
+            expires,
+            self.name,
+            self.value
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self, Error> {
+        let (http_only, line) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let fields: Vec<_> = line.splitn(7, '\t').collect();
+        anyhow::ensure!(fields.len() == 7, "Invalid cookie: [{}]", line);
+        let flag = |s: &str| s.eq_ignore_ascii_case("TRUE");
+        let expires: u64 = fields[4].parse()?;
+        Ok(Cookie {
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+            domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+            include_subdomains: flag(fields[1]),
+            path: fields[2].to_string(),
+            secure: flag(fields[3]),
+            http_only,
+            expires: match expires {
+                0 => None,
+                secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            },
+        })
+    }
+}
+
+/// Parse the cookies of a cookie file, ignoring comments and empty lines.
+fn parse_cookie_file(text: &str) -> Result<Vec<Cookie>, Error> {
+    text.lines()
+        .filter(|l| {
+            !l.trim().is_empty() && (!l.starts_with('#') || l.starts_with(HTTP_ONLY_PREFIX))
+        })
+        .map(Cookie::from_line)
+        .collect()
+}
+
+/// Parse a `Set-Cookie` header received in response to a request to `url`.
+/// Returns `None` if the cookie is invalid, or if it is set for a domain
+/// that `url` does not belong to.
+fn parse_set_cookie(header: &str, url: &Url, now: SystemTime) -> Option<Cookie> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        include_subdomains: false,
+        path: default_path(url),
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    for attr in parts {
+        let (key, value) = match attr.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attr.trim(), ""),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                // Cookies can only be set for the host of the request, or
+                // one of its parent domains other than a top-level domain.
+                if host != domain
+                    && (!host.ends_with(&format!(".{}", domain)) || !domain.contains('.'))
+                {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.include_subdomains = true;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "expires" => {
+                if let Ok(expires) = httpdate::parse_http_date(value) {
+                    cookie.expires = Some(expires);
+                }
+            }
+            "max-age" => {
+                if let Ok(secs) = value.parse::<i64>() {
+                    max_age = Some(secs);
+                }
+            }
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _ => {}
+        }
+    }
+    // `Max-Age` takes precedence over `Expires`.
+    if let Some(secs) = max_age {
+        cookie.expires = Some(match u64::try_from(secs) {
+            Ok(secs) if secs > 0 => now + Duration::from_secs(secs),
+            _ => UNIX_EPOCH,
+        });
+    }
+    Some(cookie)
+}
+
+/// The path a cookie applies to when it does not have a `Path` attribute,
+/// which is the directory of the request path.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => url.path()[..i].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        s.parse().unwrap()
+    }
+
+    fn set_cookies(jar: &CookieJar, url: &Url, cookies: &[&'static str]) {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(SET_COOKIE, HeaderValue::from_static(cookie));
+        }
+        jar.store_response_cookies(url, &headers);
+    }
+
+    fn cookie_header(jar: &CookieJar, url: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        jar.add_cookie_header(&self::url(url), &mut headers);
+        headers.get(COOKIE).map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_parse_set_cookie() {
+        let now = SystemTime::now();
+        let url = url("https://api.example.com/v1/login");
+        let cookie = parse_set_cookie(
+            "session=abc; Domain=.example.com; Path=/; Max-Age=60; Secure; HttpOnly",
+            &url,
+            now,
+        )
+        .unwrap();
+        assert_eq!("session", cookie.name);
+        assert_eq!("abc", cookie.value);
+        assert_eq!("example.com", cookie.domain);
+        assert!(cookie.include_subdomains);
+        assert_eq!("/", cookie.path);
+        assert!(cookie.secure && cookie.http_only);
+        assert_eq!(Some(now + Duration::from_secs(60)), cookie.expires);
+
+        let cookie = parse_set_cookie("a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT", &url, now);
+        let cookie = cookie.unwrap();
+        assert_eq!("api.example.com", cookie.domain);
+        assert!(!cookie.include_subdomains);
+        assert_eq!("/v1", cookie.path);
+        assert!(cookie.is_expired(now));
+
+        assert!(parse_set_cookie("a=1; Domain=other.com", &url, now).is_none());
+        assert!(parse_set_cookie("a=1; Domain=com", &url, now).is_none());
+        assert!(parse_set_cookie("no-value", &url, now).is_none());
+    }
+
+    #[test]
+    fn test_cookie_header() {
+        let jar = CookieJar::new();
+        set_cookies(
+            &jar,
+            &url("https://example.com/login"),
+            &[
+                "session=abc; Path=/",
+                "pref=dark; Path=/app",
+                "token=xyz; Secure",
+                "shared=1; Domain=example.com",
+            ],
+        );
+
+        assert_eq!(
+            Some("pref=dark; session=abc; token=xyz; shared=1".to_string()),
+            cookie_header(&jar, "https://example.com/app/page")
+        );
+        assert_eq!(
+            Some("session=abc; shared=1".to_string()),
+            cookie_header(&jar, "http://example.com/application")
+        );
+        assert_eq!(
+            Some("shared=1".to_string()),
+            cookie_header(&jar, "https://www.example.com/")
+        );
+        assert_eq!(None, cookie_header(&jar, "https://example.org/"));
+
+        // Cookies are replaced, and removed when they expire.
+        set_cookies(
+            &jar,
+            &url("https://example.com/"),
+            &["session=def; Path=/", "token=; Max-Age=0"],
+        );
+        assert_eq!(
+            Some("session=def; shared=1".to_string()),
+            cookie_header(&jar, "https://example.com/")
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("guest=1"));
+        jar.add_cookie_header(&url("https://example.com/"), &mut headers);
+        assert_eq!(
+            "guest=1; session=def; shared=1",
+            headers.get(COOKIE).unwrap()
+        );
+
+        jar.clear();
+        assert_eq!(None, cookie_header(&jar, "https://example.com/"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let jar = CookieJar::new();
+        set_cookies(
+            &jar,
+            &url("https://example.com/"),
+            &[
+                "session=abc",
+                "persistent=1; Domain=example.com; Max-Age=3600; HttpOnly",
+            ],
+        );
+        assert_eq!(2, jar.to_text(true).lines().count());
+
+        let path = std::env::temp_dir().join(format!("wasi-http-cookies-{}", std::process::id()));
+        jar.save(&path).unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let text = loaded.to_text(true);
+        assert_eq!(jar.to_text(false), text);
+        assert!(text.starts_with("#HttpOnly_.example.com\tTRUE\t/\tFALSE\t"));
+        assert!(text.ends_with("\tpersistent\t1\n"));
+
+        assert!(CookieJar::load(&path).unwrap().to_text(true).is_empty());
+        assert!(parse_cookie_file("example.com\tFALSE\t/").is_err());
+    }
+}
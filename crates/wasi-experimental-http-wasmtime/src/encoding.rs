@@ -0,0 +1,284 @@
+//! Content codings of request and response bodies.
+//!
+//! The host can advertise and decode gzip, deflate, brotli and zstd response
+//! bodies, and compress the request bodies of guests, as configured by the
+//! `HttpCtx` of the instance.
+
+use crate::HttpError;
+use anyhow::{bail, Error};
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH},
+    HeaderMap, HeaderValue,
+};
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+/// A content coding the host can decode response bodies from, and encode
+/// request bodies with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// The name of the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    /// Parse a coding of a `Content-Encoding` header.
+    fn from_header(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+            "deflate" => Some(ContentEncoding::Deflate),
+            "br" => Some(ContentEncoding::Brotli),
+            "zstd" => Some(ContentEncoding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Decode `bytes`, stopping once more than `max_size` bytes are decoded.
+    pub(crate) fn decode(&self, bytes: &[u8], max_size: Option<usize>) -> io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Gzip => read(flate2::read::MultiGzDecoder::new(bytes), max_size),
+            // `deflate` bodies should be zlib streams, but some servers send
+            // raw deflate data instead.
+            ContentEncoding::Deflate => read(flate2::read::ZlibDecoder::new(bytes), max_size)
+                .or_else(|_| read(flate2::read::DeflateDecoder::new(bytes), max_size)),
+            ContentEncoding::Brotli => read(brotli::Decompressor::new(bytes, 4096), max_size),
+            ContentEncoding::Zstd => read(zstd::stream::read::Decoder::new(bytes)?, max_size),
+        }
+    }
+
+    pub(crate) fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            ContentEncoding::Brotli => {
+                let mut res = Vec::new();
+                brotli::CompressorReader::new(bytes, 4096, 5, 22).read_to_end(&mut res)?;
+                Ok(res)
+            }
+            ContentEncoding::Zstd => zstd::stream::encode_all(bytes, 0),
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            "br" | "brotli" => Ok(ContentEncoding::Brotli),
+            "zstd" => Ok(ContentEncoding::Zstd),
+            _ => bail!(
+                "unsupported content encoding {}, expected gzip, deflate, br or zstd",
+                s
+            ),
+        }
+    }
+}
+
+/// How the host handles the content coding of response bodies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Decompression {
+    /// Response bodies are passed to guests as received, with their
+    /// `Content-Encoding` header.
+    #[default]
+    Passthrough,
+    /// The host advertises the codings it supports with `Accept-Encoding`,
+    /// unless the guest sets that header itself, and decodes response bodies
+    /// encoded with them before passing them to the guest.
+    Auto,
+}
+
+impl FromStr for Decompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" | "none" => Ok(Decompression::Passthrough),
+            "auto" => Ok(Decompression::Auto),
+            _ => bail!(
+                "unsupported decompression mode {}, expected auto or passthrough",
+                s
+            ),
+        }
+    }
+}
+
+/// The `Accept-Encoding` header sent when decompression is automatic.
+pub(crate) fn accept_encoding() -> HeaderValue {
+    HeaderValue::from_static("gzip, deflate, br, zstd")
+}
+
+/// Read `reader` to the end, or until it returned more than `max_size` bytes.
+fn read(reader: impl Read, max_size: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    reader
+        .take(max_size.map_or(u64::MAX, |max| max as u64 + 1))
+        .read_to_end(&mut res)?;
+    Ok(res)
+}
+
+/// Decode a response body according to its `Content-Encoding` headers, and
+/// return the decoded body along with the codings that were removed, in
+/// the order they were applied. Bodies encoded with a coding the host does not
+/// support are returned as is, with their headers.
+pub(crate) fn decode(
+    headers: &mut HeaderMap,
+    body: Bytes,
+    max_size: Option<usize>,
+) -> Result<(Bytes, Option<String>), HttpError> {
+    let mut names = vec![];
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|e| HttpError::DecodeError(e.into()))?;
+        names.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty() && !s.eq_ignore_ascii_case("identity")),
+        );
+    }
+    if names.is_empty() || body.is_empty() {
+        return Ok((body, None));
+    }
+    let encodings = match names
+        .iter()
+        .map(|s| ContentEncoding::from_header(s))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(encodings) => encodings,
+        None => {
+            tracing::debug!(?names, "unsupported content encoding, passing body through");
+            return Ok((body, None));
+        }
+    };
+
+    let mut body = body.to_vec();
+    for encoding in encodings.iter().rev() {
+        body = encoding
+            .decode(&body, max_size)
+            .map_err(|e| HttpError::DecodeError(e.into()))?;
+        if let Some(max) = max_size.filter(|max| body.len() > *max) {
+            return Err(HttpError::ResponseTooLarge(max));
+        }
+    }
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    let decoded = encodings
+        .iter()
+        .map(ContentEncoding::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok((body.into(), Some(decoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ContentEncoding; 4] = [
+        ContentEncoding::Gzip,
+        ContentEncoding::Deflate,
+        ContentEncoding::Brotli,
+        ContentEncoding::Zstd,
+    ];
+
+    fn headers(content_encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("42"));
+        headers
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let body = b"hello hello hello hello".repeat(10);
+        for encoding in ALL {
+            let encoded = encoding.encode(&body).unwrap();
+            assert_ne!(body, encoded);
+            assert_eq!(body, encoding.decode(&encoded, None).unwrap());
+            assert_eq!(encoding, encoding.as_str().parse().unwrap());
+        }
+        assert!("compress".parse::<ContentEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let body = Bytes::from_static(b"hello");
+        let encoded = ContentEncoding::Brotli
+            .encode(&ContentEncoding::Gzip.encode(&body).unwrap())
+            .unwrap();
+        let mut hm = headers("gzip, br");
+        let (decoded, encodings) = decode(&mut hm, encoded.into(), None).unwrap();
+        assert_eq!(body, decoded);
+        assert_eq!(Some("gzip, br".to_string()), encodings);
+        assert!(hm.is_empty());
+
+        // Raw deflate data is also accepted.
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body).unwrap();
+        let encoded = encoder.finish().unwrap();
+        let (decoded, _) = decode(&mut headers("deflate"), encoded.into(), None).unwrap();
+        assert_eq!(body, decoded);
+
+        let mut hm = headers("identity");
+        let (decoded, encodings) = decode(&mut hm, body.clone(), None).unwrap();
+        assert_eq!(body, decoded);
+        assert_eq!(None, encodings);
+
+        let mut hm = headers("compress");
+        let (decoded, encodings) = decode(&mut hm, body.clone(), None).unwrap();
+        assert_eq!(body, decoded);
+        assert_eq!(None, encodings);
+        assert!(hm.contains_key(CONTENT_ENCODING));
+
+        assert!(matches!(
+            decode(&mut headers("gzip"), body, None),
+            Err(HttpError::DecodeError(_))
+        ));
+
+        let large = Bytes::from(vec![0; 1024]);
+        let encoded = ContentEncoding::Zstd.encode(&large).unwrap();
+        let (decoded, _) =
+            decode(&mut headers("zstd"), encoded.clone().into(), Some(1024)).unwrap();
+        assert_eq!(large, decoded);
+        assert!(matches!(
+            decode(&mut headers("zstd"), encoded.into(), Some(1023)),
+            Err(HttpError::ResponseTooLarge(1023))
+        ));
+    }
+
+    #[test]
+    fn test_decompression_from_str() {
+        assert_eq!(Decompression::Auto, "auto".parse().unwrap());
+        assert_eq!(Decompression::Passthrough, "none".parse().unwrap());
+        assert!("gzip".parse::<Decompression>().is_err());
+    }
+}
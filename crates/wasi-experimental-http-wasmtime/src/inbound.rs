@@ -0,0 +1,105 @@
+//! Inbound requests received by the host and handled by guests.
+//!
+//! A server embedding the host stores the request it received in the
+//! `HttpCtx` of a new instance, and calls the handler of the guest, which
+//! reads the request and sets its response through the `inbound_*` host
+//! functions. The server then takes the response back from the request.
+
+use bytes::Bytes;
+use http::{HeaderMap, Request, Response};
+use std::sync::{Arc, Mutex};
+
+/// An inbound request handled by a guest, and the response it set. Clones
+/// share the same request, so that the server can get the response the
+/// guest set in the `HttpCtx` of its instance.
+#[derive(Clone, Debug)]
+pub struct InboundRequest {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    method: String,
+    url: String,
+    headers: HeaderMap,
+    body: Bytes,
+    /// How much of the body the guest read.
+    pos: usize,
+    response: Option<Response<Bytes>>,
+}
+
+impl InboundRequest {
+    /// Create an inbound request. Its URI should be absolute, as the guest
+    /// gets it as its URL.
+    pub fn new(req: Request<Bytes>) -> Self {
+        let (parts, body) = req.into_parts();
+        InboundRequest {
+            inner: Arc::new(Mutex::new(Inner {
+                method: parts.method.to_string(),
+                url: parts.uri.to_string(),
+                headers: parts.headers,
+                body,
+                pos: 0,
+                response: None,
+            })),
+        }
+    }
+
+    /// Take the response the guest set, if any.
+    pub fn take_response(&self) -> Option<Response<Bytes>> {
+        self.inner.lock().unwrap().response.take()
+    }
+
+    pub(crate) fn method(&self) -> String {
+        self.inner.lock().unwrap().method.clone()
+    }
+
+    pub(crate) fn url(&self) -> String {
+        self.inner.lock().unwrap().url.clone()
+    }
+
+    pub(crate) fn headers(&self) -> HeaderMap {
+        self.inner.lock().unwrap().headers.clone()
+    }
+
+    /// Read at most `len` bytes of the body, after what was already read.
+    pub(crate) fn body_read(&self, len: usize) -> Bytes {
+        let mut inner = self.inner.lock().unwrap();
+        let available = std::cmp::min(len, inner.body.len() - inner.pos);
+        let chunk = inner.body.slice(inner.pos..inner.pos + available);
+        inner.pos += available;
+        chunk
+    }
+
+    /// Set the response, replacing the one the guest set before, if any.
+    pub(crate) fn set_response(&self, res: Response<Bytes>) {
+        self.inner.lock().unwrap().response = Some(res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_request() {
+        let req = Request::post("http://localhost:3000/a?b=c")
+            .header("x-a", "b")
+            .body(Bytes::from("hello"))
+            .unwrap();
+        let inbound = InboundRequest::new(req);
+        assert_eq!("POST", inbound.method());
+        assert_eq!("http://localhost:3000/a?b=c", inbound.url());
+        assert_eq!("b", inbound.headers()["x-a"]);
+        assert_eq!(Bytes::from("hel"), inbound.body_read(3));
+        assert_eq!(Bytes::from("lo"), inbound.body_read(3));
+        assert!(inbound.body_read(3).is_empty());
+
+        assert!(inbound.take_response().is_none());
+        inbound
+            .clone()
+            .set_response(Response::new(Bytes::from("bye")));
+        assert_eq!("bye", inbound.take_response().unwrap().body());
+        assert!(inbound.take_response().is_none());
+    }
+}
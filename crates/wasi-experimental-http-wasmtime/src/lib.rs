@@ -1,18 +1,45 @@
 use anyhow::Error;
 use bytes::Bytes;
-use futures::executor::block_on;
-use http::{header::HeaderName, HeaderMap, HeaderValue};
-use reqwest::{Client, Method};
+use futures::{
+    executor::block_on,
+    future::{join_all, select, select_all, Either},
+    Future, FutureExt,
+};
+use http::{header::HeaderName, HeaderMap, HeaderValue, Method};
 use std::{
     collections::HashMap,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, PoisonError, RwLock},
+    time::Duration,
 };
-use tokio::runtime::Handle;
+use tokio::{runtime::Handle, task::JoinHandle};
+use tracing::Instrument;
 use url::Url;
 use wasmtime::*;
-
-const MEMORY: &str = "memory";
+use wiggle::GuestPtr;
+
+mod client;
+mod cookies;
+mod encoding;
+mod inbound;
+mod metrics;
+mod tls;
+mod trace;
+mod websocket;
+mod witx;
+
+pub use cookies::CookieJar;
+pub use encoding::{ContentEncoding, Decompression};
+pub use inbound::InboundRequest;
+pub use metrics::Metrics;
+pub use tls::{CertificatePin, ClientIdentity, TlsConfig, TlsVersion};
+pub use trace::TraceContext;
+
+/// Version of the ABI exposed by the `wasi_experimental_http_v2` module,
+/// whose functions are frozen: adding functions requires a new module and
+/// version.
+const ABI_VERSION: u32 = 2;
 const ALLOW_ALL_HOSTS: &str = "insecure:allow-all";
 
 pub type WasiHttpHandle = u32;
@@ -28,30 +55,150 @@ struct Body {
 struct Response {
     headers: HeaderMap,
     body: Body,
+    trailers: HeaderMap,
+    metadata: client::Metadata,
+}
+
+/// A request whose body is streamed by the guest. The request is sent
+/// on the runtime of the instance while the guest writes the body.
+struct OutgoingRequest {
+    body: hyper::body::Sender,
+    trailers: HeaderMap,
+    response: JoinHandle<Result<client::Response, HttpError>>,
+}
+
+/// A request sent without blocking the guest. It runs on the runtime of the
+/// instance until the guest retrieves its response with `req_result`.
+enum PendingRequest {
+    Running(JoinHandle<Result<client::Response, HttpError>>),
+    /// The request completed, but its response was not retrieved yet.
+    Done(Box<Result<client::Response, HttpError>>),
+}
+
+impl PendingRequest {
+    /// Check whether the request completed, without blocking.
+    fn is_ready(&mut self) -> bool {
+        if let PendingRequest::Running(task) = self {
+            match task.now_or_never() {
+                Some(res) => *self = PendingRequest::Done(Box::new(task_result(res))),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Get the response of the request, waiting for it if needed.
+    fn wait(self) -> Result<client::Response, HttpError> {
+        match self {
+            PendingRequest::Running(task) => task_result(block_on(task)),
+            PendingRequest::Done(res) => *res,
+        }
+    }
+}
+
+/// The result of a request sent in the background.
+fn task_result(
+    res: Result<Result<client::Response, HttpError>, tokio::task::JoinError>,
+) -> Result<client::Response, HttpError> {
+    res.map_err(|_| HttpError::RuntimeError)?
+}
+
+/// How header maps are encoded when exchanged with the guest.
+#[derive(Clone, Copy, Debug)]
+enum HeaderEncoding {
+    /// One `name:value` line per header, used by `req` and `headers_get_all`.
+    /// Header values must be valid UTF-8.
+    Text,
+    /// Each header as a little-endian `u32` name length, the name, a
+    /// little-endian `u32` value length and the value, used by `req_binary`
+    /// and `headers_get_all_binary`. Header values can contain any bytes
+    /// allowed by HTTP.
+    Binary,
+}
+
+/// The part of the inbound request written by `HostCalls::inbound_get`.
+#[derive(Clone, Copy)]
+enum InboundPart {
+    Method,
+    Url,
+    Headers,
 }
 
 /// Host state for the responses of the instance.
 #[derive(Default)]
 struct State {
     responses: HashMap<WasiHttpHandle, Response>,
+    outgoing: HashMap<WasiHttpHandle, OutgoingRequest>,
+    pending: HashMap<WasiHttpHandle, PendingRequest>,
+    websockets: HashMap<WasiHttpHandle, websocket::WebSocket>,
     current_handle: WasiHttpHandle,
+    last_error: Option<String>,
+}
+
+impl State {
+    /// Check that the guest can start `count` more requests.
+    fn check_sessions(&self, ctx: &HttpCtx, count: usize) -> Result<(), HttpError> {
+        if let Some(max) = ctx.max_concurrent_requests {
+            if self.open_handles() + count > max as usize {
+                return Err(HttpError::TooManySessions);
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of responses, outgoing requests, pending requests and
+    /// WebSockets of the guest.
+    fn open_handles(&self) -> usize {
+        self.responses.len() + self.outgoing.len() + self.pending.len() + self.websockets.len()
+    }
+
+    /// Find a handle that is not used by a response, an outgoing request,
+    /// a pending request, or a WebSocket.
+    fn next_handle(&mut self) -> Result<WasiHttpHandle, HttpError> {
+        let initial_handle = self.current_handle;
+        while self.responses.contains_key(&self.current_handle)
+            || self.outgoing.contains_key(&self.current_handle)
+            || self.pending.contains_key(&self.current_handle)
+            || self.websockets.contains_key(&self.current_handle)
+        {
+            self.current_handle += 1;
+            if self.current_handle == initial_handle {
+                return Err(HttpError::TooManySessions);
+            }
+        }
+        Ok(self.current_handle)
+    }
+}
+
+impl Drop for State {
+    /// Abort the requests the guest did not finish or retrieve once the
+    /// instance is dropped. Responses and WebSockets are released with it.
+    fn drop(&mut self) {
+        for (_, outgoing) in self.outgoing.drain() {
+            outgoing.body.abort();
+            outgoing.response.abort();
+        }
+        for (_, pending) in self.pending.drain() {
+            if let PendingRequest::Running(task) = pending {
+                task.abort();
+            }
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum HttpError {
     #[error("Invalid handle: [{0}]")]
     InvalidHandle(WasiHttpHandle),
-    #[error("Memory not found")]
-    MemoryNotFound,
     #[error("Memory access error")]
-    MemoryAccessError(#[from] wasmtime::MemoryAccessError),
+    MemoryAccessError(#[source] wiggle::GuestError),
     #[error("Buffer too small")]
     BufferTooSmall,
     #[error("Header not found")]
     HeaderNotFound,
     #[error("UTF-8 error")]
     Utf8Error(#[from] std::str::Utf8Error),
-    #[error("Destination not allowed")]
+    #[error("Destination not allowed: {0}")]
     DestinationNotAllowed(String),
     #[error("Invalid method")]
     InvalidMethod,
@@ -60,33 +207,127 @@ enum HttpError {
     #[error("Invalid URL")]
     InvalidUrl,
     #[error("HTTP error")]
-    RequestError(#[from] reqwest::Error),
+    RequestError(#[source] TransportError),
     #[error("Runtime error")]
     RuntimeError,
     #[error("Too many sessions")]
     TooManySessions,
+    #[error("Certificate pin mismatch")]
+    CertificatePinMismatch(#[source] TransportError),
+    #[error("DNS error")]
+    DnsError(#[source] TransportError),
+    #[error("Connection refused")]
+    ConnectionRefused(#[source] TransportError),
+    #[error("TLS error")]
+    TlsError(#[source] TransportError),
+    #[error("Timeout")]
+    Timeout(#[source] TransportError),
+    #[error("Decode error")]
+    DecodeError(#[source] TransportError),
+    #[error("Too many redirects")]
+    TooManyRedirects(#[source] TransportError),
+    #[error("Response body not entirely read")]
+    BodyNotRead,
+    #[error("Response body larger than {0} bytes")]
+    ResponseTooLarge(usize),
+    #[error("No inbound request")]
+    NoInboundRequest,
 }
 
-impl From<HttpError> for u32 {
-    fn from(e: HttpError) -> u32 {
+/// The underlying error of a failed request.
+type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+impl HttpError {
+    /// The error message, followed by the messages of all its sources.
+    fn message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            message.push_str(": ");
+            message.push_str(&e.to_string());
+            source = e.source();
+        }
+        message
+    }
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(e: hyper::Error) -> Self {
+        if e.is_timeout() {
+            HttpError::Timeout(e.into())
+        } else if e.is_parse() {
+            HttpError::DecodeError(e.into())
+        } else {
+            transport_error(e.into())
+        }
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for HttpError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        use tokio_tungstenite::tungstenite::{error::CapacityError, Error};
         match e {
-            HttpError::InvalidHandle(_) => 1,
-            HttpError::MemoryNotFound => 2,
-            HttpError::MemoryAccessError(_) => 3,
-            HttpError::BufferTooSmall => 4,
-            HttpError::HeaderNotFound => 5,
-            HttpError::Utf8Error(_) => 6,
-            HttpError::DestinationNotAllowed(_) => 7,
-            HttpError::InvalidMethod => 8,
-            HttpError::InvalidEncoding => 9,
-            HttpError::InvalidUrl => 10,
-            HttpError::RequestError(_) => 11,
-            HttpError::RuntimeError => 12,
-            HttpError::TooManySessions => 13,
+            Error::Io(e) => transport_error(e.into()),
+            Error::Capacity(CapacityError::MessageTooLong { max_size, .. }) => {
+                HttpError::ResponseTooLarge(max_size)
+            }
+            Error::Http(res) => HttpError::RequestError(
+                format!("server refused the upgrade with status {}", res.status()).into(),
+            ),
+            e @ (Error::Capacity(_) | Error::Protocol(_) | Error::Utf8) => {
+                HttpError::DecodeError(e.into())
+            }
+            Error::Url(_) => HttpError::InvalidUrl,
+            e => HttpError::RequestError(e.into()),
         }
     }
 }
 
+/// Classify a transport error by walking its chain of sources, so
+/// that failures to resolve, connect, or complete a TLS handshake can
+/// be told apart from other request errors.
+fn transport_error(e: TransportError) -> HttpError {
+    if tls::is_pin_mismatch(e.as_ref()) {
+        return HttpError::CertificatePinMismatch(e);
+    }
+    if tls::is_tls_error(e.as_ref()) {
+        return HttpError::TlsError(e);
+    }
+    let class = error_chain(e.as_ref()).find_map(|err| -> Option<fn(_) -> HttpError> {
+        // hyper's connector does not expose its error type, only its message,
+        // which is followed by the error of the resolver.
+        if err.to_string().starts_with("dns error") {
+            return Some(HttpError::DnsError);
+        }
+        match err.downcast_ref::<std::io::Error>()?.kind() {
+            // Connecting to a missing Unix domain socket fails with `NotFound`.
+            std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound => {
+                Some(HttpError::ConnectionRefused)
+            }
+            std::io::ErrorKind::TimedOut => Some(HttpError::Timeout),
+            _ => None,
+        }
+    });
+    class.unwrap_or(HttpError::RequestError)(e)
+}
+
+/// The chain of sources of `err`, starting with `err` itself. I/O errors
+/// report the source of the error they wrap rather than that error, which
+/// is visited instead.
+pub(crate) fn error_chain<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+    std::iter::successors(Some(err), |err| {
+        match err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            Some(inner) => Some(inner as &(dyn std::error::Error + 'static)),
+            None => err.source(),
+        }
+    })
+}
+
 impl From<PoisonError<std::sync::RwLockReadGuard<'_, State>>> for HttpError {
     fn from(_: PoisonError<std::sync::RwLockReadGuard<'_, State>>) -> Self {
         HttpError::RuntimeError
@@ -111,62 +352,53 @@ impl HostCalls {
     /// Remove the current handle from the state.
     /// Depending on the implementation, guest modules might
     /// have to manually call `close`.
+    /// Closing an outgoing request that was not finished, or a pending
+    /// request that did not complete, aborts it.
     // TODO (@radu-matei)
     // Fix the clippy warning.
     #[allow(clippy::unnecessary_wraps)]
     fn close(st: Arc<RwLock<State>>, handle: WasiHttpHandle) -> Result<(), HttpError> {
         let mut st = st.write()?;
         st.responses.remove(&handle);
+        if let Some(outgoing) = st.outgoing.remove(&handle) {
+            outgoing.body.abort();
+            outgoing.response.abort();
+        }
+        if let Some(PendingRequest::Running(task)) = st.pending.remove(&handle) {
+            task.abort();
+        }
+        // Dropping a WebSocket closes its connection without a close frame.
+        st.websockets.remove(&handle);
         Ok(())
     }
 
-    /// Read `buf_len` bytes from the response of `handle` and
-    /// write them into `buf_ptr`.
+    /// Read the next bytes of the response of `handle` into `buf`, and
+    /// return their number.
     fn body_read(
         st: Arc<RwLock<State>>,
-        memory: Memory,
-        mut store: impl AsContextMut,
         handle: WasiHttpHandle,
-        buf_ptr: u32,
-        buf_len: u32,
-        buf_read_ptr: u32,
-    ) -> Result<(), HttpError> {
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
         let mut st = st.write()?;
 
-        let mut body = &mut st.responses.get_mut(&handle).unwrap().body;
-        let mut context = store.as_context_mut();
+        let body = &mut st.responses.get_mut(&handle).unwrap().body;
 
         // Write at most either the remaining of the response body, or the entire
         // length requested by the guest.
-        let available = std::cmp::min(buf_len as _, body.bytes.len() - body.pos);
-        memory.write(
-            &mut context,
-            buf_ptr as _,
-            &body.bytes[body.pos..body.pos + available],
-        )?;
+        let available = std::cmp::min(buf.len() as _, body.bytes.len() - body.pos);
+        let written = write_bytes(buf, &body.bytes[body.pos..body.pos + available])?;
         body.pos += available;
-        // Write the number of bytes written back to the guest.
-        memory.write(
-            &mut context,
-            buf_read_ptr as _,
-            &(available as u32).to_le_bytes(),
-        )?;
-        Ok(())
+        Ok(written)
     }
 
     /// Get a response header value given a key.
-    #[allow(clippy::too_many_arguments)]
+    /// If the header has multiple values, only the first one is returned.
     fn header_get(
         st: Arc<RwLock<State>>,
-        memory: Memory,
-        mut store: impl AsContextMut,
         handle: WasiHttpHandle,
-        name_ptr: u32,
-        name_len: u32,
-        value_ptr: u32,
-        value_len: u32,
-        value_written_ptr: u32,
-    ) -> Result<(), HttpError> {
+        name: &GuestPtr<'_, str>,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
         let st = st.read()?;
 
         // Get the current response headers.
@@ -176,34 +408,40 @@ impl HostCalls {
             .ok_or(HttpError::InvalidHandle(handle))?
             .headers;
 
-        let mut store = store.as_context_mut();
-
         // Read the header key from the module's memory.
-        let key = string_from_memory(&memory, &mut store, name_ptr, name_len)?.to_ascii_lowercase();
+        let key = string_from_guest(name)?.to_ascii_lowercase();
         // Attempt to get the corresponding value from the resposne headers.
         let value = headers.get(key).ok_or(HttpError::HeaderNotFound)?;
-        if value.len() > value_len as _ {
-            return Err(HttpError::BufferTooSmall);
-        }
-        // Write the header value and its length.
-        memory.write(&mut store, value_ptr as _, value.as_bytes())?;
-        memory.write(
-            &mut store,
-            value_written_ptr as _,
-            &(value.len() as u32).to_le_bytes(),
-        )?;
-        Ok(())
+        write_bytes(buf, value.as_bytes())
+    }
+
+    /// Get all values of a response header given a key, separated
+    /// by new lines, in the order they were received.
+    fn header_values_get(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+        name: &GuestPtr<'_, str>,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
+        let st = st.read()?;
+
+        let headers = &st
+            .responses
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .headers;
+
+        let key = string_from_guest(name)?.to_ascii_lowercase();
+        let values = header_values_to_string(headers, &key)?;
+        write_bytes(buf, values.as_bytes())
     }
 
     fn headers_get_all(
         st: Arc<RwLock<State>>,
-        memory: Memory,
-        mut store: impl AsContextMut,
+        encoding: HeaderEncoding,
         handle: WasiHttpHandle,
-        buf_ptr: u32,
-        buf_len: u32,
-        buf_written_ptr: u32,
-    ) -> Result<(), HttpError> {
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
         let st = st.read()?;
 
         let headers = &st
@@ -212,313 +450,844 @@ impl HostCalls {
             .ok_or(HttpError::InvalidHandle(handle))?
             .headers;
 
-        let headers = match header_map_to_string(headers) {
-            Ok(res) => res,
-            Err(_) => return Err(HttpError::RuntimeError),
+        let headers = match encoding {
+            HeaderEncoding::Text => match header_map_to_string(headers) {
+                Ok(res) => res.into_bytes(),
+                Err(_) => return Err(HttpError::RuntimeError),
+            },
+            HeaderEncoding::Binary => header_map_to_bytes(headers),
         };
+        write_bytes(buf, &headers)
+    }
 
-        if headers.len() > buf_len as _ {
-            return Err(HttpError::BufferTooSmall);
+    /// Write the metadata of the response of `handle`, such as its HTTP
+    /// version or timings, using the binary encoding of headers.
+    fn response_metadata_get(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
+        let st = st.read()?;
+
+        let metadata = &st
+            .responses
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .metadata;
+        write_bytes(buf, &header_map_to_bytes(&metadata.to_header_map()))
+    }
+
+    /// Write the trailers of the response of `handle`, using the binary
+    /// encoding of headers. Trailers are received after the body, so they
+    /// are only available once the guest has read the entire body.
+    fn trailers_get_all(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
+        let st = st.read()?;
+
+        let response = st
+            .responses
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?;
+        if response.body.pos < response.body.bytes.len() {
+            return Err(HttpError::BodyNotRead);
         }
+        write_bytes(buf, &header_map_to_bytes(&response.trailers))
+    }
 
-        let mut store = store.as_context_mut();
+    /// Write the cookies of the instance, one per line in the Netscape cookie
+    /// file format, including session cookies. Nothing is written if the
+    /// instance does not have a cookie jar.
+    fn cookies_get_all(ctx: &HttpCtx, buf: &GuestPtr<'_, [u8]>) -> Result<u32, HttpError> {
+        let cookies = ctx
+            .cookie_jar
+            .as_ref()
+            .map(|jar| jar.to_text(true))
+            .unwrap_or_default();
+        write_bytes(buf, cookies.as_bytes())
+    }
 
-        memory.write(&mut store, buf_ptr as _, headers.as_bytes())?;
-        memory.write(
-            &mut store,
-            buf_written_ptr as _,
-            &(headers.len() as u32).to_le_bytes(),
-        )?;
+    /// Remove all cookies from the cookie jar of the instance.
+    #[allow(clippy::unnecessary_wraps)]
+    fn cookies_clear(ctx: &HttpCtx) -> Result<(), HttpError> {
+        if let Some(jar) = &ctx.cookie_jar {
+            jar.clear();
+        }
         Ok(())
     }
 
+    /// Write the message of the last error returned to the guest
+    /// into `buf`.
+    fn last_error(st: Arc<RwLock<State>>, buf: &GuestPtr<'_, [u8]>) -> Result<u32, HttpError> {
+        let st = st.read()?;
+        let message = st.last_error.as_deref().unwrap_or_default();
+        write_bytes(buf, message.as_bytes())
+    }
+
     /// Execute a request for a guest module, given
-    /// the request data.
-    #[allow(clippy::too_many_arguments)]
+    /// the request data, and return its status code and response handle.
     fn req(
-        st: Arc<RwLock<State>>,
-        allowed_hosts: Option<&[String]>,
-        max_concurrent_requests: Option<u32>,
-        memory: Memory,
-        mut store: impl AsContextMut,
-        url_ptr: u32,
-        url_len: u32,
-        method_ptr: u32,
-        method_len: u32,
-        req_headers_ptr: u32,
-        req_headers_len: u32,
-        req_body_ptr: u32,
-        req_body_len: u32,
-        status_code_ptr: u32,
-        res_handle_ptr: u32,
-    ) -> Result<(), HttpError> {
+        instance: &HttpInstance,
+        encoding: HeaderEncoding,
+        options: client::Options,
+        url: &GuestPtr<'_, str>,
+        method: &GuestPtr<'_, str>,
+        headers: &GuestPtr<'_, [u8]>,
+        body: &GuestPtr<'_, [u8]>,
+    ) -> Result<(u16, WasiHttpHandle), HttpError> {
+        let ctx = &instance.ctx;
         let span = tracing::trace_span!("req");
+        if let Some(trace_context) = &ctx.trace_context {
+            trace_context.link(&span);
+        }
         let _enter = span.enter();
 
+        instance.state.read()?.check_sessions(ctx, 1)?;
+
+        let (url, method, headers) = request_from_guest(ctx, encoding, url, method, headers)?;
+        let req_body = slice_from_guest(body)?;
+
+        // Send the request.
+        let res = request(
+            url.as_str(),
+            headers,
+            method,
+            req_body.as_slice(),
+            options,
+            &instance.runtime,
+            &instance.transport,
+            ctx,
+        )?;
+        Self::add_response(&mut *instance.state.write()?, res)
+    }
+
+    /// Start a request whose body is then written by the guest with
+    /// `req_body_write`, and return the handle of the outgoing request.
+    /// The request is sent as soon as it is started, and its body is
+    /// streamed to the server as it is written.
+    fn req_start(
+        instance: &HttpInstance,
+        url: &GuestPtr<'_, str>,
+        method: &GuestPtr<'_, str>,
+        headers: &GuestPtr<'_, [u8]>,
+    ) -> Result<WasiHttpHandle, HttpError> {
+        let ctx = &instance.ctx;
+        let mut st = instance.state.write()?;
+        st.check_sessions(ctx, 1)?;
+
+        let (url, method, headers) =
+            request_from_guest(ctx, HeaderEncoding::Binary, url, method, headers)?;
+        tracing::debug!(%url, ?headers, ?method, "starting streaming request");
+
+        let (body, stream) = hyper::Body::channel();
+        let req = client::Request {
+            url: url.parse().map_err(|_| HttpError::InvalidUrl)?,
+            method,
+            headers,
+            body: client::RequestBody::Streaming(stream),
+            options: client::Options::default(),
+        };
+        let response = instance.runtime.send(instance.client(), req);
+
+        let handle = st.next_handle()?;
+        st.outgoing.insert(
+            handle,
+            OutgoingRequest {
+                body,
+                trailers: HeaderMap::new(),
+                response,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Send the bytes of `body` as the next chunk of the body of the
+    /// outgoing request `handle`. If the request failed, the outgoing
+    /// request is closed and its error is returned.
+    fn req_body_write(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+        body: &GuestPtr<'_, [u8]>,
+    ) -> Result<(), HttpError> {
+        let chunk = slice_from_guest(body)?;
+        // The request is taken out of the state while the chunk is sent, so
+        // that the state is not locked while waiting for the server.
+        let mut outgoing = st
+            .write()?
+            .outgoing
+            .remove(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?;
+        if block_on(outgoing.body.send_data(chunk.into())).is_ok() {
+            st.write()?.outgoing.insert(handle, outgoing);
+            return Ok(());
+        }
+
+        // The body can no longer be sent, because the request either failed
+        // or was answered before its entire body was sent.
+        match block_on(outgoing.response) {
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(_)) => Err(HttpError::RequestError(
+                "the server responded before the entire body was sent".into(),
+            )),
+            Err(_) => Err(HttpError::RuntimeError),
+        }
+    }
+
+    /// Set the trailers sent after the body of the outgoing request `handle`,
+    /// using the binary encoding of headers.
+    fn req_trailers_set(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+        trailers: &GuestPtr<'_, [u8]>,
+    ) -> Result<(), HttpError> {
         let mut st = st.write()?;
-        if let Some(max) = max_concurrent_requests {
-            if st.responses.len() > (max - 1) as usize {
-                return Err(HttpError::TooManySessions);
+
+        let trailers = bytes_to_header_map(&slice_from_guest(trailers)?)
+            .map_err(|_| HttpError::InvalidEncoding)?;
+        st.outgoing
+            .get_mut(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .trailers = trailers;
+        Ok(())
+    }
+
+    /// Finish the body of the outgoing request `handle`, sending its trailers
+    /// if any, and wait for the response. The outgoing request is closed, and
+    /// the status code and handle of the response are returned.
+    fn req_finish(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+    ) -> Result<(u16, WasiHttpHandle), HttpError> {
+        let mut outgoing = st
+            .write()?
+            .outgoing
+            .remove(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?;
+        if !outgoing.trailers.is_empty() {
+            // This only fails if the request failed, in which case the error
+            // is returned with the response.
+            let _ = block_on(outgoing.body.send_trailers(outgoing.trailers));
+        }
+        // Dropping the sender ends the body.
+        drop(outgoing.body);
+
+        let res = block_on(outgoing.response).map_err(|_| HttpError::RuntimeError)??;
+        Self::add_response(&mut *st.write()?, res)
+    }
+
+    /// Send a request with binary encoded headers and the options at
+    /// `options` without waiting for its response, and return the handle
+    /// of the pending request.
+    fn req_async(
+        instance: &HttpInstance,
+        url: &GuestPtr<'_, str>,
+        method: &GuestPtr<'_, str>,
+        headers: &GuestPtr<'_, [u8]>,
+        body: &GuestPtr<'_, [u8]>,
+        options: &GuestPtr<'_, witx::v2::types::RequestOptions>,
+    ) -> Result<WasiHttpHandle, HttpError> {
+        let ctx = &instance.ctx;
+        let mut st = instance.state.write()?;
+        st.check_sessions(ctx, 1)?;
+
+        let (url, method, headers) =
+            request_from_guest(ctx, HeaderEncoding::Binary, url, method, headers)?;
+        let body = slice_from_guest(body)?;
+        let options = options_from_guest(options)?;
+        tracing::debug!(%url, ?headers, ?method, ?options, "starting asynchronous request");
+
+        let req = client::Request {
+            url: url.parse().map_err(|_| HttpError::InvalidUrl)?,
+            method,
+            headers,
+            body: client::RequestBody::Full(body.into()),
+            options,
+        };
+        let task = instance.runtime.send(instance.client(), req);
+
+        let handle = st.next_handle()?;
+        st.pending.insert(handle, PendingRequest::Running(task));
+        Ok(handle)
+    }
+
+    /// Write the handles of the pending requests which completed among
+    /// `handles` into `ready`, without blocking, and return their number.
+    fn req_poll(
+        st: Arc<RwLock<State>>,
+        handles: &GuestPtr<'_, [witx::v2::types::PendingHandle]>,
+        ready: &GuestPtr<'_, [witx::v2::types::PendingHandle]>,
+    ) -> Result<u32, HttpError> {
+        let mut st = st.write()?;
+        let handles = handles_from_guest(handles)?;
+
+        let mut ready_handles = vec![];
+        for handle in handles {
+            let pending = st
+                .pending
+                .get_mut(&handle)
+                .ok_or(HttpError::InvalidHandle(handle))?;
+            if pending.is_ready() && !ready_handles.contains(&handle) {
+                ready_handles.push(handle);
+            }
+        }
+        if ready_handles.len() > ready.len() as usize {
+            return Err(HttpError::BufferTooSmall);
+        }
+
+        for (ptr, handle) in ready.iter().zip(&ready_handles) {
+            ptr?.write((*handle).into())?;
+        }
+        Ok(ready_handles.len() as u32)
+    }
+
+    /// Wait until one of the pending requests of `handles` completes, for at
+    /// most `timeout_ms` milliseconds, or indefinitely if it is `0`, and
+    /// return its handle.
+    fn req_wait_any(
+        state: Arc<RwLock<State>>,
+        runtime: &Runtime,
+        handles: &GuestPtr<'_, [witx::v2::types::PendingHandle]>,
+        timeout_ms: u32,
+    ) -> Result<WasiHttpHandle, HttpError> {
+        let mut st = state.write()?;
+        let handles = handles_from_guest(handles)?;
+        if handles.is_empty() {
+            return Err(HttpError::InvalidEncoding);
+        }
+
+        let mut ready = None;
+        for handle in &handles {
+            let pending = st
+                .pending
+                .get_mut(handle)
+                .ok_or(HttpError::InvalidHandle(*handle))?;
+            if ready.is_none() && pending.is_ready() {
+                ready = Some(*handle);
+            }
+        }
+
+        if let Some(handle) = ready {
+            return Ok(handle);
+        }
+
+        // None of the requests completed, so they are all running.
+        // Their tasks are taken out of the state while waiting, so
+        // that the state is not locked, and put back afterwards.
+        let mut running = vec![];
+        let mut tasks = vec![];
+        for handle in handles {
+            if let Some(PendingRequest::Running(task)) = st.pending.remove(&handle) {
+                running.push(handle);
+                tasks.push(task);
+            }
+        }
+        drop(st);
+
+        let any = select_all(tasks);
+        let res = if timeout_ms == 0 {
+            Ok(block_on(any))
+        } else {
+            let timeout = Duration::from_millis(timeout_ms.into());
+            let timer = runtime.handle.spawn(tokio::time::sleep(timeout));
+            match block_on(select(any, timer)) {
+                Either::Left((res, timer)) => {
+                    timer.abort();
+                    Ok(res)
+                }
+                Either::Right((_, any)) => Err((timeout, any.into_inner())),
             }
         };
 
-        let mut store = store.as_context_mut();
+        let mut st = state.write()?;
+        match res {
+            Ok((res, i, tasks)) => {
+                // `select_all` removes the completed task with
+                // `swap_remove`, which keeps the remaining tasks in
+                // the order of `running`.
+                let handle = running.swap_remove(i);
+                for (handle, task) in running.into_iter().zip(tasks) {
+                    st.pending.insert(handle, PendingRequest::Running(task));
+                }
+                st.pending
+                    .insert(handle, PendingRequest::Done(Box::new(task_result(res))));
+                Ok(handle)
+            }
+            Err((timeout, tasks)) => {
+                for (handle, task) in running.into_iter().zip(tasks) {
+                    st.pending.insert(handle, PendingRequest::Running(task));
+                }
+                Err(HttpError::Timeout(
+                    format!("no request completed after {:?}", timeout).into(),
+                ))
+            }
+        }
+    }
 
-        // Read the request parts from the module's linear memory and check early if
-        // the guest is allowed to make a request to the given URL.
-        let url = string_from_memory(&memory, &mut store, url_ptr, url_len)?;
-        if !is_allowed(url.as_str(), allowed_hosts)? {
-            return Err(HttpError::DestinationNotAllowed(url));
+    /// Get the response of a pending request, waiting for it if it did not
+    /// complete yet. The pending request is closed, and its handle may be
+    /// reused for the response.
+    fn req_result(
+        st: Arc<RwLock<State>>,
+        handle: WasiHttpHandle,
+    ) -> Result<(u16, WasiHttpHandle), HttpError> {
+        let pending = st
+            .write()?
+            .pending
+            .remove(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?;
+        let res = pending.wait()?;
+        Self::add_response(&mut *st.write()?, res)
+    }
+
+    /// Send the requests described by the `$batch_request` records of
+    /// `requests` concurrently, with the options at `options`, wait for all
+    /// of them, and write a `$batch_result` record for each into `results`.
+    /// A request that cannot be sent only fails its own result, and the
+    /// messages of such failures are recorded as the last error, one per
+    /// line, prefixed with the index of the request.
+    fn req_batch<'a>(
+        instance: &HttpInstance,
+        requests: &GuestPtr<'a, [witx::v2::types::BatchRequest<'a>]>,
+        options: &GuestPtr<'a, witx::v2::types::RequestOptions>,
+        results: &GuestPtr<'a, [witx::v2::types::BatchResult]>,
+    ) -> Result<(), HttpError> {
+        let ctx = &instance.ctx;
+        if results.len() < requests.len() {
+            return Err(HttpError::BufferTooSmall);
+        }
+        instance
+            .state
+            .read()?
+            .check_sessions(ctx, requests.len() as usize)?;
+
+        let options = options_from_guest(options)?;
+        let records = requests
+            .iter()
+            .map(|record| Ok(record?.read()?))
+            .collect::<Result<Vec<_>, HttpError>>()?;
+        let tasks = records
+            .iter()
+            .map(|record| {
+                let req = batch_request_from_guest(ctx, record, options)?;
+                tracing::debug!(url = %req.url, headers = ?req.headers, method = ?req.method, "starting batch request");
+                Ok(instance.runtime.send(instance.client(), req))
+            })
+            .collect::<Vec<Result<_, HttpError>>>();
+        let responses = block_on(join_all(tasks.into_iter().map(|task| async {
+            match task {
+                Ok(task) => task_result(task.await),
+                Err(e) => Err(e),
+            }
+        })));
+
+        let mut st = instance.state.write()?;
+        let mut errors = vec![];
+        for (i, (res, ptr)) in responses.into_iter().zip(results.iter()).enumerate() {
+            let result = match res.and_then(|res| Self::add_response(&mut st, res)) {
+                Ok((status, handle)) => witx::v2::types::BatchResult {
+                    error: witx::v2::types::HttpError::Success,
+                    status_code: status,
+                    response_handle: handle.into(),
+                },
+                Err(e) => {
+                    errors.push(format!("{}: {}", i, e.message()));
+                    witx::v2::types::BatchResult {
+                        error: (&e).into(),
+                        status_code: 0,
+                        response_handle: 0.into(),
+                    }
+                }
+            };
+            ptr?.write(result)?;
+        }
+        if !errors.is_empty() {
+            tracing::debug!(?errors, "batch requests failed");
+            st.last_error = Some(errors.join("\n"));
         }
+        Ok(())
+    }
 
-        let method = Method::from_str(
-            string_from_memory(&memory, &mut store, method_ptr, method_len)?.as_str(),
-        )
-        .map_err(|_| HttpError::InvalidMethod)?;
-        let req_body = slice_from_memory(&memory, &mut store, req_body_ptr, req_body_len)?;
-        let headers = string_to_header_map(
-            string_from_memory(&memory, &mut store, req_headers_ptr, req_headers_len)?.as_str(),
-        )
-        .map_err(|_| HttpError::InvalidEncoding)?;
+    /// Open a WebSocket connection to the `ws` or `wss` URL `url`, with the
+    /// binary encoded `headers` added to the opening handshake, and return
+    /// its handle.
+    fn ws_open(
+        instance: &HttpInstance,
+        url: &GuestPtr<'_, str>,
+        headers: &GuestPtr<'_, [u8]>,
+    ) -> Result<WasiHttpHandle, HttpError> {
+        let ctx = &instance.ctx;
+        instance.state.read()?.check_sessions(ctx, 1)?;
+
+        let url = string_from_guest(url)?;
+        check_allowed(&url, ctx)?;
+        let url = Url::parse(&url).map_err(|_| HttpError::InvalidUrl)?;
+        let headers = bytes_to_header_map(&slice_from_guest(headers)?)
+            .map_err(|_| HttpError::InvalidEncoding)?;
+        tracing::debug!(%url, ?headers, "opening websocket");
+
+        let client = instance.client();
+        let stream = instance
+            .runtime
+            .run(async move { client.websocket(url, headers).await })??;
+
+        let mut st = instance.state.write()?;
+        let handle = st.next_handle()?;
+        st.websockets
+            .insert(handle, websocket::WebSocket::new(stream));
+        Ok(handle)
+    }
 
-        // Send the request.
-        let (status, resp_headers, resp_body) =
-            request(url.as_str(), headers, method, req_body.as_slice())?;
-        tracing::debug!(
-            status,
-            ?resp_headers,
-            body_len = resp_body.as_ref().len(),
-            "got HTTP response, writing back to memory"
-        );
+    /// Send `data` as a message of type `message_type` on the WebSocket of
+    /// `handle`.
+    fn ws_send(
+        st: Arc<RwLock<State>>,
+        runtime: &Runtime,
+        handle: WasiHttpHandle,
+        message_type: websocket::MessageType,
+        data: &GuestPtr<'_, [u8]>,
+    ) -> Result<(), HttpError> {
+        let data = slice_from_guest(data)?;
+        let send = st
+            .read()?
+            .websockets
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .send(message_type, data);
+        runtime.run(send)?
+    }
 
-        // Write the status code to the guest.
-        memory.write(&mut store, status_code_ptr as _, &status.to_le_bytes())?;
+    /// Receive the next message of the WebSocket of `handle`, waiting for
+    /// at most `timeout_ms` milliseconds, or indefinitely if it is `0`, and
+    /// return its type and size. The message is only written into `buf` if
+    /// it fits, and is otherwise kept for the next call.
+    fn ws_recv(
+        st: Arc<RwLock<State>>,
+        runtime: &Runtime,
+        handle: WasiHttpHandle,
+        timeout_ms: u32,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<(websocket::MessageType, u32), HttpError> {
+        let (received, receive) = {
+            let mut st = st.write()?;
+            let ws = st
+                .websockets
+                .get_mut(&handle)
+                .ok_or(HttpError::InvalidHandle(handle))?;
+            (ws.received.take(), ws.receive())
+        };
+        let (message_type, data) = match received {
+            Some(message) => message,
+            None if timeout_ms == 0 => runtime.run(receive)??,
+            None => {
+                let timeout = Duration::from_millis(timeout_ms.into());
+                // The timer must be created on the runtime.
+                runtime
+                    .run(async move { tokio::time::timeout(timeout, receive).await })?
+                    .map_err(|_| {
+                        HttpError::Timeout(
+                            format!("no message received after {:?}", timeout).into(),
+                        )
+                    })??
+            }
+        };
+
+        let len = data.len() as u32;
+        if data.len() > buf.len() as usize {
+            if let Some(ws) = st.write()?.websockets.get_mut(&handle) {
+                ws.received = Some((message_type, data));
+            }
+        } else {
+            write_bytes(buf, &data)?;
+        }
+        Ok((message_type, len))
+    }
+
+    /// Close the WebSocket of `handle` with the close code `code` and the
+    /// reason `reason`, and release its handle.
+    fn ws_close(
+        st: Arc<RwLock<State>>,
+        runtime: &Runtime,
+        handle: WasiHttpHandle,
+        code: u16,
+        reason: &GuestPtr<'_, str>,
+    ) -> Result<(), HttpError> {
+        // The connection is released even if it cannot be closed cleanly.
+        let ws = st
+            .write()?
+            .websockets
+            .remove(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?;
+        let reason = string_from_guest(reason)?;
+        runtime.run(ws.close(code, reason))?
+    }
+
+    /// Write the method, URL or binary encoded headers of the inbound
+    /// request.
+    fn inbound_get(
+        ctx: &HttpCtx,
+        part: InboundPart,
+        buf: &GuestPtr<'_, [u8]>,
+    ) -> Result<u32, HttpError> {
+        let inbound = ctx.inbound.as_ref().ok_or(HttpError::NoInboundRequest)?;
+        let bytes = match part {
+            InboundPart::Method => inbound.method().into_bytes(),
+            InboundPart::Url => inbound.url().into_bytes(),
+            InboundPart::Headers => header_map_to_bytes(&inbound.headers()),
+        };
+        write_bytes(buf, &bytes)
+    }
+
+    /// Fill a buffer with the next chunk of the body of the inbound request.
+    fn inbound_body_read(ctx: &HttpCtx, buf: &GuestPtr<'_, [u8]>) -> Result<u32, HttpError> {
+        let inbound = ctx.inbound.as_ref().ok_or(HttpError::NoInboundRequest)?;
+        let chunk = inbound.body_read(buf.len() as _);
+        write_bytes(buf, &chunk)
+    }
+
+    /// Set the response to the inbound request, replacing the previous one.
+    fn inbound_response_set(
+        ctx: &HttpCtx,
+        status_code: u16,
+        headers: &GuestPtr<'_, [u8]>,
+        body: &GuestPtr<'_, [u8]>,
+    ) -> Result<(), HttpError> {
+        let inbound = ctx.inbound.as_ref().ok_or(HttpError::NoInboundRequest)?;
+        let status =
+            http::StatusCode::from_u16(status_code).map_err(|_| HttpError::InvalidEncoding)?;
+        let headers = bytes_to_header_map(&slice_from_guest(headers)?)
+            .map_err(|_| HttpError::InvalidEncoding)?;
+        let body = slice_from_guest(body)?;
+
+        let mut res = http::Response::new(Bytes::from(body));
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        inbound.set_response(res);
+        Ok(())
+    }
 
-        // Construct the response, add it to the current state, and write
-        // the handle to the guest.
+    /// Replace the trace context of the instance with the `traceparent`
+    /// and `tracestate` header values. An empty `tracestate` is no trace
+    /// state. This does nothing if the host does not propagate trace
+    /// contexts.
+    fn trace_context_set(
+        ctx: &HttpCtx,
+        traceparent: &GuestPtr<'_, str>,
+        tracestate: &GuestPtr<'_, str>,
+    ) -> Result<(), HttpError> {
+        let traceparent = string_from_guest(traceparent)?;
+        let tracestate = string_from_guest(tracestate)?;
+        if let Some(trace_context) = &ctx.trace_context {
+            let tracestate = Some(tracestate.as_str()).filter(|s| !s.is_empty());
+            trace_context
+                .set(&traceparent, tracestate)
+                .map_err(|_| HttpError::InvalidEncoding)?;
+        }
+        Ok(())
+    }
+
+    /// Add the response of a request to the state, and return its status
+    /// code and handle.
+    fn add_response(
+        st: &mut State,
+        res: client::Response,
+    ) -> Result<(u16, WasiHttpHandle), HttpError> {
+        tracing::debug!(
+            status = res.status,
+            headers = ?res.headers,
+            body_len = res.body.len(),
+            trailers = ?res.trailers,
+            metadata = ?res.metadata,
+            "got HTTP response"
+        );
+        let status = res.status;
         let response = Response {
-            headers: resp_headers,
+            headers: res.headers,
             body: Body {
-                bytes: resp_body,
+                bytes: res.body,
                 pos: 0,
             },
+            trailers: res.trailers,
+            metadata: res.metadata,
         };
 
-        let initial_handle = st.current_handle;
-        while st.responses.get(&st.current_handle).is_some() {
-            st.current_handle += 1;
-            if st.current_handle == initial_handle {
-                return Err(HttpError::TooManySessions);
-            }
-        }
-        let handle = st.current_handle;
+        let handle = st.next_handle()?;
         st.responses.insert(handle, response);
-        memory.write(&mut store, res_handle_ptr as _, &handle.to_le_bytes())?;
-
-        Ok(())
+        Ok((status, handle))
     }
 }
 
 /// Per-instance context data used to control whether the guest
 /// is allowed to make an outbound HTTP request.
-#[derive(Clone)]
+///
+/// The context only holds options: the handles of an instance are kept in
+/// the `HttpInstance` created from it with `HttpState::instance`.
+#[derive(Clone, Default)]
 pub struct HttpCtx {
     pub allowed_hosts: Option<Vec<String>>,
     pub max_concurrent_requests: Option<u32>,
+    /// Certificate pins, keyed by host name. Requests to a host with
+    /// pins fail with `CertificatePinMismatch` unless the server presents
+    /// a certificate matching at least one of them.
+    pub certificate_pins: HashMap<String, Vec<CertificatePin>>,
+    /// Unix domain sockets, keyed by host name. Requests to these hosts
+    /// are sent over the socket instead of TCP, with TLS for `https` URLs,
+    /// and are still subject to `allowed_hosts`.
+    pub unix_sockets: HashMap<String, PathBuf>,
+    /// Cookie jar storing the cookies set by servers and sending them back
+    /// with later requests. Cookies are not handled by the host if `None`.
+    pub cookie_jar: Option<CookieJar>,
+    /// Whether response bodies are decoded by the host, or passed to the
+    /// guest with their `Content-Encoding`.
+    pub decompression: Decompression,
+    /// Content coding the bodies of requests are compressed with, unless
+    /// the guest already set a `Content-Encoding`. Streamed bodies are never
+    /// compressed.
+    pub request_compression: Option<ContentEncoding>,
+    /// Time limit of requests, up to reading the entire response body.
+    /// Guests can only set a shorter timeout.
+    pub request_timeout: Option<Duration>,
+    /// Maximum size of response bodies, once decoded. Guests can only set
+    /// a lower limit.
+    pub max_response_size: Option<usize>,
+    /// The inbound request the instance handles, when the host dispatches
+    /// requests it receives to the guest. The `inbound_*` functions fail
+    /// with `NoInboundRequest` if `None`.
+    pub inbound: Option<InboundRequest>,
+    /// Trace context of the instance, whose trace outbound requests join
+    /// with `traceparent` and `tracestate` headers. Trace context headers
+    /// are not added if `None`.
+    pub trace_context: Option<TraceContext>,
+    /// Metrics the requests of the instance are counted in. Requests are
+    /// not counted if `None`.
+    pub metrics: Option<Metrics>,
 }
 
-/// Experimental HTTP extension object for Wasmtime.
-pub struct HttpState {
+/// The HTTP state of an instance: the options of its `HttpCtx`, and the
+/// responses, requests and WebSockets it opened. It is kept in the data of
+/// the store of the instance, and releases its handles when dropped, so
+/// each store needs its own.
+pub struct HttpInstance {
+    ctx: Arc<HttpCtx>,
     state: Arc<RwLock<State>>,
+    runtime: Arc<Runtime>,
+    /// Connections of the instance, pooled for its requests.
+    transport: Arc<client::Transport>,
+    /// Whether the last call to `last_error` failed, in which case its
+    /// error is not recorded, so that the guest can retry with a larger
+    /// buffer.
+    last_error_failed: bool,
+    /// Reports the open handles of the instance in the metrics of its
+    /// context, until dropped.
+    _open_handles: Option<metrics::OpenHandles>,
+}
+
+impl HttpInstance {
+    /// The options of the instance.
+    pub fn ctx(&self) -> &HttpCtx {
+        &self.ctx
+    }
+
+    /// A client sending requests with the options of the instance.
+    fn client(&self) -> client::Client {
+        client::Client::new(self.transport.clone())
+    }
+}
+
+/// Experimental HTTP extension object for Wasmtime, shared by all the
+/// instances of a host, whose state is kept in their `HttpInstance`.
+pub struct HttpState {
+    runtime: Arc<Runtime>,
+    tls: Arc<tls::Tls>,
+    /// Proxies set in the environment of the host.
+    proxies: client::Proxies,
 }
 
 impl HttpState {
     /// Module the HTTP extension is going to be defined as.
     pub const MODULE: &'static str = "wasi_experimental_http";
 
+    /// Versioned module the HTTP extension is also defined as. It contains
+    /// every function of `MODULE`, as well as the `version` and `capabilities`
+    /// functions guests use to detect which optional features are supported.
+    pub const MODULE_V2: &'static str = "wasi_experimental_http_v2";
+
     /// Create a new HTTP extension object.
     /// `allowed_hosts` may be `None` (no outbound connections allowed)
     /// or a list of allowed host names.
     pub fn new() -> Result<Self, Error> {
+        Self::new_with_tls(TlsConfig::default())
+    }
+
+    /// Create a new HTTP extension object that applies the given
+    /// TLS options to all outbound requests.
+    pub fn new_with_tls(tls: TlsConfig) -> Result<Self, Error> {
+        let runtime = Arc::new(Runtime::new()?);
+        Ok(HttpState {
+            runtime,
+            tls: Arc::new(tls::Tls::new(&tls)?),
+            proxies: client::Proxies::from_env(),
+        })
+    }
+
+    /// Create the state of a new instance, whose requests follow the
+    /// options of `ctx`.
+    pub fn instance(&self, ctx: HttpCtx) -> HttpInstance {
         let state = Arc::new(RwLock::new(State::default()));
-        Ok(HttpState { state })
+        let open_handles = ctx.metrics.as_ref().map(|metrics| {
+            let state = Arc::downgrade(&state);
+            metrics.register_open_handles(move || {
+                let state = state.upgrade()?;
+                let st = state.read().ok()?;
+                Some(st.open_handles())
+            })
+        });
+        let ctx = Arc::new(ctx);
+        let transport = client::Transport::new(self.tls.clone(), &self.proxies, ctx.clone());
+        HttpInstance {
+            ctx,
+            state,
+            runtime: self.runtime.clone(),
+            transport: Arc::new(transport),
+            last_error_failed: false,
+            _open_handles: open_handles,
+        }
     }
 
+    /// Define the HTTP functions in `linker`. `get_cx` returns the
+    /// `HttpInstance` in the data of the store of an instance.
     pub fn add_to_linker<T>(
-        &self,
         linker: &mut Linker<T>,
-        get_cx: impl Fn(&T) -> HttpCtx + Send + Sync + 'static,
+        get_cx: impl Fn(&mut T) -> &mut HttpInstance + Send + Sync + Copy + 'static,
     ) -> Result<(), Error> {
-        let st = self.state.clone();
-        linker.func_wrap(
-            Self::MODULE,
-            "close",
-            move |handle: WasiHttpHandle| -> u32 {
-                match HostCalls::close(st.clone(), handle) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
-            },
-        )?;
-
-        let st = self.state.clone();
-        linker.func_wrap(
-            Self::MODULE,
-            "body_read",
-            move |mut caller: Caller<'_, T>,
-                  handle: WasiHttpHandle,
-                  buf_ptr: u32,
-                  buf_len: u32,
-                  buf_read_ptr: u32|
-                  -> u32 {
-                let memory = match memory_get(&mut caller) {
-                    Ok(m) => m,
-                    Err(e) => return e.into(),
-                };
-
-                let ctx = caller.as_context_mut();
-
-                match HostCalls::body_read(
-                    st.clone(),
-                    memory,
-                    ctx,
-                    handle,
-                    buf_ptr,
-                    buf_len,
-                    buf_read_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
-            },
-        )?;
-
-        let st = self.state.clone();
-        linker.func_wrap(
-            Self::MODULE,
-            "header_get",
-            move |mut caller: Caller<'_, T>,
-                  handle: WasiHttpHandle,
-                  name_ptr: u32,
-                  name_len: u32,
-                  value_ptr: u32,
-                  value_len: u32,
-                  value_written_ptr: u32|
-                  -> u32 {
-                let memory = match memory_get(&mut caller) {
-                    Ok(m) => m,
-                    Err(e) => return e.into(),
-                };
-
-                let ctx = caller.as_context_mut();
-
-                match HostCalls::header_get(
-                    st.clone(),
-                    memory,
-                    ctx,
-                    handle,
-                    name_ptr,
-                    name_len,
-                    value_ptr,
-                    value_len,
-                    value_written_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
-            },
-        )?;
-
-        let st = self.state.clone();
-        linker.func_wrap(
-            Self::MODULE,
-            "headers_get_all",
-            move |mut caller: Caller<'_, T>,
-                  handle: WasiHttpHandle,
-                  buf_ptr: u32,
-                  buf_len: u32,
-                  buf_read_ptr: u32|
-                  -> u32 {
-                let memory = match memory_get(&mut caller) {
-                    Ok(m) => m,
-                    Err(e) => return e.into(),
-                };
-
-                let ctx = caller.as_context_mut();
-
-                match HostCalls::headers_get_all(
-                    st.clone(),
-                    memory,
-                    ctx,
-                    handle,
-                    buf_ptr,
-                    buf_len,
-                    buf_read_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
-            },
-        )?;
-
-        let st = self.state.clone();
-        linker.func_wrap(
-            Self::MODULE,
-            "req",
-            move |mut caller: Caller<'_, T>,
-                  url_ptr: u32,
-                  url_len: u32,
-                  method_ptr: u32,
-                  method_len: u32,
-                  req_headers_ptr: u32,
-                  req_headers_len: u32,
-                  req_body_ptr: u32,
-                  req_body_len: u32,
-                  status_code_ptr: u32,
-                  res_handle_ptr: u32|
-                  -> u32 {
-                let memory = match memory_get(&mut caller) {
-                    Ok(m) => m,
-                    Err(e) => return e.into(),
-                };
-
-                let ctx = caller.as_context_mut();
-                let http_ctx = get_cx(ctx.data());
-
-                match HostCalls::req(
-                    st.clone(),
-                    http_ctx.allowed_hosts.as_deref(),
-                    http_ctx.max_concurrent_requests,
-                    memory,
-                    ctx,
-                    url_ptr,
-                    url_len,
-                    method_ptr,
-                    method_len,
-                    req_headers_ptr,
-                    req_headers_len,
-                    req_body_ptr,
-                    req_body_len,
-                    status_code_ptr,
-                    res_handle_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
-            },
-        )?;
+        witx::add_to_linker(linker, get_cx)
+    }
 
-        Ok(())
+    /// Define the functions `module` imports from HTTP modules the host
+    /// does not define, such as those of newer versions of the ABI, as
+    /// returning default values, so that the guest can be instantiated,
+    /// and can detect that they are missing by calling their `version`.
+    pub fn define_unknown_imports<T>(linker: &mut Linker<T>, module: &Module) -> Result<(), Error> {
+        witx::define_unknown_imports(linker, module)
     }
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(runtime, transport, ctx))]
+#[allow(clippy::too_many_arguments)]
 fn request(
     url: &str,
     headers: HeaderMap,
     method: Method,
     body: &[u8],
-) -> Result<(u16, HeaderMap<HeaderValue>, Bytes), HttpError> {
+    options: client::Options,
+    runtime: &Runtime,
+    transport: &Arc<client::Transport>,
+    ctx: &HttpCtx,
+) -> Result<client::Response, HttpError> {
+    if let Some(trace_context) = &ctx.trace_context {
+        trace_context.link(&tracing::Span::current());
+    }
     tracing::debug!(
         %url,
         ?headers,
@@ -526,88 +1295,190 @@ fn request(
         body_len = body.len(),
         "performing request"
     );
-    let url: Url = url.parse().map_err(|_| HttpError::InvalidUrl)?;
-    let body = body.to_vec();
-    match Handle::try_current() {
-        Ok(r) => {
-            // If running in a Tokio runtime, spawn a new blocking executor
-            // that will send the HTTP request, and block on its execution.
-            // This attempts to avoid any deadlocks from other operations
-            // already executing on the same executor (compared with just
-            // blocking on the current one).
-            //
-            // This should only be a temporary workaround, until we take
-            // advantage of async functions in Wasmtime.
-            tracing::trace!("tokio runtime available, spawning request on tokio thread");
-            block_on(r.spawn_blocking(move || {
-                let client = Client::builder().build().unwrap();
-                let res = block_on(
-                    client
-                        .request(method, url)
-                        .headers(headers)
-                        .body(body)
-                        .send(),
-                )?;
-                Ok((
-                    res.status().as_u16(),
-                    res.headers().clone(),
-                    block_on(res.bytes())?,
-                ))
-            }))
-            .map_err(|_| HttpError::RuntimeError)?
-        }
-        Err(_) => {
-            tracing::trace!("no tokio runtime available, using blocking request");
-            let res = reqwest::blocking::Client::new()
-                .request(method, url)
-                .headers(headers)
-                .body(body)
-                .send()?;
-            return Ok((res.status().as_u16(), res.headers().clone(), res.bytes()?));
+    let req = client::Request {
+        url: url.parse().map_err(|_| HttpError::InvalidUrl)?,
+        method,
+        headers,
+        body: client::RequestBody::Full(Bytes::copy_from_slice(body)),
+        options,
+    };
+    let client = client::Client::new(transport.clone());
+    block_on(runtime.send(client, req)).map_err(|_| HttpError::RuntimeError)?
+}
+
+/// Runtime sending the requests of the instances of an `HttpState`. It runs
+/// on worker threads of its own, so that requests make progress between
+/// host calls, for instance while the guest writes the body of a streaming
+/// request, so that the requests of several instances are sent in
+/// parallel, and so that blocking on a request does not deadlock a Tokio
+/// runtime the host may be using.
+struct Runtime {
+    handle: Handle,
+    /// Shut down in the background when dropped, which unlike dropping a
+    /// runtime is allowed within a Tokio runtime the host may be using.
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl Runtime {
+    fn new() -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("wasi-experimental-http")
+            .enable_all()
+            .build()?;
+        Ok(Runtime {
+            handle: runtime.handle().clone(),
+            runtime: Some(runtime),
+        })
+    }
+
+    /// Run a future on the runtime, in the current span, and wait for its
+    /// output.
+    fn run<F>(&self, fut: F) -> Result<F::Output, HttpError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        block_on(self.handle.spawn(fut.in_current_span())).map_err(|_| HttpError::RuntimeError)
+    }
+
+    /// Send a request in the background, in the current span.
+    fn send(
+        &self,
+        client: client::Client,
+        req: client::Request,
+    ) -> JoinHandle<Result<client::Response, HttpError>> {
+        self.handle
+            .spawn(async move { client.send(req).await }.in_current_span())
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
         }
     }
 }
 
-/// Get the exported memory block called `memory`.
-/// This will return an `HttpError::MemoryNotFound` if the module does
-/// not export a memory block.
-fn memory_get<T>(caller: &mut Caller<'_, T>) -> Result<Memory, HttpError> {
-    if let Some(Extern::Memory(mem)) = caller.get_export(MEMORY) {
-        Ok(mem)
-    } else {
-        Err(HttpError::MemoryNotFound)
+/// Read the URL, method and headers of a request from the guest, and check
+/// early if the guest is allowed to send it.
+fn request_from_guest(
+    ctx: &HttpCtx,
+    encoding: HeaderEncoding,
+    url: &GuestPtr<'_, str>,
+    method: &GuestPtr<'_, str>,
+    headers: &GuestPtr<'_, [u8]>,
+) -> Result<(String, Method, HeaderMap), HttpError> {
+    let url = string_from_guest(url)?;
+    check_allowed(&url, ctx)?;
+
+    let method = Method::from_str(string_from_guest(method)?.as_str())
+        .map_err(|_| HttpError::InvalidMethod)?;
+    let headers = slice_from_guest(headers)?;
+    let headers = match encoding {
+        HeaderEncoding::Text => string_to_header_map(std::str::from_utf8(&headers)?),
+        HeaderEncoding::Binary => bytes_to_header_map(&headers),
     }
+    .map_err(|_| HttpError::InvalidEncoding)?;
+    Ok((url, method, headers))
 }
 
-/// Get a slice of length `len` from `memory`, starting at `offset`.
-/// This will return an `HttpError::BufferTooSmall` if the size of the
-/// requested slice is larger than the memory size.
-fn slice_from_memory(
-    memory: &Memory,
-    mut ctx: impl AsContextMut,
-    offset: u32,
-    len: u32,
-) -> Result<Vec<u8>, HttpError> {
-    let required_memory_size = offset.checked_add(len).ok_or(HttpError::BufferTooSmall)? as usize;
+/// Read the request described by a `$batch_request` record.
+fn batch_request_from_guest(
+    ctx: &HttpCtx,
+    record: &witx::v2::types::BatchRequest<'_>,
+    options: client::Options,
+) -> Result<client::Request, HttpError> {
+    let url = record.url_ptr.as_array(record.url_len).as_str_ptr();
+    let method = record.method_ptr.as_array(record.method_len).as_str_ptr();
+    let headers = record.headers_ptr.as_array(record.headers_len);
+    let (url, method, headers) =
+        request_from_guest(ctx, HeaderEncoding::Binary, &url, &method, &headers)?;
+    let body = slice_from_guest(&record.body_ptr.as_array(record.body_len))?;
+    Ok(client::Request {
+        url: url.parse().map_err(|_| HttpError::InvalidUrl)?,
+        method,
+        headers,
+        body: client::RequestBody::Full(body.into()),
+        options,
+    })
+}
 
-    if required_memory_size > memory.data_size(&mut ctx) {
-        return Err(HttpError::BufferTooSmall);
+/// Read a `$request_options` record from the guest. Fields set to `0`
+/// leave the choice to the host.
+fn options_from_guest(
+    ptr: &GuestPtr<'_, witx::v2::types::RequestOptions>,
+) -> Result<client::Options, HttpError> {
+    use witx::v2::types::{Decompression as D, HttpVersion, Redirects};
+
+    let record = ptr.read()?;
+    let mut options = client::Options::default();
+    if record.timeout_ms != 0 {
+        options.timeout = Some(Duration::from_millis(record.timeout_ms.into()));
     }
+    options.follow_redirects = match record.redirects {
+        Redirects::Default | Redirects::Follow => true,
+        Redirects::None => false,
+    };
+    if record.max_response_size != 0 {
+        options.max_response_size = Some(record.max_response_size as usize);
+    }
+    options.http_version = match record.http_version {
+        HttpVersion::Default => None,
+        HttpVersion::Http1 => Some(http::Version::HTTP_11),
+        HttpVersion::Http2 => Some(http::Version::HTTP_2),
+    };
+    options.decompression = match record.decompression {
+        D::Default => None,
+        D::Auto => Some(Decompression::Auto),
+        D::Passthrough => Some(Decompression::Passthrough),
+    };
+    Ok(options)
+}
 
-    let mut buf = vec![0u8; len as usize];
-    memory.read(&mut ctx, offset as usize, buf.as_mut_slice())?;
-    Ok(buf)
+/// Read a list of handles from the guest.
+fn handles_from_guest(
+    handles: &GuestPtr<'_, [witx::v2::types::PendingHandle]>,
+) -> Result<Vec<WasiHttpHandle>, HttpError> {
+    handles
+        .iter()
+        .map(|handle| Ok(handle?.read()?.into()))
+        .collect()
 }
 
-/// Read a string of byte length `len` from `memory`, starting at `offset`.
-fn string_from_memory(
-    memory: &Memory,
-    ctx: impl AsContextMut,
-    offset: u32,
-    len: u32,
-) -> Result<String, HttpError> {
-    let slice = slice_from_memory(memory, ctx, offset, len)?;
-    Ok(std::str::from_utf8(&slice)?.to_string())
+/// Copy the bytes of a guest buffer.
+fn slice_from_guest(buf: &GuestPtr<'_, [u8]>) -> Result<Vec<u8>, HttpError> {
+    Ok(buf.as_slice()?.to_vec())
+}
+
+/// Copy a guest string.
+fn string_from_guest(s: &GuestPtr<'_, str>) -> Result<String, HttpError> {
+    Ok(s.as_str()?.to_string())
+}
+
+/// Write `bytes` at the start of a guest buffer, and return their length,
+/// or return `BufferTooSmall` if they do not fit in the buffer.
+fn write_bytes(buf: &GuestPtr<'_, [u8]>, bytes: &[u8]) -> Result<u32, HttpError> {
+    let len = u32::try_from(bytes.len()).map_err(|_| HttpError::BufferTooSmall)?;
+    if len > buf.len() {
+        return Err(HttpError::BufferTooSmall);
+    }
+    buf.get_range(0..len)
+        .ok_or(HttpError::BufferTooSmall)?
+        .copy_from_slice(bytes)?;
+    Ok(len)
+}
+
+/// Check that the guest is allowed to send a request to `url`, and count the
+/// request as denied otherwise.
+fn check_allowed(url: &str, ctx: &HttpCtx) -> Result<(), HttpError> {
+    if is_allowed(url, ctx.allowed_hosts.as_deref())? {
+        return Ok(());
+    }
+    if let Some(metrics) = &ctx.metrics {
+        metrics.request_denied(url);
+    }
+    Err(HttpError::DestinationNotAllowed(url.to_string()))
 }
 
 /// Check if guest module is allowed to send request to URL, based on the list of
@@ -656,7 +1527,7 @@ fn string_to_header_map(s: &str) -> Result<HeaderMap, Error> {
             entry
         ))?;
         let v = parts.next().unwrap();
-        headers.insert(HeaderName::from_str(k)?, HeaderValue::from_str(v)?);
+        headers.append(HeaderName::from_str(k)?, HeaderValue::from_str(v)?);
     }
     Ok(headers)
 }
@@ -684,6 +1555,55 @@ fn header_map_to_string(hm: &HeaderMap) -> Result<String, Error> {
     Ok(res)
 }
 
+/// Decode a header map from its length-prefixed binary encoding.
+fn bytes_to_header_map(mut b: &[u8]) -> Result<HeaderMap, Error> {
+    fn next<'a>(b: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+        anyhow::ensure!(b.len() >= 4, "Truncated header length");
+        let (len, rest) = b.split_at(4);
+        let len = u32::from_le_bytes(len.try_into()?) as usize;
+        anyhow::ensure!(rest.len() >= len, "Truncated header");
+        let (field, rest) = rest.split_at(len);
+        *b = rest;
+        Ok(field)
+    }
+
+    let mut headers = HeaderMap::new();
+    while !b.is_empty() {
+        let name = HeaderName::from_bytes(next(&mut b)?)?;
+        let value = HeaderValue::from_bytes(next(&mut b)?)?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Encode a header map using a length-prefixed binary encoding.
+fn header_map_to_bytes(hm: &HeaderMap) -> Vec<u8> {
+    let mut res = Vec::new();
+    for (name, value) in hm {
+        for field in [name.as_str().as_bytes(), value.as_bytes()] {
+            res.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            res.extend_from_slice(field);
+        }
+    }
+    res
+}
+
+/// Encode all values of the `name` header, one per line.
+fn header_values_to_string(hm: &HeaderMap, name: &str) -> Result<String, HttpError> {
+    let mut values = hm.get_all(name).iter().peekable();
+    if values.peek().is_none() {
+        return Err(HttpError::HeaderNotFound);
+    }
+    let mut res = String::new();
+    for value in values {
+        // Header values cannot contain new lines, so they can be used
+        // as separators.
+        res.push_str(std::str::from_utf8(value.as_bytes())?);
+        res.push('\n');
+    }
+    Ok(res)
+}
+
 #[test]
 #[allow(clippy::bool_assert_comparison)]
 fn test_allowed_domains() {
@@ -762,3 +1682,715 @@ fn test_url_parsing() {
 
     is_allowed("not even a url", Some(allowed_domains.as_ref())).unwrap();
 }
+
+#[test]
+fn test_error_message() {
+    assert_eq!(
+        "Destination not allowed: https://example.com",
+        HttpError::DestinationNotAllowed("https://example.com".to_string()).message()
+    );
+
+    let bytes = vec![0xff];
+    let e = std::str::from_utf8(&bytes).unwrap_err();
+    assert_eq!(
+        "UTF-8 error: invalid utf-8 sequence of 1 bytes from index 0",
+        HttpError::from(e).message()
+    );
+}
+
+#[test]
+fn test_transport_error() {
+    use std::io::{Error, ErrorKind};
+
+    let error = |kind, msg: &str| -> TransportError { Box::new(Error::new(kind, msg.to_string())) };
+    let code = |e| u32::from(witx::v2::types::HttpError::from(&transport_error(e)));
+    assert_eq!(15, code(error(ErrorKind::Other, "dns error")));
+    assert_eq!(16, code(error(ErrorKind::ConnectionRefused, "refused")));
+    assert_eq!(18, code(error(ErrorKind::TimedOut, "timed out")));
+    assert_eq!(11, code(error(ErrorKind::Other, "other")));
+}
+
+#[test]
+fn test_duplicate_headers_round_trip() {
+    let mut hm = HeaderMap::new();
+    hm.append("set-cookie", HeaderValue::from_static("a=1"));
+    hm.append("set-cookie", HeaderValue::from_static("b=2"));
+    hm.append("vary", HeaderValue::from_static("accept"));
+
+    let str = header_map_to_string(&hm).unwrap();
+    assert_eq!("set-cookie:a=1\nset-cookie:b=2\nvary:accept\n", str);
+    assert_eq!(hm, string_to_header_map(&str).unwrap());
+
+    assert_eq!(
+        "a=1\nb=2\n",
+        header_values_to_string(&hm, "set-cookie").unwrap()
+    );
+    assert!(matches!(
+        header_values_to_string(&hm, "link"),
+        Err(HttpError::HeaderNotFound)
+    ));
+}
+
+#[test]
+fn test_binary_headers_round_trip() {
+    let mut hm = HeaderMap::new();
+    hm.append("set-cookie", HeaderValue::from_static("a=1"));
+    hm.append("set-cookie", HeaderValue::from_static("b=2"));
+    hm.append("x-latin-1", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+
+    let bytes = header_map_to_bytes(&hm);
+    assert_eq!(b"\x0a\0\0\0set-cookie\x03\0\0\0a=1", &bytes[..21]);
+    assert_eq!(hm, bytes_to_header_map(&bytes).unwrap());
+    assert!(header_map_to_string(&hm).is_err());
+
+    assert!(bytes_to_header_map(&bytes[..bytes.len() - 1]).is_err());
+    assert!(bytes_to_header_map(b"\x01\0\0\0a\x01\0\0\0\n").is_err());
+    assert!(bytes_to_header_map(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_request_options() {
+    let mut bytes = vec![0; 128];
+    for (i, field) in [1500u32, 2, 1024, 1, 2].iter().enumerate() {
+        bytes[8 + i * 4..12 + i * 4].copy_from_slice(&field.to_le_bytes());
+    }
+    bytes[96 + 12..96 + 16].copy_from_slice(&3u32.to_le_bytes());
+    let memory = wiggle::wasmtime::WasmtimeGuestMemory::new(&mut bytes);
+
+    let options = options_from_guest(&GuestPtr::new(&memory, 8)).unwrap();
+    assert_eq!(Some(Duration::from_millis(1500)), options.timeout);
+    assert!(!options.follow_redirects);
+    assert_eq!(Some(1024), options.max_response_size);
+    assert_eq!(Some(http::Version::HTTP_11), options.http_version);
+    assert_eq!(Some(Decompression::Passthrough), options.decompression);
+
+    // A record of zeros leaves every choice to the host.
+    let options = options_from_guest(&GuestPtr::new(&memory, 64)).unwrap();
+    assert_eq!(None, options.timeout);
+    assert!(options.follow_redirects);
+    assert_eq!(None, options.max_response_size);
+    assert_eq!(None, options.http_version);
+    assert_eq!(None, options.decompression);
+
+    assert!(matches!(
+        options_from_guest(&GuestPtr::new(&memory, 96)),
+        Err(HttpError::InvalidEncoding)
+    ));
+}
+
+#[test]
+fn test_trailers_get_all() {
+    let mut bytes = vec![0; 1024];
+    let memory = wiggle::wasmtime::WasmtimeGuestMemory::new(&mut bytes);
+    let buf = GuestPtr::<[u8]>::new(&memory, (0, 1024));
+    let st = Arc::new(RwLock::new(State::default()));
+
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+    let response = Response {
+        headers: HeaderMap::new(),
+        body: Body {
+            bytes: Bytes::from_static(b"ok"),
+            pos: 0,
+        },
+        trailers: trailers.clone(),
+        metadata: client::Metadata {
+            version: http::Version::HTTP_2,
+            url: "https://example.com".parse().unwrap(),
+            remote_addr: None,
+            decoded_encoding: None,
+            timings: Default::default(),
+        },
+    };
+    st.write().unwrap().responses.insert(0, response);
+
+    let get = || HostCalls::trailers_get_all(st.clone(), 0, &buf);
+    assert!(matches!(get(), Err(HttpError::BodyNotRead)));
+
+    st.write().unwrap().responses.get_mut(&0).unwrap().body.pos = 2;
+    let len = get().unwrap();
+    let written = slice_from_guest(&buf.get_range(0..len).unwrap()).unwrap();
+    assert_eq!(trailers, bytes_to_header_map(&written).unwrap());
+}
+
+/// Check that each instance has its own handles, which are released along
+/// with it.
+#[test]
+fn test_instance_state() {
+    let http = HttpState::new().unwrap();
+    let metrics = Metrics::new();
+    let ctx = HttpCtx {
+        metrics: Some(metrics.clone()),
+        ..Default::default()
+    };
+    let instance = http.instance(ctx.clone());
+    let other = http.instance(ctx);
+
+    let (tx, rx) = std::sync::mpsc::channel::<()>();
+    let task = http.runtime.handle.spawn(async move {
+        let _tx = tx;
+        futures::future::pending().await
+    });
+    instance
+        .state
+        .write()
+        .unwrap()
+        .pending
+        .insert(0, PendingRequest::Running(task));
+    assert_eq!(1, instance.state.read().unwrap().open_handles());
+    assert_eq!(0, other.state.read().unwrap().open_handles());
+    assert!(metrics
+        .encode()
+        .contains("wasi_http_open_handles{module=\"\"} 1\n"));
+
+    // Dropping the instance aborts its requests, and stops reporting its
+    // handles.
+    drop(instance);
+    assert_eq!(
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected),
+        rx.recv_timeout(Duration::from_secs(5))
+    );
+    assert!(metrics
+        .encode()
+        .contains("wasi_http_open_handles{module=\"\"} 0\n"));
+}
+
+/// Check that the last error is recorded for the store of the instance
+/// whose call failed.
+#[test]
+fn test_last_error_per_store() {
+    let engine = Engine::default();
+    let module = Module::new(
+        &engine,
+        r#"(module
+            (import "wasi_experimental_http_v2" "header_get" (func $header_get (param i32 i32 i32 i32 i32 i32) (result i32)))
+            (import "wasi_experimental_http_v2" "last_error" (func $last_error (param i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "fail") (result i32)
+                (call $header_get (i32.const 42) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)))
+            (func (export "last_error") (result i32)
+                (call $last_error (i32.const 0) (i32.const 1024) (i32.const 2048))))"#,
+    )
+    .unwrap();
+    let mut linker = Linker::new(&engine);
+    HttpState::add_to_linker(&mut linker, |cx: &mut HttpInstance| cx).unwrap();
+
+    let http = HttpState::new().unwrap();
+    let mut stores = [
+        Store::new(&engine, http.instance(HttpCtx::default())),
+        Store::new(&engine, http.instance(HttpCtx::default())),
+    ];
+    let instances = stores
+        .iter_mut()
+        .map(|store| linker.instantiate(store, &module).unwrap())
+        .collect::<Vec<_>>();
+    let fail = instances[0]
+        .get_typed_func::<(), u32, _>(&mut stores[0], "fail")
+        .unwrap();
+    assert_eq!(
+        u32::from(witx::v2::types::HttpError::InvalidHandle),
+        fail.call(&mut stores[0], ()).unwrap()
+    );
+
+    let mut written = vec![];
+    for (instance, store) in instances.iter().zip(&mut stores) {
+        let last_error = instance
+            .get_typed_func::<(), u32, _>(&mut *store, "last_error")
+            .unwrap();
+        assert_eq!(0, last_error.call(&mut *store, ()).unwrap());
+        let memory = instance.get_memory(&mut *store, "memory").unwrap();
+        written.push(u32::from_le_bytes(
+            memory.data(&*store)[2048..2052].try_into().unwrap(),
+        ));
+    }
+    assert!(written[0] > 0);
+    assert_eq!(0, written[1]);
+}
+
+/// Tests of the functions linked into guest modules.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instantiate the module written in `wat` in a new store holding an
+    /// instance created from `ctx`, with the HTTP functions linked.
+    fn instantiate(wat: &str, ctx: HttpCtx) -> (Store<HttpInstance>, Instance, Memory) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let http = HttpState::new().unwrap();
+        let mut store = Store::new(&engine, http.instance(ctx));
+        let mut linker = Linker::new(&engine);
+        HttpState::add_to_linker(&mut linker, |cx: &mut HttpInstance| cx).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        (store, instance, memory)
+    }
+
+    /// A context allowing requests to the servers of the tests.
+    fn local_ctx() -> HttpCtx {
+        HttpCtx {
+            allowed_hosts: Some(vec!["http://127.0.0.1".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    /// Listen for the connections of a test on a local port.
+    fn listen() -> (std::net::TcpListener, std::net::SocketAddr) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn test_versioned_modules() {
+        let wat = r#"(module
+                (import "wasi_experimental_http" "req" (func (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req" (func (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "version" (func $version (param i32) (result i32)))
+                (import "wasi_experimental_http_v2" "capabilities" (func $capabilities (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "run") (result i32)
+                    (drop (call $version (i32.const 0)))
+                    (drop (call $capabilities (i32.const 4)))
+                    (i32.load (i32.const 0))))"#;
+
+        let (mut store, instance, memory) = instantiate(wat, HttpCtx::default());
+
+        let run = instance
+            .get_typed_func::<(), u32, _>(&mut store, "run")
+            .unwrap();
+        assert_eq!(ABI_VERSION, run.call(&mut store, ()).unwrap());
+        // Every optional feature is supported.
+        assert_eq!(0b1111u32.to_le_bytes(), memory.data(&store)[4..8]);
+    }
+
+    #[test]
+    fn test_frozen_modules() {
+        // Guests fail to link with hosts lacking a function they import, so
+        // the functions of a module never change once released.
+        let definitions = [
+            include_str!(concat!(env!("OUT_DIR"), "/wasi_experimental_http.witx")),
+            include_str!(concat!(env!("OUT_DIR"), "/wasi_experimental_http_v2.witx")),
+        ];
+        for ((module, funcs), witx) in witx::MODULES.iter().zip(definitions) {
+            let defined = witx
+                .split("(@interface func (export \"")
+                .skip(1)
+                .map(|func| &func[..func.find('"').unwrap()])
+                .collect::<Vec<_>>();
+            assert_eq!(*funcs, defined, "functions of {}", module);
+        }
+    }
+
+    #[test]
+    fn test_unknown_imports() {
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "version" (func $version (param i32) (result i32)))
+                (import "wasi_experimental_http_v3" "version" (func $v3_version (param i32) (result i32)))
+                (import "wasi_experimental_http_v3" "version" (func (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 4) "\07")
+                (func (export "run") (result i32)
+                    (drop (call $version (i32.const 0)))
+                    (i32.add (call $v3_version (i32.const 4)) (i32.load (i32.const 4)))))"#;
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let mut store = Store::new(
+            &engine,
+            HttpState::new().unwrap().instance(HttpCtx::default()),
+        );
+        let mut linker = Linker::new(&engine);
+        HttpState::add_to_linker(&mut linker, |cx: &mut HttpInstance| cx).unwrap();
+        assert!(linker.instantiate(&mut store, &module).is_err());
+        HttpState::define_unknown_imports(&mut linker, &module).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+
+        let run = instance
+            .get_typed_func::<(), u32, _>(&mut store, "run")
+            .unwrap();
+        // The unknown function succeeds without writing its result.
+        assert_eq!(7, run.call(&mut store, ()).unwrap());
+        assert_eq!(ABI_VERSION.to_le_bytes(), memory.data(&store)[0..4]);
+    }
+
+    #[test]
+    fn test_streaming_request() {
+        use std::io::{Read, Write};
+
+        let (listener, addr) = listen();
+        let url = format!("http://{}/upload", addr);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req = vec![];
+            let mut buf = [0u8; 1024];
+            while !req.ends_with(b"0\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(req).unwrap()
+        });
+
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "req_start" (func $start (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req_body_write" (func $write (param i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req_finish" (func $finish (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 256) "POSThello")
+                (func (export "run") (param $url_len i32) (result i32)
+                    (local $err i32)
+                    (local.set $err (call $start (i32.const 0) (local.get $url_len) (i32.const 256) (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 300)))
+                    (if (local.get $err) (then (return (local.get $err))))
+                    (local.set $err (call $write (i32.load (i32.const 300)) (i32.const 260) (i32.const 5)))
+                    (if (local.get $err) (then (return (local.get $err))))
+                    (call $finish (i32.load (i32.const 300)) (i32.const 304) (i32.const 308))))"#;
+
+        let (mut store, instance, memory) = instantiate(wat, local_ctx());
+        memory.write(&mut store, 0, url.as_bytes()).unwrap();
+
+        let run = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "run")
+            .unwrap();
+        assert_eq!(0, run.call(&mut store, url.len() as u32).unwrap());
+        assert_eq!(201u16.to_le_bytes(), memory.data(&store)[304..306]);
+
+        let req = server.join().unwrap();
+        assert!(req.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(req.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_async_requests() {
+        use std::io::{Read, Write};
+
+        let (listener, addr) = listen();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let server = std::thread::spawn(move || {
+            let mut streams = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut req = vec![];
+                let mut buf = [0u8; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                streams.push((req.starts_with(b"GET /a "), stream));
+            }
+            // Respond to `/b` first, and to `/a` once released.
+            streams.sort_by_key(|(a, _)| *a);
+            let (_, mut b) = streams.remove(0);
+            b.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            released.recv().unwrap();
+            let (_, mut a) = streams.remove(0);
+            a.write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "req_async" (func $async (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req_poll" (func $poll (param i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req_wait_any" (func $wait_any (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "req_result" (func $result (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 256) "GET")
+                (func (export "start") (param $url_len i32) (result i32)
+                    (local $err i32)
+                    (local.set $err (call $async (i32.const 0) (local.get $url_len) (i32.const 256) (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 320) (i32.const 400)))
+                    (if (local.get $err) (then (return (local.get $err))))
+                    (call $async (i32.const 128) (local.get $url_len) (i32.const 256) (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 320) (i32.const 404)))
+                (func (export "wait_any") (result i32)
+                    (call $wait_any (i32.const 400) (i32.const 2) (i32.const 0) (i32.const 420)))
+                (func (export "poll") (result i32)
+                    (call $poll (i32.const 400) (i32.const 2) (i32.const 408) (i32.const 2) (i32.const 416)))
+                (func (export "result") (param $handle i32) (result i32)
+                    (call $result (local.get $handle) (i32.const 424) (i32.const 428))))"#;
+
+        let (mut store, instance, memory) = instantiate(wat, local_ctx());
+        let url_a = format!("http://{}/a", addr);
+        let url_b = format!("http://{}/b", addr);
+        memory.write(&mut store, 0, url_a.as_bytes()).unwrap();
+        memory.write(&mut store, 128, url_b.as_bytes()).unwrap();
+        let u32_at = |store: &Store<HttpInstance>, offset: usize| {
+            u32::from_le_bytes(memory.data(store)[offset..offset + 4].try_into().unwrap())
+        };
+
+        let start = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "start")
+            .unwrap();
+        let wait_any = instance
+            .get_typed_func::<(), u32, _>(&mut store, "wait_any")
+            .unwrap();
+        let poll = instance
+            .get_typed_func::<(), u32, _>(&mut store, "poll")
+            .unwrap();
+        let result = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "result")
+            .unwrap();
+
+        assert_eq!(0, start.call(&mut store, url_a.len() as u32).unwrap());
+        let (a, b) = (u32_at(&store, 400), u32_at(&store, 404));
+        assert_ne!(a, b);
+
+        assert_eq!(0, wait_any.call(&mut store, ()).unwrap());
+        assert_eq!(b, u32_at(&store, 420));
+        assert_eq!(0, poll.call(&mut store, ()).unwrap());
+        assert_eq!(1, u32_at(&store, 416));
+        assert_eq!(b, u32_at(&store, 408));
+
+        release.send(()).unwrap();
+        assert_eq!(0, result.call(&mut store, a).unwrap());
+        assert_eq!(201u16.to_le_bytes(), memory.data(&store)[424..426]);
+        assert_eq!(0, result.call(&mut store, b).unwrap());
+        assert_eq!(200u16.to_le_bytes(), memory.data(&store)[424..426]);
+        assert_eq!(1, result.call(&mut store, b).unwrap());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_batch_requests() {
+        use std::io::{Read, Write};
+
+        let (listener, addr) = listen();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut req = vec![];
+                let mut buf = [0u8; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .unwrap();
+            }
+        });
+
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "req_batch" (func $batch (param i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 256) "GET")
+                (func (export "batch") (param $len i32) (result i32)
+                    (call $batch (i32.const 512) (local.get $len) (i32.const 320) (i32.const 640) (i32.const 3))))"#;
+
+        let ctx = HttpCtx {
+            max_concurrent_requests: Some(3),
+            ..local_ctx()
+        };
+        let (mut store, instance, memory) = instantiate(wat, ctx);
+
+        let urls = [
+            format!("http://{}/a", addr),
+            "http://example.com/".to_string(),
+            format!("http://{}/b", addr),
+        ];
+        let mut records = vec![];
+        for (i, url) in urls.iter().enumerate() {
+            let url_ptr = i as u32 * 64;
+            memory
+                .write(&mut store, url_ptr as usize, url.as_bytes())
+                .unwrap();
+            for field in [url_ptr, url.len() as u32, 256, 3, 0, 0, 0, 0] {
+                records.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        memory.write(&mut store, 512, &records).unwrap();
+        let result = |store: &Store<HttpInstance>, i: usize| {
+            let bytes = &memory.data(store)[640 + i * 12..640 + i * 12 + 12];
+            (
+                u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+                u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            )
+        };
+
+        let batch = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "batch")
+            .unwrap();
+        assert_eq!(0, batch.call(&mut store, 3).unwrap());
+        let (error_a, status_a, handle_a) = result(&store, 0);
+        let (error_b, _, _) = result(&store, 1);
+        let (error_c, status_c, handle_c) = result(&store, 2);
+        assert_eq!((0, 200), (error_a, status_a));
+        assert_eq!(7, error_b);
+        assert_eq!((0, 200), (error_c, status_c));
+        assert_ne!(handle_a, handle_c);
+
+        // Two responses are still open, so there is no room for two more requests.
+        assert_eq!(13, batch.call(&mut store, 2).unwrap());
+        // The results buffer only has room for three results.
+        assert_eq!(4, batch.call(&mut store, 4).unwrap());
+    }
+
+    #[test]
+    fn test_websocket() {
+        use futures::{SinkExt, StreamExt};
+
+        let (listener, addr) = listen();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(message)) = ws.next().await {
+                    if message.is_text() || message.is_binary() {
+                        ws.send(message).await.unwrap();
+                    }
+                }
+            });
+        });
+
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "ws_open" (func $open (param i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "ws_send" (func $send (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "ws_recv" (func $recv (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "ws_close" (func $close (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 256) "hello")
+                (data (i32.const 300) "bye")
+                (func (export "open") (param $url_len i32) (result i32)
+                    (call $open (i32.const 0) (local.get $url_len) (i32.const 0) (i32.const 0) (i32.const 400)))
+                (func (export "send") (param $handle i32) (param $type i32) (result i32)
+                    (call $send (local.get $handle) (local.get $type) (i32.const 256) (i32.const 5)))
+                (func (export "recv") (param $handle i32) (param $timeout_ms i32) (param $buf_len i32) (result i32)
+                    (call $recv (local.get $handle) (local.get $timeout_ms) (i32.const 512) (local.get $buf_len) (i32.const 404) (i32.const 408)))
+                (func (export "close") (param $handle i32) (result i32)
+                    (call $close (local.get $handle) (i32.const 1000) (i32.const 300) (i32.const 3))))"#;
+
+        let (mut store, instance, memory) = instantiate(wat, local_ctx());
+        let u32_at = |store: &Store<HttpInstance>, offset: usize| {
+            u32::from_le_bytes(memory.data(store)[offset..offset + 4].try_into().unwrap())
+        };
+
+        let open = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "open")
+            .unwrap();
+        let send = instance
+            .get_typed_func::<(u32, u32), u32, _>(&mut store, "send")
+            .unwrap();
+        let recv = instance
+            .get_typed_func::<(u32, u32, u32), u32, _>(&mut store, "recv")
+            .unwrap();
+        let close = instance
+            .get_typed_func::<u32, u32, _>(&mut store, "close")
+            .unwrap();
+
+        let url = "ws://example.com/";
+        memory.write(&mut store, 0, url.as_bytes()).unwrap();
+        assert_eq!(7, open.call(&mut store, url.len() as u32).unwrap());
+
+        let url = format!("ws://{}/", addr);
+        memory.write(&mut store, 0, url.as_bytes()).unwrap();
+        assert_eq!(0, open.call(&mut store, url.len() as u32).unwrap());
+        let handle = u32_at(&store, 400);
+
+        // A message too large for the buffer is kept for the next call.
+        assert_eq!(0, send.call(&mut store, (handle, 0)).unwrap());
+        assert_eq!(0, recv.call(&mut store, (handle, 0, 2)).unwrap());
+        assert_eq!((0, 5), (u32_at(&store, 404), u32_at(&store, 408)));
+        assert_eq!(0, recv.call(&mut store, (handle, 0, 64)).unwrap());
+        assert_eq!((0, 5), (u32_at(&store, 404), u32_at(&store, 408)));
+        assert_eq!(b"hello", &memory.data(&store)[512..517]);
+
+        assert_eq!(0, send.call(&mut store, (handle, 1)).unwrap());
+        assert_eq!(0, recv.call(&mut store, (handle, 1000, 64)).unwrap());
+        assert_eq!((1, 5), (u32_at(&store, 404), u32_at(&store, 408)));
+
+        // Close messages cannot be sent, and nothing else is received.
+        assert_eq!(9, send.call(&mut store, (handle, 2)).unwrap());
+        assert_eq!(18, recv.call(&mut store, (handle, 100, 64)).unwrap());
+
+        assert_eq!(0, close.call(&mut store, handle).unwrap());
+        assert_eq!(1, recv.call(&mut store, (handle, 0, 64)).unwrap());
+    }
+
+    #[test]
+    fn test_inbound_request() {
+        let wat = r#"(module
+                (import "wasi_experimental_http_v2" "inbound_method_get" (func $method (param i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "inbound_url_get" (func $url (param i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "inbound_headers_get_all" (func $headers (param i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "inbound_body_read" (func $body (param i32 i32 i32) (result i32)))
+                (import "wasi_experimental_http_v2" "inbound_response_set" (func $set (param i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 256) "\03\00\00\00x-a\01\00\00\00b")
+                (data (i32.const 300) "bye")
+                (func (export "method") (param $buf_len i32) (result i32)
+                    (call $method (i32.const 0) (local.get $buf_len) (i32.const 400)))
+                (func (export "url") (param $buf_len i32) (result i32)
+                    (call $url (i32.const 0) (local.get $buf_len) (i32.const 400)))
+                (func (export "headers") (param $buf_len i32) (result i32)
+                    (call $headers (i32.const 0) (local.get $buf_len) (i32.const 400)))
+                (func (export "body") (param $buf_len i32) (result i32)
+                    (call $body (i32.const 0) (local.get $buf_len) (i32.const 400)))
+                (func (export "set") (param $status i32) (result i32)
+                    (call $set (local.get $status) (i32.const 256) (i32.const 12) (i32.const 300) (i32.const 3))))"#;
+
+        let req = http::Request::post("http://localhost:3000/a")
+            .header("x-a", "b")
+            .body(Bytes::from("hello"))
+            .unwrap();
+        let inbound = InboundRequest::new(req);
+        for ctx in [
+            HttpCtx::default(),
+            HttpCtx {
+                inbound: Some(inbound.clone()),
+                ..Default::default()
+            },
+        ] {
+            let serving = ctx.inbound.is_some();
+            let (mut store, instance, memory) = instantiate(wat, ctx);
+            let call = |store: &mut Store<HttpInstance>, name: &str, arg: u32| {
+                instance
+                    .get_typed_func::<u32, u32, _>(&mut *store, name)
+                    .unwrap()
+                    .call(&mut *store, arg)
+                    .unwrap()
+            };
+            let written = |store: &Store<HttpInstance>| {
+                let len = u32::from_le_bytes(memory.data(store)[400..404].try_into().unwrap());
+                memory.data(store)[..len as usize].to_vec()
+            };
+
+            if !serving {
+                assert_eq!(23, call(&mut store, "method", 64));
+                assert_eq!(23, call(&mut store, "set", 200));
+                continue;
+            }
+
+            assert_eq!(0, call(&mut store, "method", 64));
+            assert_eq!(b"POST", &written(&store)[..]);
+            assert_eq!(0, call(&mut store, "url", 64));
+            assert_eq!(b"http://localhost:3000/a", &written(&store)[..]);
+            assert_eq!(4, call(&mut store, "headers", 4));
+            assert_eq!(0, call(&mut store, "headers", 64));
+            assert_eq!(b"\x03\0\0\0x-a\x01\0\0\0b", &written(&store)[..]);
+            assert_eq!(0, call(&mut store, "body", 3));
+            assert_eq!(b"hel", &written(&store)[..]);
+            assert_eq!(0, call(&mut store, "body", 64));
+            assert_eq!(b"lo", &written(&store)[..]);
+            assert_eq!(0, call(&mut store, "body", 64));
+            assert!(written(&store).is_empty());
+
+            assert_eq!(9, call(&mut store, "set", 1000));
+            assert_eq!(0, call(&mut store, "set", 201));
+        }
+
+        let res = inbound.take_response().unwrap();
+        assert_eq!(201, res.status());
+        assert_eq!("b", res.headers()["x-a"]);
+        assert_eq!(Bytes::from("bye"), res.body());
+    }
+}
@@ -0,0 +1,563 @@
+//! Metrics of the outbound HTTP traffic of guests.
+//!
+//! Requests are counted by module, destination host, method and status
+//! class, with histograms of their duration and of the size of their
+//! bodies, and gauges of the requests in flight and of the handles guests
+//! hold. Metrics are encoded in the Prometheus text exposition format, so
+//! that hosts can serve them to a Prometheus server.
+//!
+//! Destination hosts are chosen by guests, so only the first `MAX_HOSTS`
+//! hosts requests are sent to, and the first `MAX_HOSTS` hosts requests are
+//! denied to, get their own label. Requests to other hosts are counted with
+//! the `other` host, which keeps the number of series bounded. Methods
+//! are bounded the same way: methods other than the standard ones are
+//! counted with the `other` method.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// Maximum number of hosts with their own label, for allowed and for
+/// denied requests.
+const MAX_HOSTS: usize = 100;
+/// Label of the hosts beyond `MAX_HOSTS`, and of the methods that are not
+/// in `METHODS`.
+const OTHER: &str = "other";
+/// Methods with their own label.
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT", "TRACE",
+];
+
+/// Upper bounds of the buckets of the request duration histogram, in
+/// seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// Upper bounds of the buckets of the body size histograms, in bytes.
+const SIZE_BUCKETS: &[f64] = &[
+    100.0,
+    1_000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+];
+
+/// Metrics of the requests of guests. Clones share the same metrics, and
+/// `with_module` returns a clone whose requests are counted for a module,
+/// so that a single registry can be served for all the modules of a host.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    module: Arc<str>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("module", &self.module)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Requests by module, host, method and status class.
+    requests: BTreeMap<[String; 4], u64>,
+    /// Requests to hosts that are not allowed, by module and host.
+    denied: BTreeMap<[String; 2], u64>,
+    /// Histograms by module and host.
+    duration: BTreeMap<[String; 2], Histogram>,
+    request_size: BTreeMap<[String; 2], Histogram>,
+    response_size: BTreeMap<[String; 2], Histogram>,
+    in_flight: BTreeMap<String, u64>,
+    /// Functions returning the number of open handles of a module, by the
+    /// ID of their `OpenHandles`.
+    open_handles: BTreeMap<u64, (String, HandleCount)>,
+    next_open_handles: u64,
+    /// Hosts with their own label, for allowed and for denied requests.
+    hosts: HostLabels,
+    denied_hosts: HostLabels,
+}
+
+/// Hosts given their own label, up to `MAX_HOSTS`.
+#[derive(Default)]
+struct HostLabels(BTreeSet<String>);
+
+impl HostLabels {
+    /// The label of `host`, which is `other` if `MAX_HOSTS` other hosts
+    /// already have their own label.
+    fn label(&mut self, host: &str) -> String {
+        if !self.0.contains(host) {
+            if self.0.len() >= MAX_HOSTS {
+                return OTHER.to_string();
+            }
+            self.0.insert(host.to_string());
+        }
+        host.to_string()
+    }
+}
+
+type HandleCount = Arc<dyn Fn() -> Option<usize> + Send + Sync>;
+
+/// The open handles of an instance, reported until dropped.
+pub(crate) struct OpenHandles {
+    metrics: Metrics,
+    id: u64,
+}
+
+impl Drop for OpenHandles {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = self.metrics.registry.lock() {
+            registry.open_handles.remove(&self.id);
+        }
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// The number of observations in each bucket, not cumulated.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// Create an empty registry, whose requests are counted for a module
+    /// with an empty name.
+    pub fn new() -> Self {
+        Metrics {
+            registry: Default::default(),
+            module: "".into(),
+        }
+    }
+
+    /// Return a clone counting requests for `module`.
+    pub fn with_module(&self, module: &str) -> Self {
+        Metrics {
+            registry: self.registry.clone(),
+            module: module.into(),
+        }
+    }
+
+    /// Encode the metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        // The handles are counted without holding the registry, as counting
+        // them locks the state of the instances.
+        let counts = self
+            .registry
+            .lock()
+            .unwrap()
+            .open_handles
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut open_handles = BTreeMap::new();
+        for (module, count) in counts {
+            if let Some(count) = count() {
+                *open_handles.entry(module).or_default() += count as u64;
+            }
+        }
+
+        let registry = self.registry.lock().unwrap();
+
+        let mut out = String::new();
+        header(
+            &mut out,
+            "wasi_http_requests_total",
+            "counter",
+            "Outbound requests of guests, by destination host, method and status class",
+        );
+        for ([module, host, method, status], count) in &registry.requests {
+            let labels = labels(&[
+                ("module", module),
+                ("host", host),
+                ("method", method),
+                ("status", status),
+            ]);
+            writeln!(out, "wasi_http_requests_total{{{}}} {}", labels, count).unwrap();
+        }
+        header(
+            &mut out,
+            "wasi_http_denied_requests_total",
+            "counter",
+            "Outbound requests of guests to hosts they are not allowed to send requests to",
+        );
+        for ([module, host], count) in &registry.denied {
+            let labels = labels(&[("module", module), ("host", host)]);
+            writeln!(
+                out,
+                "wasi_http_denied_requests_total{{{}}} {}",
+                labels, count
+            )
+            .unwrap();
+        }
+        for (name, help, histograms) in [
+            (
+                "wasi_http_request_duration_seconds",
+                "Duration of outbound requests, up to reading the entire response body",
+                &registry.duration,
+            ),
+            (
+                "wasi_http_request_body_bytes",
+                "Size of the bodies of outbound requests, unless they are streamed",
+                &registry.request_size,
+            ),
+            (
+                "wasi_http_response_body_bytes",
+                "Size of the bodies of responses, once decoded",
+                &registry.response_size,
+            ),
+        ] {
+            header(&mut out, name, "histogram", help);
+            for ([module, host], histogram) in histograms {
+                encode_histogram(&mut out, name, module, host, histogram);
+            }
+        }
+        for (name, help, gauges) in [
+            (
+                "wasi_http_requests_in_flight",
+                "Outbound requests waiting for their response",
+                &registry.in_flight,
+            ),
+            (
+                "wasi_http_open_handles",
+                "Responses, requests and WebSocket connections held by guests",
+                &open_handles,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            for (module, value) in gauges {
+                let labels = labels(&[("module", module)]);
+                writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+            }
+        }
+        out
+    }
+
+    /// Count a request to `url` as denied.
+    pub(crate) fn request_denied(&self, url: &str) {
+        let mut registry = self.registry.lock().unwrap();
+        let host = registry.denied_hosts.label(&host(url));
+        *registry
+            .denied
+            .entry([self.module.to_string(), host])
+            .or_default() += 1;
+    }
+
+    /// Count a request as in flight until the returned request is
+    /// finished or dropped.
+    pub(crate) fn request_started(
+        &self,
+        url: &Url,
+        method: &str,
+        body_size: Option<usize>,
+    ) -> RequestMetrics {
+        let mut registry = self.registry.lock().unwrap();
+        let host = registry.hosts.label(url.host_str().unwrap_or_default());
+        *registry
+            .in_flight
+            .entry(self.module.to_string())
+            .or_default() += 1;
+        if let Some(size) = body_size {
+            registry
+                .request_size
+                .entry([self.module.to_string(), host.clone()])
+                .or_insert_with(|| Histogram::new(SIZE_BUCKETS))
+                .observe(size as f64);
+        }
+        RequestMetrics {
+            metrics: self.clone(),
+            host,
+            method: self::method(method),
+            start: Instant::now(),
+        }
+    }
+
+    /// Report the number of open handles of the module with `count`, until
+    /// the returned `OpenHandles` is dropped. `count` returns `None` if the
+    /// handles cannot be counted.
+    pub(crate) fn register_open_handles(
+        &self,
+        count: impl Fn() -> Option<usize> + Send + Sync + 'static,
+    ) -> OpenHandles {
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_open_handles;
+        registry.next_open_handles += 1;
+        registry
+            .open_handles
+            .insert(id, (self.module.to_string(), Arc::new(count)));
+        OpenHandles {
+            metrics: self.clone(),
+            id,
+        }
+    }
+}
+
+/// A request in flight.
+pub(crate) struct RequestMetrics {
+    metrics: Metrics,
+    host: String,
+    method: &'static str,
+    start: Instant,
+}
+
+impl RequestMetrics {
+    /// Count the request with the status code and body size of its
+    /// response, or as failed.
+    pub(crate) fn finish(self, res: Option<(u16, usize)>) {
+        let duration = self.start.elapsed();
+        self.record(res, duration);
+    }
+
+    fn record(&self, res: Option<(u16, usize)>, duration: Duration) {
+        let module = self.metrics.module.to_string();
+        let status = match res {
+            Some((status, _)) => format!("{}xx", status / 100),
+            None => "error".to_string(),
+        };
+        let mut registry = self.metrics.registry.lock().unwrap();
+        *registry
+            .requests
+            .entry([
+                module.clone(),
+                self.host.clone(),
+                self.method.to_string(),
+                status,
+            ])
+            .or_default() += 1;
+        let key = [module, self.host.clone()];
+        registry
+            .duration
+            .entry(key.clone())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(duration.as_secs_f64());
+        if let Some((_, size)) = res {
+            registry
+                .response_size
+                .entry(key)
+                .or_insert_with(|| Histogram::new(SIZE_BUCKETS))
+                .observe(size as f64);
+        }
+    }
+}
+
+impl Drop for RequestMetrics {
+    fn drop(&mut self) {
+        let mut registry = self.metrics.registry.lock().unwrap();
+        if let Some(count) = registry.in_flight.get_mut(&*self.metrics.module) {
+            *count -= 1;
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn encode_histogram(out: &mut String, name: &str, module: &str, host: &str, histogram: &Histogram) {
+    let mut cumulated = 0;
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.buckets) {
+        cumulated += count;
+        let labels = labels(&[
+            ("module", module),
+            ("host", host),
+            ("le", &bound.to_string()),
+        ]);
+        writeln!(out, "{}_bucket{{{}}} {}", name, labels, cumulated).unwrap();
+    }
+    let labels = labels(&[("module", module), ("host", host), ("le", "+Inf")]);
+    writeln!(out, "{}_bucket{{{}}} {}", name, labels, histogram.count).unwrap();
+    let labels = self::labels(&[("module", module), ("host", host)]);
+    writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum).unwrap();
+    writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count).unwrap();
+}
+
+/// Encode labels, escaping their values.
+fn labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The label of `method`, which is `other` if it is not a standard method.
+/// Methods are case-sensitive, so `get` is not `GET`.
+fn method(method: &str) -> &'static str {
+    METHODS
+        .iter()
+        .find(|m| **m == method)
+        .copied()
+        .unwrap_or(OTHER)
+}
+
+/// The host of a URL, or an empty string if it is invalid.
+fn host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new().with_module("a\"b");
+        let url = Url::parse("https://example.com/").unwrap();
+        let req = metrics.request_started(&url, "POST", Some(500));
+        assert!(metrics
+            .encode()
+            .contains("wasi_http_requests_in_flight{module=\"a\\\"b\"} 1\n"));
+        req.record(Some((201, 20)), Duration::from_millis(30));
+        drop(req);
+        let req = metrics.request_started(&url, "POST", None);
+        req.record(None, Duration::from_secs(60));
+        drop(req);
+        metrics.request_denied("https://other.com/");
+
+        let handles = Arc::new(Mutex::new(Some(2)));
+        let count = handles.clone();
+        let open_handles = metrics.register_open_handles(move || *count.lock().unwrap());
+
+        let out = metrics.encode();
+        let labels = "module=\"a\\\"b\",host=\"example.com\"";
+        for line in [
+            "# TYPE wasi_http_requests_total counter".to_string(),
+            format!(
+                "wasi_http_requests_total{{{},method=\"POST\",status=\"2xx\"}} 1",
+                labels
+            ),
+            format!(
+                "wasi_http_requests_total{{{},method=\"POST\",status=\"error\"}} 1",
+                labels
+            ),
+            "wasi_http_denied_requests_total{module=\"a\\\"b\",host=\"other.com\"} 1".to_string(),
+            format!(
+                "wasi_http_request_duration_seconds_bucket{{{},le=\"0.025\"}} 0",
+                labels
+            ),
+            format!(
+                "wasi_http_request_duration_seconds_bucket{{{},le=\"0.05\"}} 1",
+                labels
+            ),
+            format!(
+                "wasi_http_request_duration_seconds_bucket{{{},le=\"30\"}} 1",
+                labels
+            ),
+            format!(
+                "wasi_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                labels
+            ),
+            format!("wasi_http_request_duration_seconds_count{{{}}} 2", labels),
+            format!(
+                "wasi_http_request_body_bytes_bucket{{{},le=\"1000\"}} 1",
+                labels
+            ),
+            format!("wasi_http_request_body_bytes_sum{{{}}} 500", labels),
+            format!("wasi_http_response_body_bytes_count{{{}}} 1", labels),
+            "wasi_http_requests_in_flight{module=\"a\\\"b\"} 0".to_string(),
+            "wasi_http_open_handles{module=\"a\\\"b\"} 2".to_string(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{} not in\n{}", line, out);
+        }
+
+        // Handles that cannot be counted are skipped, and dropped gauges
+        // are removed.
+        *handles.lock().unwrap() = None;
+        assert!(!metrics.encode().contains("wasi_http_open_handles{"));
+        *handles.lock().unwrap() = Some(1);
+        drop(open_handles);
+        assert!(!metrics.encode().contains("wasi_http_open_handles{"));
+    }
+
+    #[test]
+    fn test_host_labels() {
+        let metrics = Metrics::new();
+        for i in 0..MAX_HOSTS {
+            let url = Url::parse(&format!("https://{}.example.com/", i)).unwrap();
+            metrics.request_started(&url, "GET", None).finish(None);
+        }
+        let url = Url::parse("https://0.example.com/").unwrap();
+        metrics.request_started(&url, "GET", None).finish(None);
+        let url = Url::parse("https://new.example.com/").unwrap();
+        metrics.request_started(&url, "GET", None).finish(None);
+        // Denied hosts have labels of their own.
+        metrics.request_denied("https://denied.com/");
+
+        let out = metrics.encode();
+        let requests = |host: &str| {
+            format!(
+                "wasi_http_requests_total{{module=\"\",host=\"{}\",method=\"GET\",status=\"error\"}} ",
+                host
+            )
+        };
+        assert!(out.contains(&format!("{}2\n", requests("0.example.com"))));
+        assert!(out.contains(&format!("{}1\n", requests("other"))));
+        assert!(!out.contains("new.example.com"));
+        assert!(
+            out.contains("wasi_http_denied_requests_total{module=\"\",host=\"denied.com\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_method_labels() {
+        let metrics = Metrics::new();
+        let url = Url::parse("https://example.com/").unwrap();
+        for method in ["GET", "DELETE", "PROPFIND", "get", "X\"Y"] {
+            metrics.request_started(&url, method, None).finish(None);
+        }
+
+        let out = metrics.encode();
+        let requests = |method: &str| {
+            format!(
+                "wasi_http_requests_total{{module=\"\",host=\"example.com\",method=\"{}\",status=\"error\"}} ",
+                method
+            )
+        };
+        assert!(out.contains(&format!("{}1\n", requests("GET"))));
+        assert!(out.contains(&format!("{}1\n", requests("DELETE"))));
+        assert!(out.contains(&format!("{}3\n", requests("other"))));
+        assert!(!out.contains("PROPFIND"));
+        assert!(!out.contains("method=\"get\""));
+    }
+}
@@ -0,0 +1,502 @@
+use anyhow::{bail, Context, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerName,
+    SupportedProtocolVersion,
+};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::SystemTime};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// The lowest TLS protocol version the host will negotiate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => bail!("unsupported TLS version {}, expected 1.2 or 1.3", s),
+        }
+    }
+}
+
+/// A PEM-encoded certificate chain and private key presented to a
+/// server that requires mutual TLS.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    pub certificate_chain: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+
+/// TLS options applied by the host to every outbound request.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// PEM-encoded root certificates trusted in addition to the system roots.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Only trust `root_certificates`, ignoring the system certificate store.
+    pub disable_system_roots: bool,
+    /// Client identities for mutual TLS, keyed by destination host name.
+    pub client_identities: HashMap<String, ClientIdentity>,
+    /// Minimum TLS version. Defaults to TLS 1.2.
+    pub min_tls_version: Option<TlsVersion>,
+    /// ALPN protocols in order of preference. Defaults to `h2`, `http/1.1`.
+    pub alpn_protocols: Vec<String>,
+}
+
+/// A pin that the certificate chain presented by a server must match.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CertificatePin {
+    /// SHA-256 digest of the DER-encoded SubjectPublicKeyInfo,
+    /// written as `sha256/<base64>`.
+    PublicKey([u8; 32]),
+    /// SHA-256 digest of the DER-encoded certificate,
+    /// written as `cert-sha256/<base64>`.
+    Certificate([u8; 32]),
+}
+
+impl CertificatePin {
+    fn matches(&self, cert: &Certificate) -> bool {
+        match self {
+            CertificatePin::Certificate(digest) => Sha256::digest(&cert.0).as_slice() == digest,
+            CertificatePin::PublicKey(digest) => match X509Certificate::from_der(&cert.0) {
+                Ok((_, cert)) => Sha256::digest(cert.public_key().raw).as_slice() == digest,
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for CertificatePin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decode = |b64: &str| -> Result<[u8; 32], Error> {
+            let digest = STANDARD.decode(b64).context("invalid base64 in pin")?;
+            digest
+                .try_into()
+                .map_err(|_| anyhow::format_err!("pin must be a SHA-256 digest"))
+        };
+        if let Some(b64) = s.strip_prefix("sha256/") {
+            Ok(CertificatePin::PublicKey(decode(b64)?))
+        } else if let Some(b64) = s.strip_prefix("cert-sha256/") {
+            Ok(CertificatePin::Certificate(decode(b64)?))
+        } else {
+            bail!("pin must start with `sha256/` or `cert-sha256/`")
+        }
+    }
+}
+
+/// Error returned by the certificate verifier when no certificate
+/// presented by the server matches the pins for its host.
+#[derive(Debug, thiserror::Error)]
+#[error("no certificate presented by {0} matches its pins")]
+struct PinMismatch(String);
+
+/// Certificate verifier that performs the regular WebPKI validation,
+/// then checks the presented chain against a set of pins.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    host: String,
+    pins: Vec<CertificatePin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.pins.iter().any(|pin| pin.matches(cert)))
+        {
+            Ok(verified)
+        } else {
+            tracing::warn!(host = %self.host, "server certificate does not match pins");
+            Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                Arc::new(PinMismatch(self.host.clone())),
+            )))
+        }
+    }
+
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
+    }
+}
+
+/// Find the first TLS error in `err` or its chain of sources.
+fn rustls_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a rustls::Error> {
+    crate::error_chain(err).find_map(|err| err.downcast_ref::<rustls::Error>())
+}
+
+/// Check whether `err`, or any error in its chain of sources, was
+/// caused by a server certificate not matching its pins.
+pub(crate) fn is_pin_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        rustls_error(err),
+        Some(rustls::Error::InvalidCertificate(CertificateError::Other(other)))
+            if other.downcast_ref::<PinMismatch>().is_some()
+    )
+}
+
+/// Check whether `err`, or any error in its chain of sources, was
+/// raised during the TLS handshake or by the TLS session.
+pub(crate) fn is_tls_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    rustls_error(err).is_some()
+}
+
+/// TLS client configurations built once from a `TlsConfig`, so that
+/// certificates are loaded and validated before any guest runs.
+pub(crate) struct Tls {
+    roots: Arc<RootCertStore>,
+    default: ClientConfig,
+    per_host: HashMap<String, ClientConfig>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: &TlsConfig) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        if !config.disable_system_roots {
+            match rustls_native_certs::load_native_certs() {
+                Ok(native) => {
+                    let native: Vec<_> = native.into_iter().map(|c| c.0).collect();
+                    roots.add_parsable_certificates(&native);
+                }
+                Err(e) => tracing::warn!(error = %e, "cannot load the system root certificates"),
+            }
+        }
+        for pem in &config.root_certificates {
+            for cert in certificates_from_pem(pem)? {
+                roots.add(&cert).context("invalid root certificate")?;
+            }
+        }
+        let roots = Arc::new(roots);
+
+        let versions: &[&SupportedProtocolVersion] = match config.min_tls_version {
+            None | Some(TlsVersion::Tls12) => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+        };
+        let alpn_protocols: Vec<Vec<u8>> = if config.alpn_protocols.is_empty() {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            config
+                .alpn_protocols
+                .iter()
+                .map(|p| p.as_bytes().to_vec())
+                .collect()
+        };
+
+        let builder = || -> Result<_, Error> {
+            Ok(ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(versions)?
+                .with_root_certificates(roots.clone()))
+        };
+
+        let mut default = builder()?.with_no_client_auth();
+        default.alpn_protocols = alpn_protocols.clone();
+
+        let mut per_host = HashMap::new();
+        for (host, identity) in &config.client_identities {
+            let chain = certificates_from_pem(&identity.certificate_chain)?;
+            let key = private_key_from_pem(&identity.private_key)?;
+            let mut cfg = builder()?
+                .with_client_auth_cert(chain, key)
+                .with_context(|| format!("invalid client identity for {}", host))?;
+            cfg.alpn_protocols = alpn_protocols.clone();
+            per_host.insert(host.to_ascii_lowercase(), cfg);
+        }
+
+        Ok(Tls {
+            roots,
+            default,
+            per_host,
+        })
+    }
+
+    /// Get the client configuration to use when connecting to `host`.
+    /// If `pins` is not empty, the server must present a certificate
+    /// matching at least one of them.
+    pub(crate) fn client_config(&self, host: &str, pins: &[CertificatePin]) -> ClientConfig {
+        let mut config = self
+            .per_host
+            .get(&host.to_ascii_lowercase())
+            .unwrap_or(&self.default)
+            .clone();
+        if !pins.is_empty() {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner: WebPkiVerifier::new(self.roots.clone(), None),
+                    host: host.to_string(),
+                    pins: pins.to_vec(),
+                }));
+        }
+        config
+    }
+}
+
+fn certificates_from_pem(pem: &[u8]) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut &pem[..]).context("invalid PEM certificate")?;
+    if certs.is_empty() {
+        bail!("no certificates found in PEM data");
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn private_key_from_pem(pem: &[u8]) -> Result<PrivateKey, Error> {
+    let mut rd = pem;
+    loop {
+        match rustls_pemfile::read_one(&mut rd).context("invalid PEM private key")? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in PEM data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_version_from_str() {
+        assert_eq!(TlsVersion::Tls12, "1.2".parse().unwrap());
+        assert_eq!(TlsVersion::Tls13, "1.3".parse().unwrap());
+        assert!("1.1".parse::<TlsVersion>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_root_certificate() {
+        let config = TlsConfig {
+            root_certificates: vec![b"not a certificate".to_vec()],
+            disable_system_roots: true,
+            ..Default::default()
+        };
+        assert!(Tls::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_certificate_pin_from_str() {
+        let b64 = STANDARD.encode([7u8; 32]);
+        assert_eq!(
+            CertificatePin::PublicKey([7u8; 32]),
+            format!("sha256/{}", b64).parse().unwrap()
+        );
+        assert_eq!(
+            CertificatePin::Certificate([7u8; 32]),
+            format!("cert-sha256/{}", b64).parse().unwrap()
+        );
+        assert!(format!("sha1/{}", b64).parse::<CertificatePin>().is_err());
+        assert!("sha256/dG9vIHNob3J0".parse::<CertificatePin>().is_err());
+    }
+
+    #[test]
+    fn test_is_pin_mismatch() {
+        let err = rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(
+            PinMismatch("example.com".to_string()),
+        )));
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        assert!(is_pin_mismatch(&io));
+
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, "other");
+        assert!(!is_pin_mismatch(&io));
+    }
+
+    #[test]
+    fn test_is_tls_error() {
+        let err = rustls::Error::InvalidCertificate(CertificateError::Expired);
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        assert!(is_tls_error(&io));
+        assert!(!is_pin_mismatch(&io));
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!is_tls_error(&io));
+    }
+
+    /// A certificate authority issuing the certificates of a test.
+    struct Pki(rcgen::Certificate);
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = rcgen::CertificateParams::new(vec![]);
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, "Test CA");
+            Pki(rcgen::Certificate::from_params(params).unwrap())
+        }
+
+        fn root_pem(&self) -> Vec<u8> {
+            self.0.serialize_pem().unwrap().into_bytes()
+        }
+
+        /// Issue a certificate for `name`, and get it along with its
+        /// private key and the identity of a client presenting it.
+        fn issue(
+            &self,
+            name: &str,
+            usage: rcgen::ExtendedKeyUsagePurpose,
+        ) -> (Certificate, PrivateKey, ClientIdentity) {
+            let mut params = rcgen::CertificateParams::new(vec![name.to_string()]);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            // Signatures are randomized, so the PEM chain is encoded from
+            // the same signed certificate.
+            let der = cert.serialize_der_with_signer(&self.0).unwrap();
+            let pem = format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                STANDARD.encode(&der)
+            );
+            let identity = ClientIdentity {
+                certificate_chain: pem.into_bytes(),
+                private_key: cert.serialize_private_key_pem().into_bytes(),
+            };
+            (
+                Certificate(der),
+                PrivateKey(cert.serialize_private_key_der()),
+                identity,
+            )
+        }
+
+        /// A server presenting a certificate for `name`, which accepts
+        /// clients with or without a certificate issued by this authority.
+        fn server(&self, name: &str) -> (rustls::ServerConfig, Certificate) {
+            let (cert, key, _) = self.issue(name, rcgen::ExtendedKeyUsagePurpose::ServerAuth);
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(&Certificate(self.0.serialize_der().unwrap()))
+                .unwrap();
+            let config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(
+                    rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+                .with_single_cert(vec![cert.clone()], key)
+                .unwrap();
+            (config, cert)
+        }
+
+        /// The TLS configurations of a host only trusting this authority.
+        fn tls(&self, client_identities: HashMap<String, ClientIdentity>) -> Tls {
+            Tls::new(&TlsConfig {
+                root_certificates: vec![self.root_pem()],
+                disable_system_roots: true,
+                client_identities,
+                ..Default::default()
+            })
+            .unwrap()
+        }
+    }
+
+    /// Perform a handshake with `server` as `host`, and get the certificate
+    /// the client presented, if any.
+    async fn handshake(
+        client: ClientConfig,
+        host: &str,
+        server: rustls::ServerConfig,
+    ) -> std::io::Result<Option<Certificate>> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let stream = tokio_rustls::TlsAcceptor::from(Arc::new(server))
+                .accept(server_io)
+                .await?;
+            let certs = stream.get_ref().1.peer_certificates();
+            Ok::<_, std::io::Error>(certs.and_then(|certs| certs.first().cloned()))
+        });
+        let name = ServerName::try_from(host).unwrap();
+        let client = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(name, client_io)
+            .await;
+        let server = server.await.unwrap();
+        client?;
+        server
+    }
+
+    #[tokio::test]
+    async fn test_root_certificates() {
+        let pki = Pki::new();
+        let (server, _) = pki.server("server.test");
+        let tls = pki.tls(HashMap::new());
+        handshake(tls.client_config("server.test", &[]), "server.test", server)
+            .await
+            .unwrap();
+
+        // The certificates of another authority are not trusted.
+        let (server, _) = Pki::new().server("server.test");
+        let err = handshake(tls.client_config("server.test", &[]), "server.test", server)
+            .await
+            .unwrap_err();
+        assert!(is_tls_error(&err));
+        assert!(!is_pin_mismatch(&err));
+    }
+
+    #[tokio::test]
+    async fn test_client_identities() {
+        let pki = Pki::new();
+        let (cert, _, identity) = pki.issue("client", rcgen::ExtendedKeyUsagePurpose::ClientAuth);
+        let tls = pki.tls([("Server.Test".to_string(), identity)].into());
+
+        let (server, _) = pki.server("server.test");
+        let presented = handshake(tls.client_config("server.test", &[]), "server.test", server)
+            .await
+            .unwrap();
+        assert_eq!(Some(cert), presented);
+
+        // The identity is only presented to its host.
+        let (server, _) = pki.server("other.test");
+        let presented = handshake(tls.client_config("other.test", &[]), "other.test", server)
+            .await
+            .unwrap();
+        assert_eq!(None, presented);
+    }
+
+    #[tokio::test]
+    async fn test_certificate_pins() {
+        let pki = Pki::new();
+        let tls = pki.tls(HashMap::new());
+        let (server, cert) = pki.server("server.test");
+        let (_, parsed) = X509Certificate::from_der(&cert.0).unwrap();
+        let public_key = CertificatePin::PublicKey(Sha256::digest(parsed.public_key().raw).into());
+        let certificate = CertificatePin::Certificate(Sha256::digest(&cert.0).into());
+        let other = CertificatePin::PublicKey([0; 32]);
+
+        for pins in [vec![public_key], vec![other.clone(), certificate]] {
+            let config = tls.client_config("server.test", &pins);
+            handshake(config, "server.test", server.clone())
+                .await
+                .unwrap();
+        }
+
+        let config = tls.client_config("server.test", &[other]);
+        let err = handshake(config, "server.test", server).await.unwrap_err();
+        assert!(is_pin_mismatch(&err));
+    }
+}
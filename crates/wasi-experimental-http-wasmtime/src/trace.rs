@@ -0,0 +1,372 @@
+//! W3C trace context propagation.
+//!
+//! A trace context ties the requests of an instance to a distributed trace:
+//! each outbound request is sent with a `traceparent` header identifying the
+//! trace and a new span for the request, and with the `tracestate` of the
+//! trace, unless the guest set a `traceparent` itself. The host sets the
+//! context, for instance from the headers of the inbound request of the
+//! instance, and guests can replace it with `trace_context_set`.
+//!
+//! With the `opentelemetry` feature, the spans of outbound requests are
+//! children of the span of the context, and the `traceparent` header of a
+//! request carries the ID of its span, so that a `tracing-opentelemetry`
+//! layer exports them as part of the trace.
+
+use anyhow::{bail, Error};
+use http::{HeaderMap, HeaderValue};
+use rand::RngCore;
+use std::sync::{Arc, Mutex};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+/// Trace flag of traces whose spans are recorded.
+const SAMPLED: u8 = 1;
+
+/// Trace context of an instance. Clones share the same context, so that a
+/// context set by the guest applies to the later requests of the instance.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    inner: Arc<Mutex<Context>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Context {
+    trace_id: [u8; 16],
+    /// The span the requests are children of, unless they start the trace.
+    parent_id: Option<[u8; 8]>,
+    flags: u8,
+    state: Option<HeaderValue>,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceContext {
+    /// Start a new trace, which is sampled. With the `opentelemetry`
+    /// feature, this continues the trace of the current span instead, if
+    /// it is exported.
+    pub fn new() -> Self {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(context) = otel::current() {
+            return Self::with_context(context);
+        }
+        Self::with_context(Context {
+            trace_id: random_id(),
+            parent_id: None,
+            flags: SAMPLED,
+            state: None,
+        })
+    }
+
+    /// Continue the trace of the `traceparent` and `tracestate` headers of
+    /// a request, or start a new trace if they are missing or invalid.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let state = headers.get(TRACESTATE).cloned();
+        match headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_traceparent(value, state).ok())
+        {
+            Some(context) => Self::with_context(context),
+            None => Self::new(),
+        }
+    }
+
+    fn with_context(context: Context) -> Self {
+        TraceContext {
+            inner: Arc::new(Mutex::new(context)),
+        }
+    }
+
+    /// Replace the context with that of `traceparent` and `tracestate`
+    /// header values. Invalid values leave the context unchanged.
+    pub fn set(&self, traceparent: &str, tracestate: Option<&str>) -> Result<(), Error> {
+        let state = tracestate.map(HeaderValue::from_str).transpose()?;
+        *self.inner.lock().unwrap() = parse_traceparent(traceparent, state)?;
+        Ok(())
+    }
+
+    /// The ID of the trace, as 32 hexadecimal digits.
+    pub fn trace_id(&self) -> String {
+        hex(&self.inner.lock().unwrap().trace_id)
+    }
+
+    /// Make `span` a child of the span of the context, if it has one.
+    /// This does nothing without the `opentelemetry` feature.
+    pub(crate) fn link(&self, span: &tracing::Span) {
+        #[cfg(feature = "opentelemetry")]
+        otel::link(&self.inner.lock().unwrap(), span);
+        #[cfg(not(feature = "opentelemetry"))]
+        let _ = span;
+    }
+
+    /// Add the trace context headers to an outbound request, with the ID
+    /// of the current span if it is exported in the trace, or a new span
+    /// ID, unless it already has a `traceparent` header.
+    pub(crate) fn inject(&self, headers: &mut HeaderMap) {
+        if headers.contains_key(TRACEPARENT) {
+            return;
+        }
+        let context = self.inner.lock().unwrap().clone();
+        #[cfg(feature = "opentelemetry")]
+        let span_id = otel::current()
+            .filter(|current| current.trace_id == context.trace_id)
+            .and_then(|current| current.parent_id)
+            .unwrap_or_else(random_id);
+        #[cfg(not(feature = "opentelemetry"))]
+        let span_id = random_id::<8>();
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            hex(&context.trace_id),
+            hex(&span_id),
+            context.flags
+        );
+        tracing::debug!(
+            trace_id = %hex(&context.trace_id),
+            span_id = %hex(&span_id),
+            parent_span_id = ?context.parent_id.map(|id| hex(&id)),
+            "propagating trace context"
+        );
+        headers.insert(TRACEPARENT, HeaderValue::from_str(&traceparent).unwrap());
+        if let Some(state) = context.state {
+            headers.insert(TRACESTATE, state);
+        }
+    }
+}
+
+/// Parse a `traceparent` header value. Values of later versions are
+/// parsed as version `00`, ignoring the fields they add.
+fn parse_traceparent(value: &str, state: Option<HeaderValue>) -> Result<Context, Error> {
+    let mut fields = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(v), Some(t), Some(p), Some(f)) => (v, t, p, f),
+            _ => bail!("invalid traceparent: {}", value),
+        };
+    let version = parse_hex::<1>(version)?[0];
+    if version == 0xff || (version == 0 && fields.next().is_some()) {
+        bail!("invalid traceparent version: {}", value);
+    }
+    let trace_id = parse_hex::<16>(trace_id)?;
+    let parent_id = parse_hex::<8>(parent_id)?;
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        bail!("invalid traceparent IDs: {}", value);
+    }
+    Ok(Context {
+        trace_id,
+        parent_id: Some(parent_id),
+        flags: parse_hex::<1>(flags)?[0],
+        state: state.filter(|state| !state.is_empty()),
+    })
+}
+
+/// Parse exactly `N` bytes of lowercase hexadecimal digits.
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], Error> {
+    if s.len() != 2 * N || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("invalid hexadecimal field: {}", s);
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A random ID, which is never all zeros.
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    while id == [0; N] {
+        rand::thread_rng().fill_bytes(&mut id);
+    }
+    id
+}
+
+/// Conversions between trace contexts and the spans exported by a
+/// `tracing-opentelemetry` layer.
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use super::Context;
+    use http::HeaderValue;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use std::str::FromStr;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    /// The context of the current span, whose ID is the parent ID, if it
+    /// is exported.
+    pub(super) fn current() -> Option<Context> {
+        let cx = tracing::Span::current().context();
+        let span = cx.span();
+        let span_context = span.span_context();
+        if !span_context.is_valid() {
+            return None;
+        }
+        let state = span_context.trace_state().header();
+        Some(Context {
+            trace_id: span_context.trace_id().to_bytes(),
+            parent_id: Some(span_context.span_id().to_bytes()),
+            flags: span_context.trace_flags().to_u8(),
+            state: HeaderValue::from_str(&state)
+                .ok()
+                .filter(|state| !state.is_empty()),
+        })
+    }
+
+    /// Make `span` a child of the remote parent of `context`.
+    pub(super) fn link(context: &Context, span: &tracing::Span) {
+        let parent_id = match context.parent_id {
+            Some(parent_id) => parent_id,
+            None => return,
+        };
+        let state = context
+            .state
+            .as_ref()
+            .and_then(|state| state.to_str().ok())
+            .and_then(|state| TraceState::from_str(state).ok())
+            .unwrap_or_default();
+        let parent = SpanContext::new(
+            TraceId::from_bytes(context.trace_id),
+            SpanId::from_bytes(parent_id),
+            TraceFlags::new(context.flags),
+            true,
+            state,
+        );
+        span.set_parent(opentelemetry::Context::new().with_remote_span_context(parent));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_parse_traceparent() {
+        let context = parse_traceparent(PARENT, None).unwrap();
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", hex(&context.trace_id));
+        assert_eq!("b7ad6b7169203331", hex(&context.parent_id.unwrap()));
+        assert_eq!(SAMPLED, context.flags);
+
+        // Later versions can add fields.
+        let future = "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra";
+        assert_eq!(0, parse_traceparent(future, None).unwrap().flags);
+
+        for invalid in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+        ] {
+            assert!(parse_traceparent(invalid, None).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_inject() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(PARENT));
+        headers.insert(TRACESTATE, HeaderValue::from_static("a=b"));
+        let context = TraceContext::from_headers(&headers);
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", context.trace_id());
+
+        // Each request is a new span of the trace.
+        let mut first = HeaderMap::new();
+        context.clone().inject(&mut first);
+        let mut second = HeaderMap::new();
+        context.inject(&mut second);
+        let first = first[TRACEPARENT].to_str().unwrap().to_string();
+        let second = second[TRACEPARENT].to_str().unwrap().to_string();
+        assert!(first.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(first.ends_with("-01"));
+        assert_ne!(PARENT, first);
+        assert_ne!(first, second);
+        let mut headers = HeaderMap::new();
+        context.inject(&mut headers);
+        assert_eq!("a=b", headers[TRACESTATE]);
+
+        // A traceparent set by the guest is kept.
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(PARENT));
+        context.inject(&mut headers);
+        assert_eq!(PARENT, headers[TRACEPARENT]);
+
+        // Clones share the context.
+        context
+            .clone()
+            .set(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+                None,
+            )
+            .unwrap();
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", context.trace_id());
+        let mut headers = HeaderMap::new();
+        context.inject(&mut headers);
+        assert!(headers[TRACEPARENT].to_str().unwrap().ends_with("-00"));
+        assert!(!headers.contains_key(TRACESTATE));
+        assert!(context.set("invalid", None).is_err());
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", context.trace_id());
+
+        // Requests without trace context headers start a new trace.
+        assert_ne!(
+            TraceContext::new().trace_id(),
+            TraceContext::from_headers(&HeaderMap::new()).trace_id()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "opentelemetry")]
+    fn test_link() {
+        use opentelemetry::trace::{TraceContextExt, TracerProvider};
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACEPARENT, HeaderValue::from_static(PARENT));
+            let context = TraceContext::from_headers(&headers);
+
+            // The span of a request is a child of the parent of the context,
+            // and its ID is sent to the server.
+            let span = tracing::info_span!("send");
+            context.link(&span);
+            let span_context = span.context().span().span_context().clone();
+            assert_eq!(
+                "0af7651916cd43dd8448eb211c80319c",
+                span_context.trace_id().to_string()
+            );
+            let mut headers = HeaderMap::new();
+            span.in_scope(|| context.inject(&mut headers));
+            assert_eq!(
+                format!(
+                    "00-0af7651916cd43dd8448eb211c80319c-{}-01",
+                    span_context.span_id()
+                ),
+                headers[TRACEPARENT]
+            );
+
+            // A new context continues the trace of the current span.
+            let run = tracing::info_span!("run");
+            let context = run.in_scope(TraceContext::new);
+            assert_eq!(
+                run.context().span().span_context().trace_id().to_string(),
+                context.trace_id()
+            );
+        });
+    }
+}
@@ -0,0 +1,163 @@
+//! WebSocket connections opened by guests.
+//!
+//! Connections are opened by the `Client`, on the same kind of connection
+//! as requests, and then owned by the state of the instance. The guest
+//! exchanges text, binary and close messages with the server, while pings
+//! are answered by the host.
+
+use crate::{client::Io, HttpError};
+use futures::{Future, SinkExt, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
+
+/// How long closing a connection waits for the server to acknowledge it.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The type of a message, as exchanged with the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MessageType {
+    Text = 0,
+    Binary = 1,
+    /// The server closed the connection. The payload of the message is
+    /// the big-endian close code followed by the reason, if any.
+    Close = 2,
+}
+
+impl TryFrom<u32> for MessageType {
+    type Error = HttpError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageType::Text),
+            1 => Ok(MessageType::Binary),
+            2 => Ok(MessageType::Close),
+            _ => Err(HttpError::InvalidEncoding),
+        }
+    }
+}
+
+/// A WebSocket connection of a guest.
+pub(crate) struct WebSocket {
+    /// The connection, shared with the tasks using it on the runtime.
+    stream: Arc<Mutex<WebSocketStream<Box<dyn Io>>>>,
+    /// A message received from the server, but too large for the buffer
+    /// the guest passed, kept until the guest retrieves it.
+    pub(crate) received: Option<(MessageType, Vec<u8>)>,
+}
+
+impl WebSocket {
+    pub(crate) fn new(stream: WebSocketStream<Box<dyn Io>>) -> Self {
+        WebSocket {
+            stream: Arc::new(Mutex::new(stream)),
+            received: None,
+        }
+    }
+
+    /// Send a text or binary message.
+    pub(crate) fn send(
+        &self,
+        message_type: MessageType,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send + 'static {
+        let stream = self.stream.clone();
+        async move {
+            let message = match message_type {
+                MessageType::Text => Message::Text(
+                    String::from_utf8(data).map_err(|e| HttpError::Utf8Error(e.utf8_error()))?,
+                ),
+                MessageType::Binary => Message::Binary(data),
+                // Connections are closed with `close`.
+                MessageType::Close => return Err(HttpError::InvalidEncoding),
+            };
+            stream.lock().await.send(message).await?;
+            Ok(())
+        }
+    }
+
+    /// Receive the next text, binary or close message.
+    pub(crate) fn receive(
+        &self,
+    ) -> impl Future<Output = Result<(MessageType, Vec<u8>), HttpError>> + Send + 'static {
+        let stream = self.stream.clone();
+        async move {
+            let mut stream = stream.lock().await;
+            while let Some(message) = stream.next().await {
+                match message? {
+                    Message::Text(text) => return Ok((MessageType::Text, text.into_bytes())),
+                    Message::Binary(data) => return Ok((MessageType::Binary, data)),
+                    Message::Close(frame) => return Ok((MessageType::Close, close_payload(frame))),
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                }
+            }
+            Err(HttpError::RequestError("the connection is closed".into()))
+        }
+    }
+
+    /// Close the connection with `code` and `reason`, and wait for the
+    /// server to acknowledge it, for at most `CLOSE_TIMEOUT`.
+    pub(crate) fn close(
+        self,
+        code: u16,
+        reason: String,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send + 'static {
+        let stream = self.stream;
+        async move {
+            let mut stream = stream.lock().await;
+            let frame = CloseFrame {
+                code: CloseCode::from(code),
+                reason: reason.into(),
+            };
+            match stream.close(Some(frame)).await {
+                // The server may have closed the connection first.
+                Ok(()) | Err(tungstenite::Error::ConnectionClosed) => {}
+                Err(tungstenite::Error::AlreadyClosed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            let drain = async { while let Some(Ok(_)) = stream.next().await {} };
+            if tokio::time::timeout(CLOSE_TIMEOUT, drain).await.is_err() {
+                tracing::debug!("server did not acknowledge the close of the connection");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Encode a close frame as the payload of a close message.
+fn close_payload(frame: Option<CloseFrame>) -> Vec<u8> {
+    match frame {
+        Some(frame) => {
+            let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+            payload.extend_from_slice(frame.reason.as_bytes());
+            payload
+        }
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_payload() {
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        assert_eq!(b"\x03\xe8bye".to_vec(), close_payload(Some(frame)));
+        assert!(close_payload(None).is_empty());
+        assert_eq!(
+            Ok(MessageType::Close),
+            MessageType::try_from(2).map_err(|_| ())
+        );
+        assert!(MessageType::try_from(3).is_err());
+    }
+}
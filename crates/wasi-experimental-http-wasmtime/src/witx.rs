@@ -0,0 +1,619 @@
+//! Host functions of the `wasi_experimental_http` and
+//! `wasi_experimental_http_v2` modules, generated by `wiggle` from their
+//! witx definitions. The generated glue reads the arguments from the memory
+//! of the guest and writes its results back, while `HttpInstance` implements
+//! each function with `HostCalls`.
+
+use crate::{
+    websocket, HeaderEncoding, HostCalls, HttpError, HttpInstance, InboundPart, ABI_VERSION,
+};
+use wasmtime::{ExternType, Linker, Module, Val, ValType};
+use wiggle::{GuestError, GuestErrorType, GuestPtr, Trap};
+
+/// The functions of each module defined by the host. Those of
+/// `wasi_experimental_http_v2` are frozen, so that guests importing them can
+/// be instantiated by every host defining the module: new functions go to a
+/// new module, along with a new `ABI_VERSION`.
+pub(crate) const MODULES: [(&str, &[&str]); 2] = [
+    (
+        "wasi_experimental_http",
+        &["req", "close", "header_get", "headers_get_all", "body_read"],
+    ),
+    (
+        "wasi_experimental_http_v2",
+        &[
+            "req",
+            "req_binary",
+            "close",
+            "header_get",
+            "header_values_get",
+            "headers_get_all",
+            "headers_get_all_binary",
+            "body_read",
+            "last_error",
+            "version",
+            "capabilities",
+            "response_metadata_get",
+            "trailers_get_all",
+            "req_start",
+            "req_body_write",
+            "req_trailers_set",
+            "req_finish",
+            "cookies_get_all",
+            "cookies_clear",
+            "req_with_options",
+            "req_async",
+            "req_poll",
+            "req_wait_any",
+            "req_result",
+            "req_batch",
+            "ws_open",
+            "ws_send",
+            "ws_recv",
+            "ws_close",
+            "inbound_method_get",
+            "inbound_url_get",
+            "inbound_headers_get_all",
+            "inbound_body_read",
+            "inbound_response_set",
+            "trace_context_set",
+        ],
+    ),
+];
+
+/// The original module, whose functions exchange headers as text.
+#[allow(clippy::too_many_arguments)]
+pub(crate) mod v1 {
+    use crate::HttpError;
+
+    wiggle::from_witx!({
+        witx: ["$OUT_DIR/wasi_experimental_http.witx"],
+        errors: { http_error => HttpError },
+    });
+}
+
+/// The versioned module.
+#[allow(clippy::too_many_arguments)]
+pub(crate) mod v2 {
+    use crate::HttpError;
+
+    wiggle::from_witx!({
+        witx: ["$OUT_DIR/wasi_experimental_http_v2.witx"],
+        errors: { http_error => HttpError },
+    });
+}
+
+use v2::types::{
+    AbiVersion, BatchRequests, BatchResultsBuf, Capabilities, CookiesBuf, ErrorMessageBuf,
+    HeaderValueBuf, InboundBuf, IncomingBody, MessageType, OutgoingBody, OutgoingHeaders,
+    PendingHandle, PendingHandles, ReadyHandlesBuf, RequestHandle, RequestOptions, ResponseHandle,
+    StatusCode, WebsocketHandle, WrittenBytes, WrittenHandles,
+};
+
+impl GuestErrorType for v1::types::HttpError {
+    fn success() -> Self {
+        Self::Success
+    }
+}
+
+impl GuestErrorType for v2::types::HttpError {
+    fn success() -> Self {
+        Self::Success
+    }
+}
+
+/// Define the functions of both modules in `linker`.
+pub(crate) fn add_to_linker<T>(
+    linker: &mut Linker<T>,
+    get_cx: impl Fn(&mut T) -> &mut HttpInstance + Send + Sync + Copy + 'static,
+) -> Result<(), anyhow::Error> {
+    v1::wasi_experimental_http::add_to_linker(linker, get_cx)?;
+    v2::wasi_experimental_http_v2::add_to_linker(linker, get_cx)?;
+    Ok(())
+}
+
+/// Define the functions `module` imports from modules named like
+/// `wasi_experimental_http*` which are not in `MODULES`, such as those of a
+/// module newer than the host, as returning default values. The error
+/// code they return is then 0, meaning success, but they write no results,
+/// so that guests probing the `version` of such a module read what they
+/// initialized it with.
+pub(crate) fn define_unknown_imports<T>(
+    linker: &mut Linker<T>,
+    module: &Module,
+) -> Result<(), anyhow::Error> {
+    let mut defined = Vec::new();
+    for import in module.imports() {
+        let (name, ty) = match (import.name(), import.ty()) {
+            (Some(name), ExternType::Func(ty)) => (name, ty),
+            _ => continue,
+        };
+        let known = MODULES
+            .iter()
+            .any(|(m, funcs)| *m == import.module() && funcs.contains(&name));
+        if known
+            || !import.module().starts_with("wasi_experimental_http")
+            || defined.contains(&(import.module(), name))
+        {
+            continue;
+        }
+        tracing::debug!(
+            module = import.module(),
+            name,
+            "defining unknown import as returning default values"
+        );
+        let results = ty.results().map(default_value).collect::<Vec<_>>();
+        linker.func_new(import.module(), name, ty, move |_, _, out| {
+            out.clone_from_slice(&results);
+            Ok(())
+        })?;
+        defined.push((import.module(), name));
+    }
+    Ok(())
+}
+
+/// The zero or null value of a type.
+fn default_value(ty: ValType) -> Val {
+    match ty {
+        ValType::I32 => Val::I32(0),
+        ValType::I64 => Val::I64(0),
+        ValType::F32 => Val::F32(0),
+        ValType::F64 => Val::F64(0),
+        ValType::V128 => Val::V128(0),
+        ValType::ExternRef => Val::ExternRef(None),
+        ValType::FuncRef => Val::FuncRef(None),
+    }
+}
+
+impl From<GuestError> for HttpError {
+    fn from(e: GuestError) -> Self {
+        match e {
+            GuestError::InvalidUtf8(e) => HttpError::Utf8Error(e),
+            // Enums and flags the guest passes in records.
+            GuestError::InvalidEnumValue(_) | GuestError::InvalidFlagValue(_) => {
+                HttpError::InvalidEncoding
+            }
+            GuestError::InFunc { err, .. } => HttpError::from(*err),
+            e => HttpError::MemoryAccessError(e),
+        }
+    }
+}
+
+impl From<&HttpError> for v2::types::HttpError {
+    fn from(e: &HttpError) -> Self {
+        use v2::types::HttpError as E;
+        match e {
+            HttpError::InvalidHandle(_) => E::InvalidHandle,
+            HttpError::MemoryAccessError(_) => E::MemoryAccessError,
+            HttpError::BufferTooSmall => E::BufferTooSmall,
+            HttpError::HeaderNotFound => E::HeaderNotFound,
+            HttpError::Utf8Error(_) => E::Utf8Error,
+            HttpError::DestinationNotAllowed(_) => E::DestinationNotAllowed,
+            HttpError::InvalidMethod => E::InvalidMethod,
+            HttpError::InvalidEncoding => E::InvalidEncoding,
+            HttpError::InvalidUrl => E::InvalidUrl,
+            HttpError::RequestError(_) => E::RequestError,
+            HttpError::RuntimeError => E::RuntimeError,
+            HttpError::TooManySessions => E::TooManySessions,
+            HttpError::CertificatePinMismatch(_) => E::CertificatePinMismatch,
+            HttpError::DnsError(_) => E::DnsError,
+            HttpError::ConnectionRefused(_) => E::ConnectionRefused,
+            HttpError::TlsError(_) => E::TlsError,
+            HttpError::Timeout(_) => E::Timeout,
+            HttpError::DecodeError(_) => E::DecodeError,
+            HttpError::TooManyRedirects(_) => E::TooManyRedirects,
+            HttpError::BodyNotRead => E::BodyNotRead,
+            HttpError::ResponseTooLarge(_) => E::ResponseTooLarge,
+            HttpError::NoInboundRequest => E::NoInboundRequest,
+        }
+    }
+}
+
+impl From<&HttpError> for v1::types::HttpError {
+    /// Errors added after the original module are request errors for its
+    /// guests.
+    fn from(e: &HttpError) -> Self {
+        let code = u32::from(v2::types::HttpError::from(e));
+        Self::try_from(code).unwrap_or(Self::RequestError)
+    }
+}
+
+impl HttpInstance {
+    /// Record the message of `e` as the last error of the instance, unless
+    /// it was returned by `last_error` itself, so that the guest can retry
+    /// with a larger buffer.
+    fn record_error(&mut self, e: &HttpError) {
+        if std::mem::take(&mut self.last_error_failed) {
+            return;
+        }
+        let message = e.message();
+        tracing::debug!(%message, "host call failed");
+        if let Ok(mut st) = self.state.write() {
+            st.last_error = Some(message);
+        }
+    }
+}
+
+impl v1::types::UserErrorConversion for HttpInstance {
+    fn http_error_from_http_error(&mut self, e: HttpError) -> Result<v1::types::HttpError, Trap> {
+        self.record_error(&e);
+        Ok((&e).into())
+    }
+}
+
+impl v2::types::UserErrorConversion for HttpInstance {
+    fn http_error_from_http_error(&mut self, e: HttpError) -> Result<v2::types::HttpError, Trap> {
+        self.record_error(&e);
+        Ok((&e).into())
+    }
+}
+
+impl v1::wasi_experimental_http::WasiExperimentalHttp for HttpInstance {
+    fn req<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &GuestPtr<'a, str>,
+        body: &v1::types::OutgoingBody<'a>,
+    ) -> Result<(v1::types::StatusCode, v1::types::ResponseHandle), HttpError> {
+        let (status, handle) = v2::wasi_experimental_http_v2::WasiExperimentalHttpV2::req(
+            self, url, method, headers, body,
+        )?;
+        Ok((status, u32::from(handle).into()))
+    }
+
+    fn close(&mut self, handle: v1::types::ResponseHandle) -> Result<(), HttpError> {
+        HostCalls::close(self.state.clone(), handle.into())
+    }
+
+    fn header_get<'a>(
+        &mut self,
+        handle: v1::types::ResponseHandle,
+        name: &GuestPtr<'a, str>,
+        buf: &v1::types::HeaderValueBuf<'a>,
+    ) -> Result<v1::types::WrittenBytes, HttpError> {
+        HostCalls::header_get(self.state.clone(), handle.into(), name, buf)
+    }
+
+    fn headers_get_all<'a>(
+        &mut self,
+        handle: v1::types::ResponseHandle,
+        buf: &v1::types::HeaderValueBuf<'a>,
+    ) -> Result<v1::types::WrittenBytes, HttpError> {
+        HostCalls::headers_get_all(self.state.clone(), HeaderEncoding::Text, handle.into(), buf)
+    }
+
+    fn body_read<'a>(
+        &mut self,
+        handle: v1::types::ResponseHandle,
+        buf: &v1::types::IncomingBody<'a>,
+    ) -> Result<v1::types::WrittenBytes, HttpError> {
+        HostCalls::body_read(self.state.clone(), handle.into(), buf)
+    }
+}
+
+impl v2::wasi_experimental_http_v2::WasiExperimentalHttpV2 for HttpInstance {
+    fn req<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &GuestPtr<'a, str>,
+        body: &OutgoingBody<'a>,
+    ) -> Result<(StatusCode, ResponseHandle), HttpError> {
+        let (status, handle) = HostCalls::req(
+            self,
+            HeaderEncoding::Text,
+            Default::default(),
+            url,
+            method,
+            &headers.as_bytes(),
+            body,
+        )?;
+        Ok((status, handle.into()))
+    }
+
+    fn req_binary<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &OutgoingHeaders<'a>,
+        body: &OutgoingBody<'a>,
+    ) -> Result<(StatusCode, ResponseHandle), HttpError> {
+        let (status, handle) = HostCalls::req(
+            self,
+            HeaderEncoding::Binary,
+            Default::default(),
+            url,
+            method,
+            headers,
+            body,
+        )?;
+        Ok((status, handle.into()))
+    }
+
+    fn close(&mut self, handle: ResponseHandle) -> Result<(), HttpError> {
+        HostCalls::close(self.state.clone(), handle.into())
+    }
+
+    fn header_get<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        name: &GuestPtr<'a, str>,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::header_get(self.state.clone(), handle.into(), name, buf)
+    }
+
+    fn header_values_get<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        name: &GuestPtr<'a, str>,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::header_values_get(self.state.clone(), handle.into(), name, buf)
+    }
+
+    fn headers_get_all<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::headers_get_all(self.state.clone(), HeaderEncoding::Text, handle.into(), buf)
+    }
+
+    fn headers_get_all_binary<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::headers_get_all(
+            self.state.clone(),
+            HeaderEncoding::Binary,
+            handle.into(),
+            buf,
+        )
+    }
+
+    fn body_read<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        buf: &IncomingBody<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::body_read(self.state.clone(), handle.into(), buf)
+    }
+
+    fn last_error<'a>(&mut self, buf: &ErrorMessageBuf<'a>) -> Result<WrittenBytes, HttpError> {
+        let res = HostCalls::last_error(self.state.clone(), buf);
+        self.last_error_failed = res.is_err();
+        res
+    }
+
+    fn version(&mut self) -> Result<AbiVersion, HttpError> {
+        Ok(ABI_VERSION)
+    }
+
+    /// Streaming request bodies, asynchronous requests, trailers and
+    /// WebSockets are all supported.
+    fn capabilities(&mut self) -> Result<Capabilities, HttpError> {
+        Ok(Capabilities::STREAMING
+            | Capabilities::ASYNC
+            | Capabilities::TRAILERS
+            | Capabilities::WEBSOCKET)
+    }
+
+    fn response_metadata_get<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::response_metadata_get(self.state.clone(), handle.into(), buf)
+    }
+
+    fn trailers_get_all<'a>(
+        &mut self,
+        handle: ResponseHandle,
+        buf: &HeaderValueBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::trailers_get_all(self.state.clone(), handle.into(), buf)
+    }
+
+    fn req_start<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &OutgoingHeaders<'a>,
+    ) -> Result<RequestHandle, HttpError> {
+        Ok(HostCalls::req_start(self, url, method, headers)?.into())
+    }
+
+    fn req_body_write<'a>(
+        &mut self,
+        handle: RequestHandle,
+        body: &OutgoingBody<'a>,
+    ) -> Result<(), HttpError> {
+        HostCalls::req_body_write(self.state.clone(), handle.into(), body)
+    }
+
+    fn req_trailers_set<'a>(
+        &mut self,
+        handle: RequestHandle,
+        trailers: &OutgoingHeaders<'a>,
+    ) -> Result<(), HttpError> {
+        HostCalls::req_trailers_set(self.state.clone(), handle.into(), trailers)
+    }
+
+    fn req_finish(
+        &mut self,
+        handle: RequestHandle,
+    ) -> Result<(StatusCode, ResponseHandle), HttpError> {
+        let (status, handle) = HostCalls::req_finish(self.state.clone(), handle.into())?;
+        Ok((status, handle.into()))
+    }
+
+    fn cookies_get_all<'a>(&mut self, buf: &CookiesBuf<'a>) -> Result<WrittenBytes, HttpError> {
+        HostCalls::cookies_get_all(&self.ctx, buf)
+    }
+
+    fn cookies_clear(&mut self) -> Result<(), HttpError> {
+        HostCalls::cookies_clear(&self.ctx)
+    }
+
+    fn req_with_options<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &OutgoingHeaders<'a>,
+        body: &OutgoingBody<'a>,
+        options: &GuestPtr<'a, RequestOptions>,
+    ) -> Result<(StatusCode, ResponseHandle), HttpError> {
+        let options = crate::options_from_guest(options)?;
+        tracing::trace!(?options, "request options");
+        let (status, handle) = HostCalls::req(
+            self,
+            HeaderEncoding::Binary,
+            options,
+            url,
+            method,
+            headers,
+            body,
+        )?;
+        Ok((status, handle.into()))
+    }
+
+    fn req_async<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        method: &GuestPtr<'a, str>,
+        headers: &OutgoingHeaders<'a>,
+        body: &OutgoingBody<'a>,
+        options: &GuestPtr<'a, RequestOptions>,
+    ) -> Result<PendingHandle, HttpError> {
+        Ok(HostCalls::req_async(self, url, method, headers, body, options)?.into())
+    }
+
+    fn req_poll<'a>(
+        &mut self,
+        handles: &PendingHandles<'a>,
+        ready: &ReadyHandlesBuf<'a>,
+    ) -> Result<WrittenHandles, HttpError> {
+        HostCalls::req_poll(self.state.clone(), handles, ready)
+    }
+
+    fn req_wait_any<'a>(
+        &mut self,
+        handles: &PendingHandles<'a>,
+        timeout_ms: u32,
+    ) -> Result<PendingHandle, HttpError> {
+        Ok(HostCalls::req_wait_any(self.state.clone(), &self.runtime, handles, timeout_ms)?.into())
+    }
+
+    fn req_result(
+        &mut self,
+        handle: PendingHandle,
+    ) -> Result<(StatusCode, ResponseHandle), HttpError> {
+        let (status, handle) = HostCalls::req_result(self.state.clone(), handle.into())?;
+        Ok((status, handle.into()))
+    }
+
+    fn req_batch<'a>(
+        &mut self,
+        requests: &BatchRequests<'a>,
+        options: &GuestPtr<'a, RequestOptions>,
+        results: &BatchResultsBuf<'a>,
+    ) -> Result<(), HttpError> {
+        HostCalls::req_batch(self, requests, options, results)
+    }
+
+    fn ws_open<'a>(
+        &mut self,
+        url: &GuestPtr<'a, str>,
+        headers: &OutgoingHeaders<'a>,
+    ) -> Result<WebsocketHandle, HttpError> {
+        Ok(HostCalls::ws_open(self, url, headers)?.into())
+    }
+
+    fn ws_send<'a>(
+        &mut self,
+        handle: WebsocketHandle,
+        message_type: MessageType,
+        data: &OutgoingBody<'a>,
+    ) -> Result<(), HttpError> {
+        let message_type = websocket::MessageType::try_from(u32::from(message_type))?;
+        HostCalls::ws_send(
+            self.state.clone(),
+            &self.runtime,
+            handle.into(),
+            message_type,
+            data,
+        )
+    }
+
+    fn ws_recv<'a>(
+        &mut self,
+        handle: WebsocketHandle,
+        timeout_ms: u32,
+        buf: &IncomingBody<'a>,
+    ) -> Result<(MessageType, WrittenBytes), HttpError> {
+        let (message_type, written) = HostCalls::ws_recv(
+            self.state.clone(),
+            &self.runtime,
+            handle.into(),
+            timeout_ms,
+            buf,
+        )?;
+        let message_type =
+            MessageType::try_from(message_type as u32).map_err(|_| HttpError::RuntimeError)?;
+        Ok((message_type, written))
+    }
+
+    fn ws_close<'a>(
+        &mut self,
+        handle: WebsocketHandle,
+        code: u16,
+        reason: &GuestPtr<'a, str>,
+    ) -> Result<(), HttpError> {
+        HostCalls::ws_close(
+            self.state.clone(),
+            &self.runtime,
+            handle.into(),
+            code,
+            reason,
+        )
+    }
+
+    fn inbound_method_get<'a>(&mut self, buf: &InboundBuf<'a>) -> Result<WrittenBytes, HttpError> {
+        HostCalls::inbound_get(&self.ctx, InboundPart::Method, buf)
+    }
+
+    fn inbound_url_get<'a>(&mut self, buf: &InboundBuf<'a>) -> Result<WrittenBytes, HttpError> {
+        HostCalls::inbound_get(&self.ctx, InboundPart::Url, buf)
+    }
+
+    fn inbound_headers_get_all<'a>(
+        &mut self,
+        buf: &InboundBuf<'a>,
+    ) -> Result<WrittenBytes, HttpError> {
+        HostCalls::inbound_get(&self.ctx, InboundPart::Headers, buf)
+    }
+
+    fn inbound_body_read<'a>(&mut self, buf: &IncomingBody<'a>) -> Result<WrittenBytes, HttpError> {
+        HostCalls::inbound_body_read(&self.ctx, buf)
+    }
+
+    fn inbound_response_set<'a>(
+        &mut self,
+        status_code: StatusCode,
+        headers: &OutgoingHeaders<'a>,
+        body: &OutgoingBody<'a>,
+    ) -> Result<(), HttpError> {
+        HostCalls::inbound_response_set(&self.ctx, status_code, headers, body)
+    }
+
+    fn trace_context_set<'a>(
+        &mut self,
+        traceparent: &GuestPtr<'a, str>,
+        tracestate: &GuestPtr<'a, str>,
+    ) -> Result<(), HttpError> {
+        HostCalls::trace_context_set(&self.ctx, traceparent, tracestate)
+    }
+}
@@ -0,0 +1,129 @@
+//! A single-threaded executor for futures awaiting HTTP responses.
+//!
+//! Guests have a single thread, and the only events they can wait for are
+//! the responses of their pending requests. `block_on` polls a future until
+//! it has to wait, then blocks on `req_wait_any` with the requests the
+//! future is waiting for, and wakes it once one of them has a response.
+
+use crate::{raw_v2, HttpError, PendingRequest, Response};
+use anyhow::Error;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+thread_local! {
+    /// The wakers of the futures waiting for each pending request.
+    static WAITING: RefCell<HashMap<raw_v2::PendingHandle, Waker>> = RefCell::new(HashMap::new());
+}
+
+/// A future resolving to the response of a pending request, created by
+/// `send`. It must be driven by `block_on`.
+pub struct ResponseFuture {
+    pending: Option<PendingRequest>,
+}
+
+impl From<PendingRequest> for ResponseFuture {
+    fn from(pending: PendingRequest) -> Self {
+        ResponseFuture {
+            pending: Some(pending),
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Response, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let pending = self
+            .pending
+            .as_ref()
+            .expect("response future polled after completion");
+        match pending.is_ready() {
+            Ok(false) => {
+                let handle = pending.handle;
+                WAITING.with(|w| w.borrow_mut().insert(handle, cx.waker().clone()));
+                Poll::Pending
+            }
+            // Errors of the request itself are returned by `wait`.
+            Ok(true) | Err(_) => {
+                let pending = self.pending.take().unwrap();
+                WAITING.with(|w| w.borrow_mut().remove(&pending.handle));
+                Poll::Ready(pending.wait())
+            }
+        }
+    }
+}
+
+/// Stop waiting for the request if the future is dropped before its
+/// response is ready. Dropping the pending request aborts it.
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if let Some(pending) = &self.pending {
+            WAITING.with(|w| w.borrow_mut().remove(&pending.handle));
+        }
+    }
+}
+
+/// Wakes the task of `block_on` by setting a flag.
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Run a future to completion on the current thread, blocking on the host
+/// while it waits for responses. Futures can send several requests
+/// concurrently, for example with `futures::join!`, but can only wait for
+/// the responses of requests sent with `send`.
+///
+/// # Panics
+///
+/// If the future is pending without waiting for any request, which would
+/// otherwise block forever.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        flag.0.store(false, Ordering::SeqCst);
+        if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+            return res;
+        }
+        if !flag.0.load(Ordering::SeqCst) {
+            wait();
+        }
+    }
+}
+
+/// Block until one of the requests futures are waiting for has a response,
+/// and wake the futures waiting for it.
+fn wait() {
+    let handles = WAITING.with(|w| w.borrow().keys().copied().collect::<Vec<_>>());
+    if handles.is_empty() {
+        panic!("future is pending, but is not waiting for any request");
+    }
+    let ready = match raw_v2::req_wait_any(handles.as_ptr(), handles.len(), 0) {
+        Ok(handle) => vec![handle],
+        // Wake every future, so that they get the error from their request.
+        Err(e) => {
+            tracing::debug!(error = %HttpError::from(e), "cannot wait for requests");
+            handles
+        }
+    };
+    for handle in ready {
+        if let Some(waker) = WAITING.with(|w| w.borrow_mut().remove(&handle)) {
+            waker.wake();
+        }
+    }
+}
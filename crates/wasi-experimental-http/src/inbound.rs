@@ -0,0 +1,103 @@
+//! Inbound requests.
+//!
+//! Hosts that receive HTTP requests can dispatch each of them to a new
+//! instance of the guest, by calling the function it exports as its
+//! handler. `handle` reads the request from the host, calls the handler
+//! with it, and sets its response, and the `handler` attribute generates
+//! the exported function.
+
+use crate::{
+    abi_version, bytes_to_header_map, ensure_supported, header_map_to_bytes, raw_v2, HttpError,
+};
+use anyhow::Error;
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+
+/// Get the inbound request the instance handles. Its URL is absolute.
+pub fn inbound_request() -> Result<Request<Bytes>, Error> {
+    ensure_supported(abi_version() >= 2, "inbound requests")?;
+    let method = String::from_utf8(read_part(raw_v2::inbound_method_get)?)?;
+    let url = String::from_utf8(read_part(raw_v2::inbound_url_get)?)?;
+    let headers = bytes_to_header_map(&read_part(raw_v2::inbound_headers_get_all)?)?;
+
+    let mut body = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read =
+            raw_v2::inbound_body_read(chunk.as_mut_ptr(), chunk.len()).map_err(HttpError::from)?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    let mut req = Request::builder()
+        .method(method.as_str())
+        .uri(url)
+        .body(Bytes::from(body))?;
+    *req.headers_mut() = headers;
+    Ok(req)
+}
+
+/// Set the response to the inbound request, replacing the one set before,
+/// if any. The host sends the response once the handler returns.
+pub fn inbound_response_set(res: &Response<Bytes>) -> Result<(), Error> {
+    ensure_supported(abi_version() >= 2, "inbound requests")?;
+    let headers = header_map_to_bytes(res.headers());
+    let body = res.body();
+    raw_v2::inbound_response_set(
+        res.status().as_u16(),
+        headers.as_ptr(),
+        headers.len(),
+        body.as_ptr(),
+        body.len(),
+    )
+    .map_err(HttpError::from)?;
+    Ok(())
+}
+
+/// Call `handler` with the inbound request, and set its response. If the
+/// request cannot be read or the handler fails, the error is printed, and
+/// the response is a `500 Internal Server Error`, so that the details of
+/// the error are not sent to the client.
+///
+/// # Panics
+///
+/// If the response cannot be set, such as when the instance is not handling
+/// an inbound request.
+pub fn handle<F, E>(handler: F)
+where
+    F: FnOnce(Request<Bytes>) -> Result<Response<Bytes>, E>,
+    E: Into<Error>,
+{
+    let res = inbound_request()
+        .and_then(|req| handler(req).map_err(Into::into))
+        .unwrap_or_else(|e| {
+            eprintln!("cannot handle request: {:#}", e);
+            let mut res = Response::new(Bytes::new());
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        });
+    inbound_response_set(&res).expect("cannot set the response");
+}
+
+/// Read a part of the inbound request, with the same limits as for the
+/// headers of responses.
+fn read_part(
+    get: fn(raw_v2::WasiMutPtr<u8>, usize) -> Result<raw_v2::WrittenBytes, raw_v2::Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut capacity = 4 * 1024;
+    let max_capacity: usize = 64 * 1024;
+
+    loop {
+        let mut buf = vec![0u8; capacity];
+        match get(buf.as_mut_ptr(), buf.len()).map_err(HttpError::from) {
+            Ok(written) => {
+                buf.truncate(written);
+                return Ok(buf);
+            }
+            Err(HttpError::BufferTooSmall) if capacity < max_capacity => capacity *= 2,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -2,15 +2,40 @@ use anyhow::{Context, Error};
 use bytes::Bytes;
 use http::{self, header::HeaderName, HeaderMap, HeaderValue, Request, StatusCode};
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
+    net::SocketAddr,
     str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+// The bindings of the original `wasi_experimental_http` module, which every
+// host defines, and of the versioned `wasi_experimental_http_v2` module,
+// whose functions are only called once `abi_version` found it.
 #[allow(dead_code)]
 #[allow(clippy::mut_from_ref)]
 #[allow(clippy::too_many_arguments)]
 pub(crate) mod raw;
 
+#[allow(dead_code)]
+#[allow(clippy::mut_from_ref)]
+#[allow(clippy::too_many_arguments)]
+#[path = "raw_v2.rs"]
+pub(crate) mod raw_v2;
+
+mod executor;
+pub use executor::{block_on, ResponseFuture};
+
+mod websocket;
+pub use websocket::{CloseFrame, Message, WebSocket};
+
+pub mod sse;
+
+mod inbound;
+pub use inbound::{handle, inbound_request, inbound_response_set};
+pub use wasi_experimental_http_macros::handler;
+
 /// HTTP errors
 #[derive(Debug, thiserror::Error)]
 pub enum HttpError {
@@ -26,51 +51,333 @@ pub enum HttpError {
     HeaderNotFound,
     #[error("UTF-8 error")]
     Utf8Error,
-    #[error("Destination not allowed")]
-    DestinationNotAllowed,
+    #[error("Destination not allowed{}", detail(.0))]
+    DestinationNotAllowed(String),
     #[error("Invalid method")]
     InvalidMethod,
     #[error("Invalid encoding")]
     InvalidEncoding,
     #[error("Invalid URL")]
     InvalidUrl,
-    #[error("HTTP error")]
-    RequestError,
+    #[error("HTTP error{}", detail(.0))]
+    RequestError(String),
     #[error("Runtime error")]
     RuntimeError,
     #[error("Too many sessions")]
     TooManySessions,
+    #[error("Certificate pin mismatch{}", detail(.0))]
+    CertificatePinMismatch(String),
+    #[error("DNS error{}", detail(.0))]
+    DnsError(String),
+    #[error("Connection refused{}", detail(.0))]
+    ConnectionRefused(String),
+    #[error("TLS error{}", detail(.0))]
+    TlsError(String),
+    #[error("Timeout{}", detail(.0))]
+    Timeout(String),
+    #[error("Decode error{}", detail(.0))]
+    DecodeError(String),
+    #[error("Too many redirects{}", detail(.0))]
+    TooManyRedirects(String),
+    #[error("Response body not entirely read")]
+    BodyNotRead,
+    #[error("Response too large{}", detail(.0))]
+    ResponseTooLarge(String),
+    #[error("No inbound request")]
+    NoInboundRequest,
+    #[error("Not supported by the host: {0}")]
+    Unsupported(&'static str),
     #[error("Unknown WASI error")]
     UnknownError,
 }
 
+/// Format the message of an error after its category, unless it is
+/// empty, as with hosts that do not record error messages.
+fn detail(message: &str) -> String {
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", message)
+    }
+}
+
 // TODO(@radu-matei)
 //
 // This error is not really used in the public API.
 impl From<raw::Error> for HttpError {
     fn from(e: raw::Error) -> Self {
         match e {
-            raw::Error::WasiError(errno) => match errno {
-                1 => HttpError::InvalidHandle,
-                2 => HttpError::MemoryNotFound,
-                3 => HttpError::MemoryAccessError,
-                4 => HttpError::BufferTooSmall,
-                5 => HttpError::HeaderNotFound,
-                6 => HttpError::Utf8Error,
-                7 => HttpError::DestinationNotAllowed,
-                8 => HttpError::InvalidMethod,
-                9 => HttpError::InvalidEncoding,
-                10 => HttpError::InvalidUrl,
-                11 => HttpError::RequestError,
-                12 => HttpError::RuntimeError,
-                13 => HttpError::TooManySessions,
-
-                _ => HttpError::UnknownError,
+            raw::Error::WasiError(errno) => HttpError::from_code(errno as _, last_error_message),
+        }
+    }
+}
+
+impl From<raw_v2::Error> for HttpError {
+    fn from(e: raw_v2::Error) -> Self {
+        match e {
+            raw_v2::Error::WasiError(errno) => HttpError::from_code(errno as _, last_error_message),
+        }
+    }
+}
+
+impl HttpError {
+    /// The error for an error code of the host, with the message returned
+    /// by `message` for the errors that have one.
+    fn from_code(code: raw::HttpError, message: impl FnOnce() -> String) -> Self {
+        match code {
+            1 => HttpError::InvalidHandle,
+            2 => HttpError::MemoryNotFound,
+            3 => HttpError::MemoryAccessError,
+            4 => HttpError::BufferTooSmall,
+            5 => HttpError::HeaderNotFound,
+            6 => HttpError::Utf8Error,
+            7 => HttpError::DestinationNotAllowed(message()),
+            8 => HttpError::InvalidMethod,
+            9 => HttpError::InvalidEncoding,
+            10 => HttpError::InvalidUrl,
+            11 => HttpError::RequestError(message()),
+            12 => HttpError::RuntimeError,
+            13 => HttpError::TooManySessions,
+            14 => HttpError::CertificatePinMismatch(message()),
+            15 => HttpError::DnsError(message()),
+            16 => HttpError::ConnectionRefused(message()),
+            17 => HttpError::TlsError(message()),
+            18 => HttpError::Timeout(message()),
+            19 => HttpError::DecodeError(message()),
+            20 => HttpError::TooManyRedirects(message()),
+            21 => HttpError::BodyNotRead,
+            22 => HttpError::ResponseTooLarge(message()),
+            23 => HttpError::NoInboundRequest,
+
+            _ => HttpError::UnknownError,
+        }
+    }
+}
+
+/// Get the message the host recorded for the last error it returned,
+/// which usually has more details than the error code, such as the
+/// reason a request could not be sent.
+/// Returns an empty string if the message cannot be retrieved, such as
+/// when the host does not define the `wasi_experimental_http_v2` module.
+pub fn last_error_message() -> String {
+    if abi_version() < 2 {
+        return String::new();
+    }
+    // Error messages are usually short, but can include long URLs.
+    let mut capacity = 1024;
+    let max_capacity: usize = 64 * 1024;
+
+    loop {
+        let mut buf = vec![0u8; capacity];
+        match raw_v2::last_error(buf.as_mut_ptr(), buf.len()) {
+            Ok(written) => {
+                buf.truncate(written);
+                return String::from_utf8_lossy(&buf).into_owned();
+            }
+            Err(raw_v2::Error::WasiError(e))
+                if e as raw_v2::HttpError == raw_v2::HTTP_ERROR::BUFFER_TOO_SMALL
+                    && capacity < max_capacity =>
+            {
+                capacity *= 2;
+            }
+            Err(_) => return String::new(),
+        }
+    }
+}
+
+/// Optional features supported by the host, as reported by `capabilities`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Request and response bodies can be streamed.
+    pub fn streaming(&self) -> bool {
+        self.0 & raw_v2::CAPABILITIES::STREAMING != 0
+    }
+
+    /// Requests can be sent without blocking the guest.
+    pub fn async_requests(&self) -> bool {
+        self.0 & raw_v2::CAPABILITIES::ASYNC != 0
+    }
+
+    /// Request and response trailers can be sent and received.
+    pub fn trailers(&self) -> bool {
+        self.0 & raw_v2::CAPABILITIES::TRAILERS != 0
+    }
+
+    /// WebSocket connections can be opened.
+    pub fn websocket(&self) -> bool {
+        self.0 & raw_v2::CAPABILITIES::WEBSOCKET != 0
+    }
+}
+
+/// Marks the values of the host that were not probed yet.
+const UNKNOWN: u32 = u32::MAX;
+
+static ABI_VERSION: AtomicU32 = AtomicU32::new(UNKNOWN);
+static CAPABILITIES: AtomicU32 = AtomicU32::new(UNKNOWN);
+
+/// Get the version of the ABI implemented by the host: 1 if it only
+/// defines the original `wasi_experimental_http` module, and otherwise
+/// the version it reports, which is at least 2. The host is only probed
+/// once.
+///
+/// Hosts that do not define the `wasi_experimental_http_v2` module can
+/// only instantiate the guest if they define its functions as returning
+/// default values, as with `Linker::define_unknown_imports_as_default_values`
+/// in recent versions of Wasmtime, or `HttpState::define_unknown_imports`
+/// in `wasi-experimental-http-wasmtime`. The version the probe reads is
+/// then 0, and the crate falls back to the original module.
+pub fn abi_version() -> u32 {
+    let mut version = ABI_VERSION.load(Ordering::Relaxed);
+    if version == UNKNOWN {
+        version = probe_version().max(1);
+        ABI_VERSION.store(version, Ordering::Relaxed);
+    }
+    version
+}
+
+/// Call the `version` function of the `wasi_experimental_http_v2` module,
+/// which leaves the result untouched if it was defined as returning default
+/// values, and get 0 if it did not report a version.
+fn probe_version() -> u32 {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        #[link_name = "version"]
+        fn v2_version(result_ptr: raw_v2::WasiMutPtr<raw_v2::AbiVersion>) -> raw_v2::HttpError;
+    }
+    let mut version = 0;
+    match unsafe { v2_version(&mut version) } {
+        raw_v2::HTTP_ERROR::SUCCESS => version,
+        _ => 0,
+    }
+}
+
+/// Get the optional features supported by the host, so that guests
+/// can check for a feature before using it and fall back otherwise.
+/// Hosts that only define the original `wasi_experimental_http` module
+/// support none of them.
+pub fn capabilities() -> Capabilities {
+    let mut caps = CAPABILITIES.load(Ordering::Relaxed);
+    if caps == UNKNOWN {
+        caps = match abi_version() {
+            1 => 0,
+            _ => raw_v2::capabilities().unwrap_or_default(),
+        };
+        CAPABILITIES.store(caps, Ordering::Relaxed);
+    }
+    Capabilities(caps)
+}
+
+/// Fail with `HttpError::Unsupported` if the host does not support
+/// `feature`, instead of calling a function it does not define.
+fn ensure_supported(supported: bool, feature: &'static str) -> Result<(), HttpError> {
+    if supported {
+        Ok(())
+    } else {
+        Err(HttpError::Unsupported(feature))
+    }
+}
+
+/// A cookie stored by the host, when it is configured with a cookie jar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Domain the cookie is sent to.
+    pub domain: String,
+    /// Whether the cookie is also sent to subdomains of `domain`.
+    pub include_subdomains: bool,
+    pub path: String,
+    /// Whether the cookie is only sent over HTTPS.
+    pub secure: bool,
+    pub http_only: bool,
+    /// Expiry time, or `None` for a session cookie.
+    pub expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Decode a cookie from a line of the Netscape cookie file format
+    /// used by the host.
+    fn from_line(line: &str) -> Result<Self, Error> {
+        let (http_only, line) = match line.strip_prefix("#HttpOnly_") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let fields: Vec<_> = line.splitn(7, '\t').collect();
+        anyhow::ensure!(fields.len() == 7, "Invalid cookie: [{}]", line);
+        let flag = |s: &str| s == "TRUE";
+        let expires: u64 = fields[4].parse()?;
+        Ok(Cookie {
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+            domain: fields[0].trim_start_matches('.').to_string(),
+            include_subdomains: flag(fields[1]),
+            path: fields[2].to_string(),
+            secure: flag(fields[3]),
+            http_only,
+            expires: match expires {
+                0 => None,
+                secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            },
+        })
+    }
+}
+
+/// Get the cookies the host stored from previous responses, and sends with
+/// matching requests. The list is empty unless the host is configured
+/// with a cookie jar.
+pub fn cookies() -> Result<Vec<Cookie>, Error> {
+    ensure_supported(abi_version() >= 2, "cookies")?;
+
+    // Cookie jars can hold many cookies, so the buffer is allowed to grow
+    // larger than for headers.
+    let mut capacity = 16 * 1024;
+    let max_capacity: usize = 1024 * 1024;
+
+    loop {
+        let mut buf = vec![0u8; capacity];
+        match raw_v2::cookies_get_all(buf.as_mut_ptr(), buf.len()) {
+            Ok(written) => {
+                buf.truncate(written);
+                return String::from_utf8(buf)?
+                    .lines()
+                    .map(Cookie::from_line)
+                    .collect();
+            }
+            Err(e) => match HttpError::from(e) {
+                HttpError::BufferTooSmall if capacity < max_capacity => {
+                    capacity *= 2;
+                }
+                e => return Err(e.into()),
             },
         }
     }
 }
 
+/// Remove all cookies stored by the host, for instance to log out.
+pub fn cookies_clear() -> Result<(), Error> {
+    ensure_supported(abi_version() >= 2, "cookies")?;
+    Ok(raw_v2::cookies_clear().map_err(HttpError::from)?)
+}
+
+/// Set the trace context whose trace the later requests of the instance
+/// join, from `traceparent` and `tracestate` header values, for instance
+/// those of a request the guest received otherwise than from the host.
+/// This does nothing if the host does not propagate trace contexts.
+pub fn trace_context_set(traceparent: &str, tracestate: Option<&str>) -> Result<(), Error> {
+    ensure_supported(abi_version() >= 2, "trace contexts")?;
+    let tracestate = tracestate.unwrap_or_default();
+    raw_v2::trace_context_set(
+        traceparent.as_ptr(),
+        traceparent.len(),
+        tracestate.as_ptr(),
+        tracestate.len(),
+    )
+    .map_err(HttpError::from)?;
+    Ok(())
+}
+
 /// An HTTP response
 pub struct Response {
     handle: raw::ResponseHandle,
@@ -91,7 +398,8 @@ impl Response {
     /// The function returns the actual number of bytes that were written, and `0`
     /// when the end of the stream has been reached.
     pub fn body_read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        let read = raw::body_read(self.handle, buf.as_mut_ptr(), buf.len())?;
+        let read =
+            raw::body_read(self.handle, buf.as_mut_ptr(), buf.len()).map_err(HttpError::from)?;
         Ok(read)
     }
 
@@ -112,6 +420,7 @@ impl Response {
     }
 
     /// Get the value of the `name` header.
+    /// If the header has multiple values, only the first one is returned.
     /// Returns `HttpError::HeaderNotFound` if no such header was found.
     pub fn header_get(&self, name: String) -> Result<String, Error> {
         let name = name;
@@ -147,10 +456,44 @@ impl Response {
                             capacity *= 2;
                             continue;
                         } else {
-                            return Err(e.into());
+                            return Err(HttpError::BufferTooSmall.into());
                         }
                     }
-                    _ => return Err(e.into()),
+                    e => return Err(e.into()),
+                },
+            };
+        }
+    }
+
+    /// Get all values of the `name` header, in the order they were received,
+    /// such as every `Set-Cookie` header of the response.
+    /// Returns `HttpError::HeaderNotFound` if no such header was found.
+    pub fn header_values_get(&self, name: &str) -> Result<Vec<String>, Error> {
+        ensure_supported(abi_version() >= 2, "header values")?;
+
+        // The same limits as in `header_get` apply to the combined values.
+        let mut capacity = 4 * 1024;
+        let max_capacity: usize = 64 * 1024;
+
+        loop {
+            let mut buf = vec![0u8; capacity];
+            match raw_v2::header_values_get(
+                self.handle,
+                name.as_ptr(),
+                name.len(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            ) {
+                Ok(written) => {
+                    buf.truncate(written);
+                    let values = String::from_utf8(buf)?;
+                    return Ok(values.lines().map(str::to_string).collect());
+                }
+                Err(e) => match HttpError::from(e) {
+                    HttpError::BufferTooSmall if capacity < max_capacity => {
+                        capacity *= 2;
+                    }
+                    e => return Err(e.into()),
                 },
             };
         }
@@ -174,14 +517,154 @@ impl Response {
         let capacity = 64 * 1024;
         let mut buf = vec![0u8; capacity];
 
-        match raw::headers_get_all(self.handle, buf.as_mut_ptr(), buf.len()) {
-            Ok(written) => {
-                buf.truncate(written);
-                let str = String::from_utf8(buf)?;
-                Ok(string_to_header_map(&str)?)
-            }
-            Err(e) => Err(e.into()),
+        // The binary encoding can hold any header value, while the text
+        // one is only used with hosts lacking the versioned module.
+        if abi_version() < 2 {
+            let written = raw::headers_get_all(self.handle, buf.as_mut_ptr(), buf.len())
+                .map_err(HttpError::from)?;
+            buf.truncate(written);
+            return string_to_header_map(&String::from_utf8(buf)?);
         }
+        let written = raw_v2::headers_get_all_binary(self.handle, buf.as_mut_ptr(), buf.len())
+            .map_err(HttpError::from)?;
+        buf.truncate(written);
+        bytes_to_header_map(&buf)
+    }
+
+    /// Get details about how the response was received, such as the
+    /// negotiated HTTP version, the final URL, or timings.
+    pub fn metadata(&self) -> Result<ResponseMetadata, Error> {
+        ensure_supported(abi_version() >= 2, "response metadata")?;
+        // The metadata only has a few short fields, the URL being the
+        // longest one.
+        let mut buf = vec![0u8; 16 * 1024];
+        let written = raw_v2::response_metadata_get(self.handle, buf.as_mut_ptr(), buf.len())
+            .map_err(HttpError::from)?;
+        buf.truncate(written);
+        ResponseMetadata::from_header_map(&bytes_to_header_map(&buf)?)
+    }
+
+    /// Get the trailers of the response, which are sent by the server
+    /// after the body. The entire body must have been read first, or
+    /// `HttpError::BodyNotRead` is returned. Trailers are only received
+    /// over HTTP/2, and require the `trailers` capability.
+    pub fn trailers(&self) -> Result<HeaderMap, Error> {
+        ensure_supported(capabilities().trailers(), "trailers")?;
+        // The same limit as for the header map applies.
+        let mut buf = vec![0u8; 64 * 1024];
+        let written = raw_v2::trailers_get_all(self.handle, buf.as_mut_ptr(), buf.len())
+            .map_err(HttpError::from)?;
+        buf.truncate(written);
+        bytes_to_header_map(&buf)
+    }
+}
+
+/// A request whose body is streamed to the server as it is written,
+/// created by `request_streaming`.
+pub struct OutgoingRequest {
+    handle: raw_v2::RequestHandle,
+}
+
+/// Abort the request if it is dropped before being finished.
+impl Drop for OutgoingRequest {
+    fn drop(&mut self) {
+        raw_v2::close(self.handle).unwrap();
+    }
+}
+
+impl OutgoingRequest {
+    /// Send the next chunk of the request body. If the request failed,
+    /// its error is returned, and the request can no longer be used.
+    pub fn body_write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        raw_v2::req_body_write(self.handle, buf.as_ptr(), buf.len()).map_err(HttpError::from)?;
+        Ok(())
+    }
+
+    /// Set the trailers sent after the body. Trailers are only sent
+    /// over HTTP/2, and require the `trailers` capability.
+    pub fn trailers_set(&mut self, trailers: &HeaderMap) -> Result<(), Error> {
+        ensure_supported(capabilities().trailers(), "trailers")?;
+        let trailers = header_map_to_bytes(trailers);
+        raw_v2::req_trailers_set(self.handle, trailers.as_ptr(), trailers.len())
+            .map_err(HttpError::from)?;
+        Ok(())
+    }
+
+    /// Finish the request body, and wait for the response.
+    pub fn finish(self) -> Result<Response, Error> {
+        let handle = self.handle;
+        // The host closes the request once it is finished, and its
+        // handle may be reused for the response.
+        std::mem::forget(self);
+        let (status_code, handle) = raw_v2::req_finish(handle).map_err(HttpError::from)?;
+        Ok(Response {
+            handle,
+            status_code: StatusCode::from_u16(status_code)?,
+        })
+    }
+}
+
+/// Details about how a response was received, as reported by the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseMetadata {
+    /// HTTP version negotiated with the server.
+    pub version: http::Version,
+    /// URL of the response, after following redirects.
+    pub url: String,
+    /// Address of the server, unless it was reached over a Unix domain socket.
+    pub remote_addr: Option<SocketAddr>,
+    /// Content codings the host removed from the body, such as `gzip`, if it
+    /// decoded it. Otherwise, the body is exactly as sent by the server, with
+    /// its `Content-Encoding` header.
+    pub decoded_encoding: Option<String>,
+    pub timings: Timings,
+}
+
+/// Time spent in each phase of a request. If redirects were followed,
+/// the phases are those of the last request, while `total` covers the
+/// whole exchange, up to reading the entire response body.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Duration,
+    /// Time between sending the request and receiving the response head.
+    pub ttfb: Duration,
+    pub total: Duration,
+}
+
+impl ResponseMetadata {
+    /// Decode the metadata from the header map encoding used by the host.
+    fn from_header_map(hm: &HeaderMap) -> Result<Self, Error> {
+        let get = |name: &str| hm.get(name).map(|v| v.to_str()).transpose();
+        let duration = |name: &str| -> Result<Duration, Error> {
+            Ok(match get(name)? {
+                Some(us) => Duration::from_micros(us.parse()?),
+                None => Duration::ZERO,
+            })
+        };
+
+        let version = match get("http-version")? {
+            Some("HTTP/0.9") => http::Version::HTTP_09,
+            Some("HTTP/1.0") => http::Version::HTTP_10,
+            Some("HTTP/1.1") => http::Version::HTTP_11,
+            Some("HTTP/2.0") => http::Version::HTTP_2,
+            Some("HTTP/3.0") => http::Version::HTTP_3,
+            v => anyhow::bail!("Invalid HTTP version: {:?}", v),
+        };
+        Ok(ResponseMetadata {
+            version,
+            url: get("url")?.unwrap_or_default().to_string(),
+            remote_addr: get("remote-addr")?.map(str::parse).transpose()?,
+            decoded_encoding: get("decoded-content-encoding")?.map(str::to_string),
+            timings: Timings {
+                dns: duration("dns-time-us")?,
+                connect: duration("connect-time-us")?,
+                tls: duration("tls-time-us")?,
+                ttfb: duration("ttfb-us")?,
+                total: duration("total-time-us")?,
+            },
+        })
     }
 }
 
@@ -193,26 +676,398 @@ pub fn request(req: Request<Option<Bytes>>) -> Result<Response, Error> {
     let url = req.uri().to_string();
     tracing::debug!(%url, headers = ?req.headers(), "performing http request using wasmtime function");
 
-    let headers = header_map_to_string(req.headers())?;
     let method = req.method().as_str().to_string();
     let body = match req.body() {
         None => Default::default(),
         Some(body) => body.as_ref(),
     };
-    let (status_code, handle) = raw::req(
+    // Headers are sent with the binary encoding, unless the host lacks the
+    // versioned module, in which case they must be valid as text.
+    let (status_code, handle) = if abi_version() < 2 {
+        let headers = header_map_to_string(req.headers())?;
+        raw::req(
+            url.as_ptr(),
+            url.len(),
+            method.as_ptr(),
+            method.len(),
+            headers.as_ptr(),
+            headers.len(),
+            body.as_ptr(),
+            body.len(),
+        )
+        .map_err(HttpError::from)?
+    } else {
+        let headers = header_map_to_bytes(req.headers());
+        raw_v2::req_binary(
+            url.as_ptr(),
+            url.len(),
+            method.as_ptr(),
+            method.len(),
+            headers.as_ptr(),
+            headers.len(),
+            body.as_ptr(),
+            body.len(),
+        )
+        .map_err(HttpError::from)?
+    };
+    Ok(Response {
+        handle,
+        status_code: StatusCode::from_u16(status_code)?,
+    })
+}
+
+/// Options of a request, applied by the host within its own limits: a
+/// timeout or maximum response size higher than the host's is ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+    timeout: Option<Duration>,
+    follow_redirects: Option<bool>,
+    max_response_size: Option<u32>,
+    http_version: Option<http::Version>,
+    decompress: Option<bool>,
+}
+
+impl RequestOptions {
+    /// Options leaving every choice to the host.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time limit of the request, up to reading the entire response body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether redirects to allowed hosts are followed. If not, redirect
+    /// responses are returned as is.
+    pub fn follow_redirects(mut self, follow: bool) -> Self {
+        self.follow_redirects = Some(follow);
+        self
+    }
+
+    /// Maximum size of the response body in bytes, once decoded. Larger
+    /// responses fail with `HttpError::ResponseTooLarge`.
+    pub fn max_response_size(mut self, max: u32) -> Self {
+        self.max_response_size = Some(max);
+        self
+    }
+
+    /// HTTP version to use: HTTP/1.1 only, or HTTP/2, which is preferred
+    /// over TLS and used with prior knowledge otherwise.
+    pub fn http_version(mut self, version: http::Version) -> Self {
+        self.http_version = Some(version);
+        self
+    }
+
+    /// Whether the host decodes compressed response bodies, or passes them
+    /// as received. `Response::metadata` tells which codings were decoded.
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.decompress = Some(decompress);
+        self
+    }
+
+    /// Send an HTTP request with these options.
+    #[tracing::instrument]
+    pub fn send(&self, req: Request<Option<Bytes>>) -> Result<Response, Error> {
+        ensure_supported(abi_version() >= 2, "request options")?;
+        let url = req.uri().to_string();
+        tracing::debug!(%url, headers = ?req.headers(), "performing http request with options");
+
+        let headers = header_map_to_bytes(req.headers());
+        let method = req.method().as_str().to_string();
+        let body = match req.body() {
+            None => Default::default(),
+            Some(body) => body.as_ref(),
+        };
+        let options = self.to_raw();
+        let (status_code, handle) = raw_v2::req_with_options(
+            url.as_ptr(),
+            url.len(),
+            method.as_ptr(),
+            method.len(),
+            headers.as_ptr(),
+            headers.len(),
+            body.as_ptr(),
+            body.len(),
+            &options,
+        )
+        .map_err(HttpError::from)?;
+        Ok(Response {
+            handle,
+            status_code: StatusCode::from_u16(status_code)?,
+        })
+    }
+
+    /// Send an HTTP request with these options, without waiting for its
+    /// response. This requires the `async_requests` capability.
+    #[tracing::instrument]
+    pub fn send_async(&self, req: Request<Option<Bytes>>) -> Result<PendingRequest, Error> {
+        ensure_supported(capabilities().async_requests(), "async requests")?;
+        let url = req.uri().to_string();
+        tracing::debug!(%url, headers = ?req.headers(), "starting asynchronous http request");
+
+        let headers = header_map_to_bytes(req.headers());
+        let method = req.method().as_str().to_string();
+        let body = match req.body() {
+            None => Default::default(),
+            Some(body) => body.as_ref(),
+        };
+        let options = self.to_raw();
+        let handle = raw_v2::req_async(
+            url.as_ptr(),
+            url.len(),
+            method.as_ptr(),
+            method.len(),
+            headers.as_ptr(),
+            headers.len(),
+            body.as_ptr(),
+            body.len(),
+            &options,
+        )
+        .map_err(HttpError::from)?;
+        Ok(PendingRequest { handle })
+    }
+
+    /// Send HTTP requests concurrently with these options, and wait for all
+    /// of their responses. See `send_all`.
+    pub fn send_all(
+        &self,
+        reqs: Vec<Request<Option<Bytes>>>,
+    ) -> Result<Vec<Result<Response, Error>>, Error> {
+        tracing::debug!(count = reqs.len(), "performing batch of http requests");
+        ensure_supported(abi_version() >= 2, "request batches")?;
+
+        let encoded = reqs
+            .iter()
+            .map(|req| {
+                (
+                    req.uri().to_string(),
+                    req.method().as_str().to_string(),
+                    header_map_to_bytes(req.headers()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let records = reqs
+            .iter()
+            .zip(&encoded)
+            .map(|(req, (url, method, headers))| {
+                let body = match req.body() {
+                    None => Default::default(),
+                    Some(body) => body.as_ref(),
+                };
+                raw_v2::BatchRequest {
+                    url_ptr: url.as_ptr(),
+                    url_len: url.len(),
+                    method_ptr: method.as_ptr(),
+                    method_len: method.len(),
+                    headers_ptr: headers.as_ptr(),
+                    headers_len: headers.len(),
+                    body_ptr: body.as_ptr(),
+                    body_len: body.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut results = vec![
+            raw_v2::BatchResult {
+                error: 0,
+                status_code: 0,
+                response_handle: 0,
+            };
+            records.len()
+        ];
+        let options = self.to_raw();
+        raw_v2::req_batch(
+            records.as_ptr(),
+            records.len(),
+            &options,
+            results.as_mut_ptr(),
+            results.len(),
+        )
+        .map_err(HttpError::from)?;
+
+        let mut messages = if results.iter().any(|r| r.error != 0) {
+            batch_error_messages(&last_error_message())
+        } else {
+            HashMap::new()
+        };
+        Ok(results
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| {
+                if r.error != 0 {
+                    let message = || messages.remove(&i).unwrap_or_default();
+                    return Err(HttpError::from_code(r.error, message).into());
+                }
+                Ok(Response {
+                    handle: r.response_handle,
+                    status_code: StatusCode::from_u16(r.status_code)?,
+                })
+            })
+            .collect())
+    }
+
+    /// Encode the options as the `$request_options` record of the ABI.
+    fn to_raw(&self) -> raw_v2::RequestOptions {
+        raw_v2::RequestOptions {
+            // A timeout of 0 would leave the choice to the host.
+            timeout_ms: self
+                .timeout
+                .map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32),
+            redirects: match self.follow_redirects {
+                None => raw_v2::REDIRECTS::DEFAULT,
+                Some(true) => raw_v2::REDIRECTS::FOLLOW,
+                Some(false) => raw_v2::REDIRECTS::NONE,
+            },
+            max_response_size: self.max_response_size.map_or(0, |max| max.max(1)),
+            http_version: match self.http_version {
+                Some(http::Version::HTTP_09 | http::Version::HTTP_10 | http::Version::HTTP_11) => {
+                    raw_v2::HTTP_VERSION::HTTP1
+                }
+                Some(http::Version::HTTP_2) => raw_v2::HTTP_VERSION::HTTP2,
+                _ => raw_v2::HTTP_VERSION::DEFAULT,
+            },
+            decompression: match self.decompress {
+                None => raw_v2::DECOMPRESSION::DEFAULT,
+                Some(true) => raw_v2::DECOMPRESSION::AUTO,
+                Some(false) => raw_v2::DECOMPRESSION::PASSTHROUGH,
+            },
+        }
+    }
+}
+
+/// A request sent without blocking the guest, created by `request_async`.
+pub struct PendingRequest {
+    handle: raw_v2::PendingHandle,
+}
+
+/// Abort the request if it is dropped before its response is retrieved.
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        raw_v2::close(self.handle).unwrap();
+    }
+}
+
+impl PendingRequest {
+    /// Check whether the response is ready, without blocking.
+    pub fn is_ready(&self) -> Result<bool, Error> {
+        Ok(!poll(&[self])?.is_empty())
+    }
+
+    /// Wait for the response. If the request failed, its error is returned.
+    pub fn wait(self) -> Result<Response, Error> {
+        let handle = self.handle;
+        // The host releases the pending request once its result is
+        // retrieved, and its handle may be reused for the response.
+        std::mem::forget(self);
+        let (status_code, handle) = raw_v2::req_result(handle).map_err(HttpError::from)?;
+        Ok(Response {
+            handle,
+            status_code: StatusCode::from_u16(status_code)?,
+        })
+    }
+}
+
+/// Send an HTTP request without waiting for its response, which is then
+/// retrieved with `PendingRequest::wait`. This requires the
+/// `async_requests` capability.
+pub fn request_async(req: Request<Option<Bytes>>) -> Result<PendingRequest, Error> {
+    RequestOptions::new().send_async(req)
+}
+
+/// Send an HTTP request, and resolve to its response without blocking the
+/// guest, so that several requests can be awaited concurrently. The future
+/// must be driven by `block_on`, and requires the `async_requests`
+/// capability.
+pub async fn send(req: Request<Option<Bytes>>) -> Result<Response, Error> {
+    ResponseFuture::from(request_async(req)?).await
+}
+
+/// Send HTTP requests concurrently, and wait for all of their responses.
+/// The host checks that it can handle every request of the batch before
+/// sending any of them, and the returned error is that of the entire batch.
+/// Otherwise, each request gets its own result, in the order of `reqs`.
+pub fn send_all(reqs: Vec<Request<Option<Bytes>>>) -> Result<Vec<Result<Response, Error>>, Error> {
+    RequestOptions::new().send_all(reqs)
+}
+
+/// Split the messages the host recorded for the failed requests of a batch,
+/// one per line, prefixed with the index of the request.
+fn batch_error_messages(s: &str) -> HashMap<usize, String> {
+    s.lines()
+        .filter_map(|line| {
+            let (i, message) = line.split_once(": ")?;
+            Some((i.parse().ok()?, message.to_string()))
+        })
+        .collect()
+}
+
+/// Get the indices of the pending requests whose response is ready,
+/// without blocking.
+pub fn poll(pending: &[&PendingRequest]) -> Result<Vec<usize>, Error> {
+    let handles = pending.iter().map(|p| p.handle).collect::<Vec<_>>();
+    let mut ready = vec![0; handles.len()];
+    let written = raw_v2::req_poll(
+        handles.as_ptr(),
+        handles.len(),
+        ready.as_mut_ptr(),
+        ready.len(),
+    )
+    .map_err(HttpError::from)?;
+    ready.truncate(written);
+    Ok(indices_of(&handles, &ready))
+}
+
+/// Wait until the response of one of the pending requests is ready, and
+/// get its index. If `timeout` expires first, `HttpError::Timeout` is
+/// returned.
+pub fn wait_any(pending: &[&PendingRequest], timeout: Option<Duration>) -> Result<usize, Error> {
+    let handles = pending.iter().map(|p| p.handle).collect::<Vec<_>>();
+    let ready = raw_v2::req_wait_any(handles.as_ptr(), handles.len(), timeout_ms(timeout))
+        .map_err(HttpError::from)?;
+    indices_of(&handles, &[ready])
+        .pop()
+        .context("host returned an unknown pending request")
+}
+
+/// Convert a timeout to milliseconds for the host, where 0 waits
+/// indefinitely.
+fn timeout_ms(timeout: Option<Duration>) -> u32 {
+    // A timeout that rounds down to 0 would wait indefinitely.
+    timeout.map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32)
+}
+
+/// Get the positions of the `ready` handles in `handles`.
+fn indices_of(handles: &[raw_v2::PendingHandle], ready: &[raw_v2::PendingHandle]) -> Vec<usize> {
+    handles
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| ready.contains(h))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Start an HTTP request whose body is then written with
+/// `OutgoingRequest::body_write`, without having to hold the entire
+/// body in memory. This requires the `streaming` capability.
+#[tracing::instrument]
+pub fn request_streaming(req: Request<()>) -> Result<OutgoingRequest, Error> {
+    ensure_supported(capabilities().streaming(), "streaming")?;
+    let url = req.uri().to_string();
+    tracing::debug!(%url, headers = ?req.headers(), "starting streaming http request");
+
+    let headers = header_map_to_bytes(req.headers());
+    let method = req.method().as_str().to_string();
+    let handle = raw_v2::req_start(
         url.as_ptr(),
         url.len(),
         method.as_ptr(),
         method.len(),
         headers.as_ptr(),
         headers.len(),
-        body.as_ptr(),
-        body.len(),
-    )?;
-    Ok(Response {
-        handle,
-        status_code: StatusCode::from_u16(status_code)?,
-    })
+    )
+    .map_err(HttpError::from)?;
+    Ok(OutgoingRequest { handle })
 }
 
 /// Send an HTTP request and get a fully formed HTTP response.
@@ -246,7 +1101,7 @@ fn append_response_headers(
         .context("error building the response headers")?;
 
     for (k, v) in hm {
-        headers.insert(k, v.clone());
+        headers.append(k, v.clone());
     }
 
     Ok(())
@@ -286,7 +1141,44 @@ pub fn string_to_header_map(s: &str) -> Result<HeaderMap, Error> {
             entry
         ))?;
         let v = parts.next().unwrap();
-        headers.insert(HeaderName::from_str(k)?, HeaderValue::from_str(v)?);
+        headers.append(HeaderName::from_str(k)?, HeaderValue::from_str(v)?);
+    }
+    Ok(headers)
+}
+
+/// Encode a header map using the length-prefixed binary encoding of
+/// `req_binary`, where every name and value is preceded by its length as
+/// a little-endian `u32`. Unlike `header_map_to_string`, this can encode
+/// any header value allowed by HTTP, including non UTF-8 bytes.
+pub fn header_map_to_bytes(hm: &HeaderMap) -> Vec<u8> {
+    let mut res = Vec::new();
+    for (name, value) in hm {
+        for field in [name.as_str().as_bytes(), value.as_bytes()] {
+            res.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            res.extend_from_slice(field);
+        }
+    }
+    res
+}
+
+/// Decode a header map from the length-prefixed binary encoding
+/// returned by `headers_get_all_binary`.
+pub fn bytes_to_header_map(mut b: &[u8]) -> Result<HeaderMap, Error> {
+    fn next<'a>(b: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+        anyhow::ensure!(b.len() >= 4, "Truncated header length");
+        let (len, rest) = b.split_at(4);
+        let len = u32::from_le_bytes(len.try_into()?) as usize;
+        anyhow::ensure!(rest.len() >= len, "Truncated header");
+        let (field, rest) = rest.split_at(len);
+        *b = rest;
+        Ok(field)
+    }
+
+    let mut headers = HeaderMap::new();
+    while !b.is_empty() {
+        let name = HeaderName::from_bytes(next(&mut b)?)?;
+        let value = HeaderValue::from_bytes(next(&mut b)?)?;
+        headers.append(name, value);
     }
     Ok(headers)
 }
@@ -295,6 +1187,7 @@ pub fn string_to_header_map(s: &str) -> Result<HeaderMap, Error> {
 mod tests {
     use super::*;
     use http::{HeaderMap, HeaderValue};
+    use std::time::Duration;
 
     #[test]
     fn test_header_map_to_string() {
@@ -307,4 +1200,194 @@ mod tests {
             str
         );
     }
+
+    #[test]
+    fn test_duplicate_headers_round_trip() {
+        let mut hm = HeaderMap::new();
+        hm.append("set-cookie", HeaderValue::from_static("a=1"));
+        hm.append("set-cookie", HeaderValue::from_static("b=2"));
+        hm.append("link", HeaderValue::from_static("</style.css>"));
+        let str = header_map_to_string(&hm).unwrap();
+        assert_eq!("set-cookie:a=1\nset-cookie:b=2\nlink:</style.css>\n", str);
+        assert_eq!(hm, string_to_header_map(&str).unwrap());
+
+        let mut res = http::Response::builder();
+        append_response_headers(&mut res, &hm).unwrap();
+        assert_eq!(&hm, res.headers_ref().unwrap());
+    }
+
+    #[test]
+    fn test_binary_headers_round_trip() {
+        let mut hm = HeaderMap::new();
+        hm.append("vary", HeaderValue::from_static("accept"));
+        hm.append("vary", HeaderValue::from_static("origin"));
+        hm.append("x-latin-1", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        let bytes = header_map_to_bytes(&hm);
+        assert_eq!(b"\x04\0\0\0vary\x06\0\0\0accept", &bytes[..18]);
+        assert_eq!(hm, bytes_to_header_map(&bytes).unwrap());
+        assert!(bytes_to_header_map(&bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let e = HttpError::RequestError("error trying to connect".to_string());
+        assert_eq!("HTTP error: error trying to connect", e.to_string());
+        // Hosts without the versioned module do not record messages.
+        assert_eq!(
+            "HTTP error",
+            HttpError::RequestError(String::new()).to_string()
+        );
+        assert_eq!("Timeout", HttpError::Timeout(String::new()).to_string());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities(raw_v2::CAPABILITIES::STREAMING | raw_v2::CAPABILITIES::TRAILERS);
+        assert!(caps.streaming());
+        assert!(!caps.async_requests());
+        assert!(caps.trailers());
+        assert!(!caps.websocket());
+        assert_eq!(Capabilities(0), Capabilities::default());
+    }
+
+    #[test]
+    fn test_response_metadata() {
+        let mut hm = HeaderMap::new();
+        hm.insert("http-version", HeaderValue::from_static("HTTP/2.0"));
+        hm.insert("url", HeaderValue::from_static("https://example.com/"));
+        hm.insert("remote-addr", HeaderValue::from_static("127.0.0.1:443"));
+        hm.insert("tls-time-us", HeaderValue::from_static("1500"));
+        let metadata = ResponseMetadata::from_header_map(&hm).unwrap();
+        assert_eq!(http::Version::HTTP_2, metadata.version);
+        assert_eq!("https://example.com/", metadata.url);
+        assert_eq!(Some("127.0.0.1:443".parse().unwrap()), metadata.remote_addr);
+        assert_eq!(Duration::from_micros(1500), metadata.timings.tls);
+        assert_eq!(Duration::ZERO, metadata.timings.dns);
+        assert_eq!(None, metadata.decoded_encoding);
+
+        hm.insert("decoded-content-encoding", HeaderValue::from_static("gzip"));
+        let metadata = ResponseMetadata::from_header_map(&hm).unwrap();
+        assert_eq!(Some("gzip"), metadata.decoded_encoding.as_deref());
+
+        hm.insert("http-version", HeaderValue::from_static("HTTP/4"));
+        assert!(ResponseMetadata::from_header_map(&hm).is_err());
+    }
+
+    #[test]
+    fn test_request_options_to_raw() {
+        let options = RequestOptions::new().to_raw();
+        assert_eq!(0, options.timeout_ms);
+        assert_eq!(raw_v2::REDIRECTS::DEFAULT, options.redirects);
+        assert_eq!(raw_v2::HTTP_VERSION::DEFAULT, options.http_version);
+
+        let options = RequestOptions::new()
+            .timeout(Duration::from_micros(10))
+            .follow_redirects(false)
+            .max_response_size(1024)
+            .http_version(http::Version::HTTP_11)
+            .decompress(true)
+            .to_raw();
+        assert_eq!(1, options.timeout_ms);
+        assert_eq!(raw_v2::REDIRECTS::NONE, options.redirects);
+        assert_eq!(1024, options.max_response_size);
+        assert_eq!(raw_v2::HTTP_VERSION::HTTP1, options.http_version);
+        assert_eq!(raw_v2::DECOMPRESSION::AUTO, options.decompression);
+    }
+
+    #[test]
+    fn test_batch_error_messages() {
+        let messages = batch_error_messages(
+            "0: Destination not allowed: http://example.com\n2: DNS error: dns error",
+        );
+        assert_eq!(2, messages.len());
+        assert_eq!("Destination not allowed: http://example.com", messages[&0]);
+        assert_eq!("DNS error: dns error", messages[&2]);
+        assert!(batch_error_messages("").is_empty());
+    }
+
+    #[test]
+    fn test_indices_of() {
+        assert_eq!(vec![0, 2], indices_of(&[4, 5, 6], &[6, 4]));
+        assert!(indices_of(&[4, 5, 6], &[7]).is_empty());
+    }
+
+    #[test]
+    fn test_timeout_ms() {
+        assert_eq!(0, timeout_ms(None));
+        assert_eq!(1, timeout_ms(Some(Duration::from_micros(10))));
+        assert_eq!(1500, timeout_ms(Some(Duration::from_millis(1500))));
+        assert_eq!(u32::MAX, timeout_ms(Some(Duration::from_secs(u64::MAX))));
+    }
+
+    #[test]
+    fn test_close_frame() {
+        let frame = websocket::close_frame(b"\x03\xe8bye").unwrap();
+        assert_eq!(1000, frame.code);
+        assert_eq!("bye", frame.reason);
+        assert!(websocket::close_frame(b"").is_none());
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = sse::Parser::new();
+        let events =
+            parser.feed(b"\xef\xbb\xbf: comment\nevent: add\ndata: a\ndata:b\r\nid: 1\n\n");
+        assert_eq!(
+            vec![sse::Event {
+                event: "add".to_string(),
+                data: "a\nb".to_string(),
+                id: Some("1".to_string()),
+            }],
+            events
+        );
+        // The ID is kept for the next events, and the type is reset.
+        let events = parser.feed(b"data\r\r");
+        assert_eq!("message", events[0].event);
+        assert_eq!("", events[0].data);
+        assert_eq!(Some("1".to_string()), events[0].id);
+        assert_eq!(Some("1"), parser.last_event_id());
+    }
+
+    #[test]
+    fn test_sse_parser_chunks() {
+        let mut parser = sse::Parser::new();
+        assert!(parser.feed(b"data: hel").is_empty());
+        assert!(parser.feed(b"lo\r").is_empty());
+        let events = parser.feed(b"\n\r\nretry: 100\nretry: x\nid\n\n");
+        assert_eq!(1, events.len());
+        assert_eq!("hello", events[0].data);
+        // An event without data is not dispatched, and an empty ID resets
+        // the last one.
+        assert_eq!(None, parser.last_event_id());
+        assert_eq!(Some(Duration::from_millis(100)), parser.retry());
+
+        assert!(parser.feed(b"id: 2\ndata: lost").is_empty());
+        parser.restart();
+        assert!(parser.feed(b"\n\n").is_empty());
+        assert_eq!(Some("2"), parser.last_event_id());
+        assert!(sse::is_event_stream("text/event-stream; charset=utf-8"));
+        assert!(!sse::is_event_stream("text/plain"));
+    }
+
+    #[test]
+    fn test_cookie_from_line() {
+        let cookie =
+            Cookie::from_line("#HttpOnly_.example.com\tTRUE\t/\tTRUE\t1700000000\tsession\ta=b")
+                .unwrap();
+        assert_eq!("session", cookie.name);
+        assert_eq!("a=b", cookie.value);
+        assert_eq!("example.com", cookie.domain);
+        assert!(cookie.include_subdomains && cookie.secure && cookie.http_only);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            cookie.expires
+        );
+
+        let cookie = Cookie::from_line("example.com\tFALSE\t/app\tFALSE\t0\tpref\t").unwrap();
+        assert_eq!("", cookie.value);
+        assert!(!cookie.include_subdomains && !cookie.http_only);
+        assert_eq!(None, cookie.expires);
+
+        assert!(Cookie::from_line("example.com\tFALSE\t/").is_err());
+    }
 }
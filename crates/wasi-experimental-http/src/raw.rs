@@ -129,6 +129,13 @@ pub mod HTTP_ERROR {
     pub const REQUEST_ERROR: HttpError = 11;
     pub const RUNTIME_ERROR: HttpError = 12;
     pub const TOO_MANY_SESSIONS: HttpError = 13;
+    pub const CERTIFICATE_PIN_MISMATCH: HttpError = 14;
+    pub const DNS_ERROR: HttpError = 15;
+    pub const CONNECTION_REFUSED: HttpError = 16;
+    pub const TLS_ERROR: HttpError = 17;
+    pub const TIMEOUT: HttpError = 18;
+    pub const DECODE_ERROR: HttpError = 19;
+    pub const TOO_MANY_REDIRECTS: HttpError = 20;
 }
 
 /// HTTP status code
@@ -0,0 +1,1329 @@
+
+//
+// This file was automatically generated by witx-codegen - Do not edit manually.
+//
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    WasiError(i32),
+}
+impl std::error::Error for Error {}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::WasiError(e) => write!(f, "Wasi error {}", e),
+        }
+    }
+}
+
+pub type WasiHandle = i32;
+pub type Char8 = u8;
+pub type Char32 = u32;
+pub type WasiPtr<T> = *const T;
+pub type WasiMutPtr<T> = *mut T;
+pub type WasiStringBytesPtr = WasiPtr<Char8>;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct WasiSlice<T> {
+    ptr: WasiPtr<T>,
+    len: usize,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct WasiMutSlice<T> {
+    ptr: WasiMutPtr<T>,
+    len: usize,
+}
+
+impl<T> WasiSlice<T> {
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn from_slice(&self, slice: &[T]) -> Self {
+        WasiSlice {
+            ptr: slice.as_ptr() as _,
+            len: slice.len(),
+        }
+    }
+}
+
+impl<T> WasiMutSlice<T> {
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    pub fn from_slice(&self, slice: &[T]) -> Self {
+        WasiMutSlice {
+            ptr: slice.as_ptr() as _,
+            len: slice.len(),
+        }
+    }
+
+    pub fn from_mut_slice(&self, slice: &mut [T]) -> Self {
+        WasiMutSlice {
+            ptr: slice.as_mut_ptr(),
+            len: slice.len(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct WasiString {
+    ptr: WasiStringBytesPtr,
+    len: usize,
+}
+
+impl<T: AsRef<str>> From<T> for WasiString {
+    fn from(s: T) -> Self {
+        let s = s.as_ref();
+        WasiString {
+            ptr: s.as_ptr() as _,
+            len: s.len(),
+        }
+    }
+}
+
+impl WasiString {
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(unsafe { std::slice::from_raw_parts(self.ptr, self.len) })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn from_slice(&self, slice: &[u8]) -> Self {
+        WasiString {
+            ptr: slice.as_ptr() as _,
+            len: slice.len(),
+        }
+    }
+}
+
+/// ---------------------- Module: [wasi_experimental_http_v2] ----------------------
+
+pub type HttpError = u32;
+
+#[allow(non_snake_case)]
+pub mod HTTP_ERROR {
+    use super::HttpError;
+    pub const SUCCESS: HttpError = 0;
+    pub const INVALID_HANDLE: HttpError = 1;
+    pub const MEMORY_NOT_FOUND: HttpError = 2;
+    pub const MEMORY_ACCESS_ERROR: HttpError = 3;
+    pub const BUFFER_TOO_SMALL: HttpError = 4;
+    pub const HEADER_NOT_FOUND: HttpError = 5;
+    pub const UTF_8_ERROR: HttpError = 6;
+    pub const DESTINATION_NOT_ALLOWED: HttpError = 7;
+    pub const INVALID_METHOD: HttpError = 8;
+    pub const INVALID_ENCODING: HttpError = 9;
+    pub const INVALID_URL: HttpError = 10;
+    pub const REQUEST_ERROR: HttpError = 11;
+    pub const RUNTIME_ERROR: HttpError = 12;
+    pub const TOO_MANY_SESSIONS: HttpError = 13;
+    pub const CERTIFICATE_PIN_MISMATCH: HttpError = 14;
+    pub const DNS_ERROR: HttpError = 15;
+    pub const CONNECTION_REFUSED: HttpError = 16;
+    pub const TLS_ERROR: HttpError = 17;
+    pub const TIMEOUT: HttpError = 18;
+    pub const DECODE_ERROR: HttpError = 19;
+    pub const TOO_MANY_REDIRECTS: HttpError = 20;
+    pub const BODY_NOT_READ: HttpError = 21;
+    pub const RESPONSE_TOO_LARGE: HttpError = 22;
+    pub const NO_INBOUND_REQUEST: HttpError = 23;
+}
+
+/// HTTP status code
+pub type StatusCode = u16;
+
+/// An HTTP body being sent
+pub type OutgoingBody = WasiSlice<u8>;
+
+/// Buffer for an HTTP body being received
+pub type IncomingBody = WasiMutSlice<u8>;
+
+/// A response handle
+pub type ResponseHandle = WasiHandle;
+
+/// A handle to a request whose body is being sent
+pub type RequestHandle = WasiHandle;
+
+/// Buffer to store a header value
+pub type HeaderValueBuf = WasiMutSlice<u8>;
+
+/// Number of bytes having been written
+pub type WrittenBytes = usize;
+
+/// Buffer to store an error message
+pub type ErrorMessageBuf = WasiMutSlice<u8>;
+
+/// Version of the ABI implemented by the host
+pub type AbiVersion = u32;
+
+/// Optional features supported by the host
+pub type Capabilities = u32;
+
+#[allow(non_snake_case)]
+pub mod CAPABILITIES {
+    use super::Capabilities;
+    pub const STREAMING: Capabilities = 1;
+    pub const ASYNC: Capabilities = 2;
+    pub const TRAILERS: Capabilities = 4;
+    pub const WEBSOCKET: Capabilities = 8;
+}
+
+/// Headers being sent, each encoded as a little-endian u32 name length,
+/// the name, a little-endian u32 value length and the value
+pub type OutgoingHeaders = WasiSlice<u8>;
+
+/// Buffer to store cookies
+pub type CookiesBuf = WasiMutSlice<u8>;
+
+/// HTTP version preferred for a request
+pub type HttpVersion = u32;
+
+#[allow(non_snake_case)]
+pub mod HTTP_VERSION {
+    use super::HttpVersion;
+    pub const DEFAULT: HttpVersion = 0;
+    pub const HTTP1: HttpVersion = 1;
+    pub const HTTP2: HttpVersion = 2;
+}
+
+/// Whether redirects are followed
+pub type Redirects = u32;
+
+#[allow(non_snake_case)]
+pub mod REDIRECTS {
+    use super::Redirects;
+    pub const DEFAULT: Redirects = 0;
+    pub const FOLLOW: Redirects = 1;
+    pub const NONE: Redirects = 2;
+}
+
+/// How compressed response bodies are handled
+pub type Decompression = u32;
+
+#[allow(non_snake_case)]
+pub mod DECOMPRESSION {
+    use super::Decompression;
+    pub const DEFAULT: Decompression = 0;
+    pub const AUTO: Decompression = 1;
+    pub const PASSTHROUGH: Decompression = 2;
+}
+
+/// Options of a request. Limits set to 0 are those of the host, and
+/// limits higher than those of the host are ignored
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct RequestOptions {
+    /// Time limit of the request in milliseconds
+    pub timeout_ms: u32,
+    pub redirects: Redirects,
+    /// Maximum size of the response body in bytes, once decoded
+    pub max_response_size: u32,
+    pub http_version: HttpVersion,
+    pub decompression: Decompression,
+}
+
+/// A handle to a request sent without blocking the guest
+pub type PendingHandle = WasiHandle;
+
+/// Pending requests
+pub type PendingHandles = WasiSlice<PendingHandle>;
+
+/// Buffer to store the pending requests whose response is ready
+pub type ReadyHandlesBuf = WasiMutSlice<PendingHandle>;
+
+/// Number of handles having been written
+pub type WrittenHandles = usize;
+
+/// A request of a batch, with binary encoded headers
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BatchRequest {
+    pub url_ptr: WasiPtr<Char8>,
+    pub url_len: usize,
+    pub method_ptr: WasiPtr<Char8>,
+    pub method_len: usize,
+    pub headers_ptr: WasiPtr<u8>,
+    pub headers_len: usize,
+    pub body_ptr: WasiPtr<u8>,
+    pub body_len: usize,
+}
+
+/// Requests sent in a batch
+pub type BatchRequests = WasiSlice<BatchRequest>;
+
+/// The result of a request of a batch
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BatchResult {
+    /// Why the request failed, or `success`
+    pub error: HttpError,
+    pub status_code: StatusCode,
+    pub response_handle: ResponseHandle,
+}
+
+/// Buffer to store the results of the requests of a batch
+pub type BatchResultsBuf = WasiMutSlice<BatchResult>;
+
+/// Buffer to store a part of an inbound request
+pub type InboundBuf = WasiMutSlice<u8>;
+
+/// A handle to a WebSocket connection
+pub type WebsocketHandle = WasiHandle;
+
+/// The type of a WebSocket message
+pub type MessageType = u32;
+
+#[allow(non_snake_case)]
+pub mod MESSAGE_TYPE {
+    use super::MessageType;
+    pub const TEXT: MessageType = 0;
+    pub const BINARY: MessageType = 1;
+    pub const CLOSE: MessageType = 2;
+}
+
+/// Send a request
+pub fn req(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    method_ptr: WasiPtr<Char8>,
+    method_len: usize,
+    headers_ptr: WasiPtr<Char8>,
+    headers_len: usize,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+) -> Result<(StatusCode, ResponseHandle), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            method_ptr: WasiPtr<Char8>,
+            method_len: usize,
+            headers_ptr: WasiPtr<Char8>,
+            headers_len: usize,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+            result_0_ptr: WasiMutPtr<StatusCode>,
+            result_1_ptr: WasiMutPtr<ResponseHandle>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req(
+        url_ptr,
+        url_len,
+        method_ptr,
+        method_len,
+        headers_ptr,
+        headers_len,
+        body_ptr,
+        body_len,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Send a request with binary encoded headers
+pub fn req_binary(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    method_ptr: WasiPtr<Char8>,
+    method_len: usize,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+) -> Result<(StatusCode, ResponseHandle), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_binary(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            method_ptr: WasiPtr<Char8>,
+            method_len: usize,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+            result_0_ptr: WasiMutPtr<StatusCode>,
+            result_1_ptr: WasiMutPtr<ResponseHandle>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_binary(
+        url_ptr,
+        url_len,
+        method_ptr,
+        method_len,
+        headers_ptr,
+        headers_len,
+        body_ptr,
+        body_len,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Close a request handle
+pub fn close(
+    response_handle: ResponseHandle,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn close(
+            response_handle: ResponseHandle,
+        ) -> HttpError;
+    }
+    let res = unsafe { close(
+        response_handle,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Get the value associated with a header
+pub fn header_get(
+    response_handle: ResponseHandle,
+    header_name_ptr: WasiPtr<Char8>,
+    header_name_len: usize,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn header_get(
+            response_handle: ResponseHandle,
+            header_name_ptr: WasiPtr<Char8>,
+            header_name_len: usize,
+            header_value_buf_ptr: WasiMutPtr<u8>,
+            header_value_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { header_get(
+        response_handle,
+        header_name_ptr,
+        header_name_len,
+        header_value_buf_ptr,
+        header_value_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get all values associated with a header, separated by new lines
+pub fn header_values_get(
+    response_handle: ResponseHandle,
+    header_name_ptr: WasiPtr<Char8>,
+    header_name_len: usize,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn header_values_get(
+            response_handle: ResponseHandle,
+            header_name_ptr: WasiPtr<Char8>,
+            header_name_len: usize,
+            header_value_buf_ptr: WasiMutPtr<u8>,
+            header_value_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { header_values_get(
+        response_handle,
+        header_name_ptr,
+        header_name_len,
+        header_value_buf_ptr,
+        header_value_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the entire response header map
+pub fn headers_get_all(
+    response_handle: ResponseHandle,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn headers_get_all(
+            response_handle: ResponseHandle,
+            header_value_buf_ptr: WasiMutPtr<u8>,
+            header_value_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { headers_get_all(
+        response_handle,
+        header_value_buf_ptr,
+        header_value_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the entire response header map, with binary encoded headers
+pub fn headers_get_all_binary(
+    response_handle: ResponseHandle,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn headers_get_all_binary(
+            response_handle: ResponseHandle,
+            header_value_buf_ptr: WasiMutPtr<u8>,
+            header_value_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { headers_get_all_binary(
+        response_handle,
+        header_value_buf_ptr,
+        header_value_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Fill a buffer with the streamed content of a response body
+pub fn body_read(
+    response_handle: ResponseHandle,
+    body_buf_ptr: WasiMutPtr<u8>,
+    body_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn body_read(
+            response_handle: ResponseHandle,
+            body_buf_ptr: WasiMutPtr<u8>,
+            body_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { body_read(
+        response_handle,
+        body_buf_ptr,
+        body_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get a human-readable message describing the last error
+pub fn last_error(
+    message_buf_ptr: WasiMutPtr<u8>,
+    message_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn last_error(
+            message_buf_ptr: WasiMutPtr<u8>,
+            message_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { last_error(
+        message_buf_ptr,
+        message_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the version of the ABI implemented by the host
+pub fn version() -> Result<AbiVersion, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn version(
+            result_ptr: WasiMutPtr<AbiVersion>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { version(
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the optional features supported by the host
+pub fn capabilities() -> Result<Capabilities, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn capabilities(
+            result_ptr: WasiMutPtr<Capabilities>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { capabilities(
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the metadata of a response, such as its HTTP version, final URL,
+/// remote address and timings, with the same binary encoding as headers
+pub fn response_metadata_get(
+    response_handle: ResponseHandle,
+    metadata_buf_ptr: WasiMutPtr<u8>,
+    metadata_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn response_metadata_get(
+            response_handle: ResponseHandle,
+            metadata_buf_ptr: WasiMutPtr<u8>,
+            metadata_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { response_metadata_get(
+        response_handle,
+        metadata_buf_ptr,
+        metadata_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the trailers of a response, with binary encoded headers,
+/// once its entire body has been read
+pub fn trailers_get_all(
+    response_handle: ResponseHandle,
+    header_value_buf_ptr: WasiMutPtr<u8>,
+    header_value_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn trailers_get_all(
+            response_handle: ResponseHandle,
+            header_value_buf_ptr: WasiMutPtr<u8>,
+            header_value_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { trailers_get_all(
+        response_handle,
+        header_value_buf_ptr,
+        header_value_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Start a request whose body is then streamed with `req_body_write`
+pub fn req_start(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    method_ptr: WasiPtr<Char8>,
+    method_len: usize,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+) -> Result<RequestHandle, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_start(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            method_ptr: WasiPtr<Char8>,
+            method_len: usize,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            result_ptr: WasiMutPtr<RequestHandle>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_start(
+        url_ptr,
+        url_len,
+        method_ptr,
+        method_len,
+        headers_ptr,
+        headers_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Send the next chunk of the body of a request
+pub fn req_body_write(
+    request_handle: RequestHandle,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_body_write(
+            request_handle: RequestHandle,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { req_body_write(
+        request_handle,
+        body_ptr,
+        body_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Set the trailers sent after the body of a request
+pub fn req_trailers_set(
+    request_handle: RequestHandle,
+    trailers_ptr: WasiPtr<u8>,
+    trailers_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_trailers_set(
+            request_handle: RequestHandle,
+            trailers_ptr: WasiPtr<u8>,
+            trailers_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { req_trailers_set(
+        request_handle,
+        trailers_ptr,
+        trailers_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Finish the body of a request, and wait for its response
+pub fn req_finish(
+    request_handle: RequestHandle,
+) -> Result<(StatusCode, ResponseHandle), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_finish(
+            request_handle: RequestHandle,
+            result_0_ptr: WasiMutPtr<StatusCode>,
+            result_1_ptr: WasiMutPtr<ResponseHandle>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_finish(
+        request_handle,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Get the cookies stored by the host for the instance, one per line in
+/// the Netscape cookie file format
+pub fn cookies_get_all(
+    cookies_buf_ptr: WasiMutPtr<u8>,
+    cookies_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn cookies_get_all(
+            cookies_buf_ptr: WasiMutPtr<u8>,
+            cookies_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { cookies_get_all(
+        cookies_buf_ptr,
+        cookies_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Remove all cookies stored by the host for the instance
+pub fn cookies_clear() -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn cookies_clear(
+        ) -> HttpError;
+    }
+    let res = unsafe { cookies_clear(
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Send a request with binary encoded headers and request options
+pub fn req_with_options(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    method_ptr: WasiPtr<Char8>,
+    method_len: usize,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+    options: WasiPtr<RequestOptions>,
+) -> Result<(StatusCode, ResponseHandle), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_with_options(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            method_ptr: WasiPtr<Char8>,
+            method_len: usize,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+            options: WasiPtr<RequestOptions>,
+            result_0_ptr: WasiMutPtr<StatusCode>,
+            result_1_ptr: WasiMutPtr<ResponseHandle>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_with_options(
+        url_ptr,
+        url_len,
+        method_ptr,
+        method_len,
+        headers_ptr,
+        headers_len,
+        body_ptr,
+        body_len,
+        options,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Send a request without waiting for its response
+pub fn req_async(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    method_ptr: WasiPtr<Char8>,
+    method_len: usize,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+    options: WasiPtr<RequestOptions>,
+) -> Result<PendingHandle, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_async(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            method_ptr: WasiPtr<Char8>,
+            method_len: usize,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+            options: WasiPtr<RequestOptions>,
+            result_ptr: WasiMutPtr<PendingHandle>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_async(
+        url_ptr,
+        url_len,
+        method_ptr,
+        method_len,
+        headers_ptr,
+        headers_len,
+        body_ptr,
+        body_len,
+        options,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the pending requests whose response is ready, without blocking
+pub fn req_poll(
+    pending_handles_ptr: WasiPtr<PendingHandle>,
+    pending_handles_len: usize,
+    ready_handles_buf_ptr: WasiMutPtr<PendingHandle>,
+    ready_handles_buf_len: usize,
+) -> Result<WrittenHandles, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_poll(
+            pending_handles_ptr: WasiPtr<PendingHandle>,
+            pending_handles_len: usize,
+            ready_handles_buf_ptr: WasiMutPtr<PendingHandle>,
+            ready_handles_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenHandles>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_poll(
+        pending_handles_ptr,
+        pending_handles_len,
+        ready_handles_buf_ptr,
+        ready_handles_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Wait until the response of one of the pending requests is ready, or
+/// until the timeout in milliseconds expires. A timeout of 0 waits
+/// indefinitely
+pub fn req_wait_any(
+    pending_handles_ptr: WasiPtr<PendingHandle>,
+    pending_handles_len: usize,
+    timeout_ms: u32,
+) -> Result<PendingHandle, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_wait_any(
+            pending_handles_ptr: WasiPtr<PendingHandle>,
+            pending_handles_len: usize,
+            timeout_ms: u32,
+            result_ptr: WasiMutPtr<PendingHandle>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_wait_any(
+        pending_handles_ptr,
+        pending_handles_len,
+        timeout_ms,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Wait for the response of a pending request, and release its handle
+pub fn req_result(
+    pending_handle: PendingHandle,
+) -> Result<(StatusCode, ResponseHandle), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_result(
+            pending_handle: PendingHandle,
+            result_0_ptr: WasiMutPtr<StatusCode>,
+            result_1_ptr: WasiMutPtr<ResponseHandle>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { req_result(
+        pending_handle,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Send requests concurrently, and wait for all of their responses. A
+/// request that fails only fails its own result, and the messages of
+/// such failures are returned by `last_error`, one per line, prefixed
+/// with the index of the request
+pub fn req_batch(
+    requests_ptr: WasiPtr<BatchRequest>,
+    requests_len: usize,
+    options: WasiPtr<RequestOptions>,
+    results_buf_ptr: WasiMutPtr<BatchResult>,
+    results_buf_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn req_batch(
+            requests_ptr: WasiPtr<BatchRequest>,
+            requests_len: usize,
+            options: WasiPtr<RequestOptions>,
+            results_buf_ptr: WasiMutPtr<BatchResult>,
+            results_buf_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { req_batch(
+        requests_ptr,
+        requests_len,
+        options,
+        results_buf_ptr,
+        results_buf_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Open a WebSocket connection to a `ws` or `wss` URL
+pub fn ws_open(
+    url_ptr: WasiPtr<Char8>,
+    url_len: usize,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+) -> Result<WebsocketHandle, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn ws_open(
+            url_ptr: WasiPtr<Char8>,
+            url_len: usize,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            result_ptr: WasiMutPtr<WebsocketHandle>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { ws_open(
+        url_ptr,
+        url_len,
+        headers_ptr,
+        headers_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Send a text or binary message
+pub fn ws_send(
+    websocket_handle: WebsocketHandle,
+    message_type: MessageType,
+    data_ptr: WasiPtr<u8>,
+    data_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn ws_send(
+            websocket_handle: WebsocketHandle,
+            message_type: MessageType,
+            data_ptr: WasiPtr<u8>,
+            data_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { ws_send(
+        websocket_handle,
+        message_type,
+        data_ptr,
+        data_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Receive the next message, waiting at most for the timeout in
+/// milliseconds. A timeout of 0 waits indefinitely. The size of the
+/// message is returned even if it does not fit in the buffer, in which
+/// case the message is kept for the next call
+pub fn ws_recv(
+    websocket_handle: WebsocketHandle,
+    timeout_ms: u32,
+    buf_ptr: WasiMutPtr<u8>,
+    buf_len: usize,
+) -> Result<(MessageType, WrittenBytes), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn ws_recv(
+            websocket_handle: WebsocketHandle,
+            timeout_ms: u32,
+            buf_ptr: WasiMutPtr<u8>,
+            buf_len: usize,
+            result_0_ptr: WasiMutPtr<MessageType>,
+            result_1_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_0_ptr = std::mem::MaybeUninit::uninit();
+    let mut result_1_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { ws_recv(
+        websocket_handle,
+        timeout_ms,
+        buf_ptr,
+        buf_len,
+        result_0_ptr.as_mut_ptr(),
+        result_1_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { (result_0_ptr.assume_init(), result_1_ptr.assume_init()) })
+}
+
+/// Close a WebSocket connection, and release its handle
+pub fn ws_close(
+    websocket_handle: WebsocketHandle,
+    code: u16,
+    reason_ptr: WasiPtr<Char8>,
+    reason_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn ws_close(
+            websocket_handle: WebsocketHandle,
+            code: u16,
+            reason_ptr: WasiPtr<Char8>,
+            reason_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { ws_close(
+        websocket_handle,
+        code,
+        reason_ptr,
+        reason_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Get the method of the inbound request the instance handles
+pub fn inbound_method_get(
+    buf_ptr: WasiMutPtr<u8>,
+    buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn inbound_method_get(
+            buf_ptr: WasiMutPtr<u8>,
+            buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { inbound_method_get(
+        buf_ptr,
+        buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the absolute URL of the inbound request
+pub fn inbound_url_get(
+    buf_ptr: WasiMutPtr<u8>,
+    buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn inbound_url_get(
+            buf_ptr: WasiMutPtr<u8>,
+            buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { inbound_url_get(
+        buf_ptr,
+        buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Get the binary encoded headers of the inbound request
+pub fn inbound_headers_get_all(
+    buf_ptr: WasiMutPtr<u8>,
+    buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn inbound_headers_get_all(
+            buf_ptr: WasiMutPtr<u8>,
+            buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { inbound_headers_get_all(
+        buf_ptr,
+        buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Fill a buffer with the next chunk of the body of the inbound request
+pub fn inbound_body_read(
+    body_buf_ptr: WasiMutPtr<u8>,
+    body_buf_len: usize,
+) -> Result<WrittenBytes, Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn inbound_body_read(
+            body_buf_ptr: WasiMutPtr<u8>,
+            body_buf_len: usize,
+            result_ptr: WasiMutPtr<WrittenBytes>,
+        ) -> HttpError;
+    }
+    let mut result_ptr = std::mem::MaybeUninit::uninit();
+    let res = unsafe { inbound_body_read(
+        body_buf_ptr,
+        body_buf_len,
+        result_ptr.as_mut_ptr(),
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(unsafe { result_ptr.assume_init() })
+}
+
+/// Set the response to the inbound request, replacing the previous one
+pub fn inbound_response_set(
+    status_code: StatusCode,
+    headers_ptr: WasiPtr<u8>,
+    headers_len: usize,
+    body_ptr: WasiPtr<u8>,
+    body_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn inbound_response_set(
+            status_code: StatusCode,
+            headers_ptr: WasiPtr<u8>,
+            headers_len: usize,
+            body_ptr: WasiPtr<u8>,
+            body_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { inbound_response_set(
+        status_code,
+        headers_ptr,
+        headers_len,
+        body_ptr,
+        body_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
+
+/// Replace the trace context whose trace the requests of the instance join,
+/// with a `traceparent` header value and a `tracestate` header value, which
+/// may be empty
+pub fn trace_context_set(
+    traceparent_ptr: WasiPtr<Char8>,
+    traceparent_len: usize,
+    tracestate_ptr: WasiPtr<Char8>,
+    tracestate_len: usize,
+) -> Result<(), Error> {
+    #[link(wasm_import_module = "wasi_experimental_http_v2")]
+    extern "C" {
+        fn trace_context_set(
+            traceparent_ptr: WasiPtr<Char8>,
+            traceparent_len: usize,
+            tracestate_ptr: WasiPtr<Char8>,
+            tracestate_len: usize,
+        ) -> HttpError;
+    }
+    let res = unsafe { trace_context_set(
+        traceparent_ptr,
+        traceparent_len,
+        tracestate_ptr,
+        tracestate_len,
+    )};
+    if res != 0 {
+        return Err(Error::WasiError(res as _));
+    }
+    Ok(())
+}
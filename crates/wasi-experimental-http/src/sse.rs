@@ -0,0 +1,294 @@
+//! Server-Sent Events.
+//!
+//! `EventSource` subscribes to a `text/event-stream` feed, and iterates
+//! over its events as the body of the response is read. When the stream
+//! ends or fails, it reconnects after the delay set by the server, sending
+//! the ID of the last event in the `Last-Event-ID` header, so that the
+//! server can resume the feed.
+
+use crate::{request, Response};
+use anyhow::Error;
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use std::{collections::VecDeque, time::Duration};
+
+/// The delay before reconnecting, unless the server sets another one.
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// An event received from a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The type of the event, `message` unless the server set another one.
+    pub event: String,
+    pub data: String,
+    /// The ID of the last event that had one, which is sent as the
+    /// `Last-Event-ID` header when reconnecting.
+    pub id: Option<String>,
+}
+
+/// An incremental parser for `text/event-stream` bodies.
+#[derive(Debug, Default)]
+pub struct Parser {
+    /// The start of a line whose end has not been received yet.
+    line: Vec<u8>,
+    /// Whether the previous chunk ended with a carriage return, which may
+    /// be followed by a line feed at the start of the next one.
+    after_cr: bool,
+    /// Whether the first line of the stream, which may start with a byte
+    /// order mark, has been parsed.
+    started: bool,
+    event: String,
+    data: String,
+    last_event_id: String,
+    retry: Option<Duration>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the next chunk of the stream, and return the events it
+    /// completed.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Event> {
+        let mut events = vec![];
+        for &b in chunk {
+            let after_cr = std::mem::replace(&mut self.after_cr, b == b'\r');
+            match b {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    events.extend(self.parse_line(&line));
+                }
+                _ => self.line.push(b),
+            }
+        }
+        events
+    }
+
+    /// The ID of the last event that had one.
+    pub fn last_event_id(&self) -> Option<&str> {
+        Some(self.last_event_id.as_str()).filter(|id| !id.is_empty())
+    }
+
+    /// The reconnection delay set by the server, if any.
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    /// Start parsing a new stream, such as after reconnecting. An event
+    /// that was not complete is discarded, while the ID of the last event
+    /// and the reconnection delay are kept.
+    pub fn restart(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.started = false;
+        self.event.clear();
+        self.data.clear();
+    }
+
+    fn parse_line(&mut self, mut line: &[u8]) -> Option<Event> {
+        if !std::mem::replace(&mut self.started, true) {
+            line = line.strip_prefix(b"\xef\xbb\xbf").unwrap_or(line);
+        }
+        let line = String::from_utf8_lossy(line);
+
+        // An empty line dispatches the event.
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.event);
+            let mut data = std::mem::take(&mut self.data);
+            if data.is_empty() {
+                return None;
+            }
+            data.pop();
+            return Some(Event {
+                event: if event.is_empty() {
+                    "message".to_string()
+                } else {
+                    event
+                },
+                data,
+                id: self.last_event_id().map(String::from),
+            });
+        }
+
+        // Lines starting with a colon are comments.
+        let (field, value) = match line.split_once(':') {
+            Some(("", _)) => return None,
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_event_id = value.to_string(),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(ms) = value.parse() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// A subscription to a `text/event-stream` feed, which iterates over its
+/// events, reconnecting when the stream ends or fails.
+pub struct EventSource {
+    uri: Uri,
+    headers: HeaderMap,
+    response: Option<Response>,
+    parser: Parser,
+    events: VecDeque<Event>,
+    /// The number of times in a row reconnecting failed.
+    failures: u32,
+    max_retries: Option<u32>,
+    done: bool,
+}
+
+impl EventSource {
+    /// Send a `GET` request for the URL and headers of `req`, and check
+    /// that the response is an event stream. Failures of this first
+    /// request are returned, rather than retried.
+    pub fn connect(req: Request<()>) -> Result<Self, Error> {
+        let mut source = EventSource {
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            response: None,
+            parser: Parser::new(),
+            events: VecDeque::new(),
+            failures: 0,
+            max_retries: None,
+            done: false,
+        };
+        let res = source.send()?;
+        match check_response(res)? {
+            Some(res) => source.response = Some(res),
+            None => source.done = true,
+        }
+        Ok(source)
+    }
+
+    /// Stop reconnecting after `max_retries` failed attempts in a row, and
+    /// return the error of the last one. By default, reconnecting is
+    /// attempted indefinitely.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// The ID of the last event that had one.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.parser.last_event_id()
+    }
+
+    /// Send the request for the stream.
+    fn send(&self) -> Result<Response, Error> {
+        let mut headers = self.headers.clone();
+        headers
+            .entry(header::ACCEPT)
+            .or_insert(HeaderValue::from_static("text/event-stream"));
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        if let Some(id) = self.last_event_id() {
+            headers.insert("last-event-id", HeaderValue::from_str(id)?);
+        }
+        let mut req = Request::get(self.uri.clone()).body(None::<Bytes>)?;
+        *req.headers_mut() = headers;
+
+        request(req)
+    }
+
+    /// Read the next chunk of the stream. If the stream ended or failed,
+    /// the response is dropped so that the next call reconnects.
+    fn read(&mut self, mut res: Response) {
+        let mut chunk = [0u8; 4096];
+        match res.body_read(&mut chunk) {
+            Ok(0) => {}
+            Ok(read) => {
+                self.events.extend(self.parser.feed(&chunk[..read]));
+                self.response = Some(res);
+            }
+            Err(e) => tracing::debug!(error = %e, "cannot read the event stream"),
+        }
+    }
+
+    /// Reconnect after the delay set by the server. Failures to send the
+    /// request are retried, but a response that is not an event stream
+    /// ends the subscription.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        std::thread::sleep(self.parser.retry().unwrap_or(DEFAULT_RETRY));
+        self.parser.restart();
+        match self.send() {
+            Ok(res) => {
+                self.failures = 0;
+                match check_response(res)? {
+                    Some(res) => self.response = Some(res),
+                    None => self.done = true,
+                }
+            }
+            Err(e) => {
+                self.failures += 1;
+                if self.max_retries.is_some_and(|max| self.failures > max) {
+                    return Err(e);
+                }
+                tracing::debug!(error = %e, "cannot reconnect to the event stream");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for EventSource {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            match self.response.take() {
+                Some(res) => self.read(res),
+                None => {
+                    if let Err(e) = self.reconnect() {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Check that a response is an event stream, and return it, or `None` if
+/// the server asked not to reconnect with a `204 No Content` response.
+fn check_response(res: Response) -> Result<Option<Response>, Error> {
+    if res.status_code == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        res.status_code == StatusCode::OK,
+        "event stream returned status {}",
+        res.status_code
+    );
+    let content_type = res.header_get(header::CONTENT_TYPE.to_string())?;
+    anyhow::ensure!(
+        is_event_stream(&content_type),
+        "event stream returned content type {}",
+        content_type
+    );
+    Ok(Some(res))
+}
+
+/// Check whether a `Content-Type` header is that of an event stream,
+/// ignoring its parameters.
+pub(crate) fn is_event_stream(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().eq_ignore_ascii_case("text/event-stream")
+}
@@ -0,0 +1,124 @@
+//! WebSocket connections.
+//!
+//! The host opens the connection and answers pings, while the guest sends
+//! and receives text and binary messages through its handle. Receiving
+//! blocks the guest until a message arrives or the timeout expires.
+
+use crate::{capabilities, ensure_supported, header_map_to_bytes, raw_v2, timeout_ms, HttpError};
+use anyhow::Error;
+use http::Request;
+
+/// A message received from or sent to a WebSocket server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The server closed the connection, with a close code and a reason if
+    /// it sent any. The connection must still be closed with `close`.
+    Close(Option<CloseFrame>),
+}
+
+/// The code and reason of the close of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// A WebSocket connection, opened with `WebSocket::connect`.
+pub struct WebSocket {
+    handle: raw_v2::WebsocketHandle,
+}
+
+/// Drop the connection without a close handshake if it was not closed.
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        raw_v2::close(self.handle).unwrap();
+    }
+}
+
+impl WebSocket {
+    /// Open a connection to the `ws` or `wss` URL of the request, with its
+    /// headers added to the opening handshake. The host allows the same
+    /// hosts as for requests, and this requires the `websocket`
+    /// capability.
+    #[tracing::instrument]
+    pub fn connect(req: Request<()>) -> Result<Self, Error> {
+        ensure_supported(capabilities().websocket(), "websocket")?;
+        let url = req.uri().to_string();
+        tracing::debug!(%url, headers = ?req.headers(), "opening websocket");
+
+        let headers = header_map_to_bytes(req.headers());
+        let handle = raw_v2::ws_open(url.as_ptr(), url.len(), headers.as_ptr(), headers.len())
+            .map_err(HttpError::from)?;
+        Ok(WebSocket { handle })
+    }
+
+    /// Send a text message.
+    pub fn send_text(&self, text: &str) -> Result<(), Error> {
+        self.send(raw_v2::MESSAGE_TYPE::TEXT, text.as_bytes())
+    }
+
+    /// Send a binary message.
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), Error> {
+        self.send(raw_v2::MESSAGE_TYPE::BINARY, data)
+    }
+
+    fn send(&self, message_type: raw_v2::MessageType, data: &[u8]) -> Result<(), Error> {
+        raw_v2::ws_send(self.handle, message_type, data.as_ptr(), data.len())
+            .map_err(HttpError::from)?;
+        Ok(())
+    }
+
+    /// Wait for the next message. If `timeout` expires first,
+    /// `HttpError::Timeout` is returned.
+    pub fn receive(&self, timeout: Option<std::time::Duration>) -> Result<Message, Error> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let (mut message_type, mut written) = raw_v2::ws_recv(
+            self.handle,
+            timeout_ms(timeout),
+            buf.as_mut_ptr(),
+            buf.len(),
+        )
+        .map_err(HttpError::from)?;
+        // The host keeps messages too large for the buffer, and returns
+        // their size so that they can be retrieved without waiting.
+        if written > buf.len() {
+            buf.resize(written, 0);
+            let res = raw_v2::ws_recv(self.handle, 0, buf.as_mut_ptr(), buf.len())
+                .map_err(HttpError::from)?;
+            message_type = res.0;
+            written = res.1;
+        }
+        buf.truncate(written);
+
+        match message_type {
+            raw_v2::MESSAGE_TYPE::TEXT => Ok(Message::Text(String::from_utf8(buf)?)),
+            raw_v2::MESSAGE_TYPE::BINARY => Ok(Message::Binary(buf)),
+            raw_v2::MESSAGE_TYPE::CLOSE => Ok(Message::Close(close_frame(&buf))),
+            _ => anyhow::bail!("host returned an unknown message type: {}", message_type),
+        }
+    }
+
+    /// Close the connection with `code` and `reason`, and wait for the
+    /// server to acknowledge it.
+    pub fn close(self, code: u16, reason: &str) -> Result<(), Error> {
+        let handle = self.handle;
+        // The host releases the connection even if closing it fails.
+        std::mem::forget(self);
+        raw_v2::ws_close(handle, code, reason.as_ptr(), reason.len()).map_err(HttpError::from)?;
+        Ok(())
+    }
+}
+
+/// Decode the payload of a close message: a big-endian close code
+/// followed by the reason, or nothing.
+pub(crate) fn close_frame(payload: &[u8]) -> Option<CloseFrame> {
+    if payload.len() < 2 {
+        return None;
+    }
+    Some(CloseFrame {
+        code: u16::from_be_bytes([payload[0], payload[1]]),
+        reason: String::from_utf8_lossy(&payload[2..]).into_owned(),
+    })
+}
@@ -2,7 +2,9 @@
 mod tests {
     use anyhow::Error;
     use std::time::Instant;
-    use wasi_experimental_http_wasmtime::{HttpCtx, HttpState};
+    use wasi_experimental_http_wasmtime::{
+        CookieJar, HttpCtx, HttpInstance, HttpState, InboundRequest,
+    };
     use wasmtime::*;
     use wasmtime_wasi::sync::WasiCtxBuilder;
     use wasmtime_wasi::*;
@@ -88,6 +90,157 @@ mod tests {
         make_concurrent_requests(module);
     }
 
+    #[test]
+    fn test_error_message() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["not_allowed"]).unwrap();
+    }
+
+    #[test]
+    fn test_response_metadata() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["metadata"]).unwrap();
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let http = HttpCtx {
+            allowed_hosts: Some(vec!["https://postman-echo.com".to_string()]),
+            cookie_jar: Some(CookieJar::new()),
+            ..Default::default()
+        };
+        let (instance, store) = create_instance_with_ctx(module, http).unwrap();
+        run_tests(&instance, store, &["cookies"]).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_request() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["streaming_post"]).unwrap();
+    }
+
+    #[test]
+    fn test_request_options() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["request_options"]).unwrap();
+    }
+
+    #[test]
+    fn test_async_requests() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["async_requests"]).unwrap();
+    }
+
+    #[test]
+    fn test_async_send() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["async_send"]).unwrap();
+    }
+
+    #[test]
+    fn test_async_send_wait_error() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let http = HttpCtx {
+            allowed_hosts: Some(vec!["https://postman-echo.com".to_string()]),
+            ..Default::default()
+        };
+        // Make every wait fail with a runtime error, so that the futures
+        // only complete if the executor wakes all of them to poll again.
+        let (instance, store) = create_instance_with_linker(module, http, |linker| {
+            linker.allow_shadowing(true);
+            linker.func_wrap(
+                HttpState::MODULE_V2,
+                "req_wait_any",
+                |_: u32, _: u32, _: u32, _: u32| -> u32 { 12 },
+            )?;
+            Ok(())
+        })
+        .unwrap();
+        run_tests(&instance, store, &["async_send_wait_error"]).unwrap();
+    }
+
+    #[test]
+    fn test_send_all() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["send_all"]).unwrap();
+    }
+
+    #[test]
+    fn test_websocket() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let (instance, store) = create_instance(
+            module,
+            Some(vec!["https://ws.postman-echo.com".to_string()]),
+            None,
+        )
+        .unwrap();
+        run_tests(&instance, store, &["websocket"]).unwrap();
+    }
+
+    #[test]
+    fn test_inbound_request() {
+        let module = "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string();
+        let req = http::Request::post("http://localhost:3000/echo")
+            .header("x-echo", "abc")
+            .body("hello".into())
+            .unwrap();
+        let inbound = InboundRequest::new(req);
+        let http = HttpCtx {
+            inbound: Some(inbound.clone()),
+            ..Default::default()
+        };
+        let (instance, store) = create_instance_with_ctx(module, http).unwrap();
+        run_tests(&instance, store, &["handle_http_request"]).unwrap();
+
+        let res = inbound.take_response().unwrap();
+        assert_eq!(201, res.status());
+        assert_eq!("abc", res.headers()["x-echo"]);
+        assert_eq!("hello", res.body());
+    }
+
     fn make_concurrent_requests(module: String) {
         let func = "concurrent";
         let (instance, mut store) = create_instance(
@@ -143,6 +296,29 @@ mod tests {
         filename: String,
         allowed_hosts: Option<Vec<String>>,
         max_concurrent_requests: Option<u32>,
+    ) -> Result<(Instance, Store<IntegrationTestsCtx>), Error> {
+        let http = HttpCtx {
+            allowed_hosts,
+            max_concurrent_requests,
+            ..Default::default()
+        };
+        create_instance_with_ctx(filename, http)
+    }
+
+    /// Create a Wasmtime::Instance with the given HTTP context.
+    fn create_instance_with_ctx(
+        filename: String,
+        http: HttpCtx,
+    ) -> Result<(Instance, Store<IntegrationTestsCtx>), Error> {
+        create_instance_with_linker(filename, http, |_| Ok(()))
+    }
+
+    /// Create a Wasmtime::Instance with the given HTTP context, letting
+    /// `link` define more functions once the HTTP functions are linked.
+    fn create_instance_with_linker(
+        filename: String,
+        http: HttpCtx,
+        link: impl FnOnce(&mut Linker<IntegrationTestsCtx>) -> Result<(), Error>,
     ) -> Result<(Instance, Store<IntegrationTestsCtx>), Error> {
         let start = Instant::now();
         let engine = Engine::default();
@@ -154,11 +330,7 @@ mod tests {
             .inherit_stderr()
             .build();
 
-        let http = HttpCtx {
-            allowed_hosts,
-            max_concurrent_requests,
-        };
-
+        let http = HttpState::new()?.instance(http);
         let ctx = IntegrationTestsCtx { wasi, http };
 
         let mut store = Store::new(&engine, ctx);
@@ -168,10 +340,11 @@ mod tests {
         )?;
 
         // Link `wasi_experimental_http`
-        let http = HttpState::new()?;
-        http.add_to_linker(&mut linker, |cx: &IntegrationTestsCtx| -> HttpCtx {
-            cx.http.clone()
-        })?;
+        HttpState::add_to_linker(
+            &mut linker,
+            |cx: &mut IntegrationTestsCtx| -> &mut HttpInstance { &mut cx.http },
+        )?;
+        link(&mut linker)?;
 
         let module = wasmtime::Module::from_file(store.engine(), filename)?;
 
@@ -183,6 +356,6 @@ mod tests {
 
     struct IntegrationTestsCtx {
         pub wasi: WasiCtx,
-        pub http: HttpCtx,
+        pub http: HttpInstance,
     }
 }
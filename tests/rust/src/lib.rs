@@ -44,7 +44,230 @@ pub extern "C" fn post() {
     assert_ne!(header_map.len(), 0);
 }
 
+#[no_mangle]
+pub extern "C" fn not_allowed() {
+    let url = "https://example.com/not-allowed".to_string();
+    let req = http::request::Builder::new().uri(&url).body(None).unwrap();
+    let err = wasi_experimental_http::request(req)
+        .err()
+        .expect("request to a host that is not allowed should fail");
+    match err.downcast_ref::<wasi_experimental_http::HttpError>() {
+        Some(wasi_experimental_http::HttpError::DestinationNotAllowed(message)) => {
+            assert!(message.contains(&url))
+        }
+        _ => panic!("unexpected error: {}", err),
+    }
+}
+
 #[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn metadata() {
+    let url = "https://postman-echo.com/get".to_string();
+    let req = http::request::Builder::new().uri(&url).body(None).unwrap();
+    let res = wasi_experimental_http::request(req).expect("cannot make get request");
+    let metadata = res.metadata().unwrap();
+    assert_eq!(metadata.url, url);
+    assert!(metadata.remote_addr.is_some());
+    assert!(metadata.timings.tls > std::time::Duration::ZERO);
+    assert!(metadata.timings.total >= metadata.timings.ttfb);
+}
+
+#[no_mangle]
+pub extern "C" fn cookies() {
+    // The server sets the cookie, and redirects to a page listing the
+    // cookies it received.
+    let url = "https://postman-echo.com/cookies/set?session=abc".to_string();
+    let mut res = make_req(url);
+    let body = res.body_read_all().unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("\"session\":\"abc\""));
+
+    let cookies = wasi_experimental_http::cookies().unwrap();
+    assert!(cookies
+        .iter()
+        .any(|c| c.name == "session" && c.value == "abc" && c.domain == "postman-echo.com"));
+
+    wasi_experimental_http::cookies_clear().unwrap();
+    assert!(wasi_experimental_http::cookies().unwrap().is_empty());
+}
+
+#[no_mangle]
+pub extern "C" fn streaming_post() {
+    let caps = wasi_experimental_http::capabilities();
+    assert!(caps.streaming());
+
+    let req = http::request::Builder::new()
+        .method(http::Method::POST)
+        .uri("https://postman-echo.com/post")
+        .header("Content-Type", "text/plain")
+        .body(())
+        .unwrap();
+    let mut req = wasi_experimental_http::request_streaming(req).expect("cannot start request");
+    for chunk in ["Testing with ", "a streamed ", "request body."] {
+        req.body_write(chunk.as_bytes()).unwrap();
+    }
+    if caps.trailers() {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("x-checksum", http::HeaderValue::from_static("abc"));
+        req.trailers_set(&trailers).unwrap();
+    }
+
+    let mut res = req.finish().expect("cannot finish request");
+    assert_eq!(res.status_code, 200);
+    let body = res.body_read_all().unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("Testing with a streamed request body."));
+    assert!(res.trailers().is_ok());
+}
+
+#[no_mangle]
+pub extern "C" fn request_options() {
+    let req = http::request::Builder::new()
+        .uri("https://postman-echo.com/redirect-to?url=https://postman-echo.com/get")
+        .body(None)
+        .unwrap();
+    let res = wasi_experimental_http::RequestOptions::new()
+        .timeout(std::time::Duration::from_secs(30))
+        .follow_redirects(false)
+        .send(req)
+        .expect("cannot make get request");
+    assert_eq!(res.status_code, 302);
+
+    let req = http::request::Builder::new()
+        .uri("https://postman-echo.com/get")
+        .body(None)
+        .unwrap();
+    let res = wasi_experimental_http::RequestOptions::new()
+        .max_response_size(1)
+        .send(req);
+    assert!(res.is_err());
+}
+
+#[no_mangle]
+pub extern "C" fn async_requests() {
+    assert!(wasi_experimental_http::capabilities().async_requests());
+    let pending = [
+        "https://postman-echo.com/get",
+        "https://postman-echo.com/delay/1",
+    ]
+    .iter()
+    .map(|url| {
+        let req = http::request::Builder::new().uri(*url).body(None).unwrap();
+        wasi_experimental_http::request_async(req).expect("cannot start request")
+    })
+    .collect::<Vec<_>>();
+    let pending = pending.iter().collect::<Vec<_>>();
+    let first = wasi_experimental_http::wait_any(&pending, None).expect("cannot wait for requests");
+    assert!(wasi_experimental_http::poll(&pending)
+        .unwrap()
+        .contains(&first));
+    drop(pending);
+
+    let req = http::request::Builder::new()
+        .uri("https://postman-echo.com/delay/1")
+        .body(None)
+        .unwrap();
+    let pending = wasi_experimental_http::request_async(req).unwrap();
+    let res =
+        wasi_experimental_http::wait_any(&[&pending], Some(std::time::Duration::from_millis(1)));
+    assert!(res.is_err());
+    let res = pending.wait().expect("cannot get response");
+    assert_eq!(res.status_code, 200);
+}
+
+#[no_mangle]
+pub extern "C" fn async_send() {
+    let get = |url: &str| {
+        let req = http::request::Builder::new().uri(url).body(None).unwrap();
+        wasi_experimental_http::send(req)
+    };
+    let (a, b) = wasi_experimental_http::block_on(async {
+        futures::join!(
+            get("https://postman-echo.com/delay/1"),
+            get("https://postman-echo.com/get")
+        )
+    });
+    assert_eq!(a.expect("cannot make get request").status_code, 200);
+    assert_eq!(b.expect("cannot make get request").status_code, 200);
+}
+
+#[no_mangle]
+pub extern "C" fn async_send_wait_error() {
+    use futures::StreamExt;
+
+    // Unlike `join!`, `FuturesUnordered` only polls the futures that were
+    // woken, so both must be woken when the host cannot wait for them.
+    let mut responses = [
+        "https://postman-echo.com/get",
+        "https://postman-echo.com/delay/1",
+    ]
+    .iter()
+    .map(|url| {
+        let req = http::request::Builder::new().uri(*url).body(None).unwrap();
+        wasi_experimental_http::send(req)
+    })
+    .collect::<futures::stream::FuturesUnordered<_>>();
+    let statuses = wasi_experimental_http::block_on(async {
+        let mut statuses = vec![];
+        while let Some(res) = responses.next().await {
+            statuses.push(res.expect("cannot make get request").status_code);
+        }
+        statuses
+    });
+    assert_eq!(statuses, [200, 200]);
+}
+
+#[no_mangle]
+pub extern "C" fn send_all() {
+    let reqs = [
+        "https://postman-echo.com/get",
+        "https://example.com/",
+        "https://postman-echo.com/status/404",
+    ]
+    .iter()
+    .map(|url| http::request::Builder::new().uri(*url).body(None).unwrap())
+    .collect();
+    let res = wasi_experimental_http::send_all(reqs).expect("cannot send batch");
+    assert_eq!(res.len(), 3);
+    assert_eq!(res[0].as_ref().unwrap().status_code, 200);
+    let err = res[1].as_ref().err().unwrap();
+    assert!(err.to_string().contains("example.com"));
+    assert_eq!(res[2].as_ref().unwrap().status_code, 404);
+}
+
+#[no_mangle]
+pub extern "C" fn websocket() {
+    let req = http::request::Builder::new()
+        .uri("wss://ws.postman-echo.com/raw")
+        .body(())
+        .unwrap();
+    let ws = wasi_experimental_http::WebSocket::connect(req).expect("cannot connect");
+    let timeout = Some(std::time::Duration::from_secs(10));
+    ws.send_text("hello").unwrap();
+    assert_eq!(
+        ws.receive(timeout).unwrap(),
+        wasi_experimental_http::Message::Text("hello".to_string())
+    );
+    ws.send_binary(&[1, 2, 3]).unwrap();
+    assert_eq!(
+        ws.receive(timeout).unwrap(),
+        wasi_experimental_http::Message::Binary(vec![1, 2, 3])
+    );
+    ws.close(1000, "done").unwrap();
+}
+
+#[wasi_experimental_http::handler]
+fn echo(req: http::Request<Bytes>) -> anyhow::Result<http::Response<Bytes>> {
+    assert_eq!(req.uri().path(), "/echo");
+    let mut res = http::Response::builder().status(201);
+    if let Some(value) = req.headers().get("x-echo") {
+        res = res.header("x-echo", value);
+    }
+    Ok(res.body(req.into_body())?)
+}
+
 #[no_mangle]
 pub extern "C" fn concurrent() {
     let url = "https://some-random-api.ml/facts/dog".to_string();